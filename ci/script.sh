@@ -3,6 +3,7 @@ set -euxo pipefail
 main() {
     if [ $TARGET = x86_64-unknown-linux-gnu ]; then
         cargo check --target $TARGET
+        cargo test --target $TARGET --lib
         return
     fi
     xargo clean
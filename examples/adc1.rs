@@ -16,7 +16,7 @@ extern crate cortex_m_rtfm as rtfm;
 extern crate f4;
 
 use f4::adc::{Adc, AdcChannel};
-use f4::dma::{CircBuffer, Dma2Stream0};
+use f4::dma::{CircBuffer, Dma2Stream0, Dma2Streams};
 use f4::time::Hertz;
 use f4::{Channel, Pwm};
 use f4::led::{self, LED};
@@ -31,13 +31,14 @@ app! {
     device: f4::stm32f40x,
 
     resources: {
-        static BUFFER: CircBuffer<[u16; N], Dma2Stream0> = CircBuffer::new([[0; N]; 2]);
+        static BUFFER: [[u16; N]; 2] = [[0; N]; 2];
+        static TRANSFER: Option<CircBuffer<[u16; N], Dma2Stream0>> = None;
     },
 
     tasks: {
         DMA2_STREAM0: {
             path: transfer_done,
-            resources: [BUFFER, DMA2],
+            resources: [TRANSFER, DMA2],
         },
     },
 }
@@ -58,7 +59,7 @@ fn init(p: init::Peripherals, r: init::Resources) {
 
     let adc = Adc(p.ADC1);
 
-    adc.init(p.DMA2, p.RCC);
+    adc.init(p.RCC);
     adc.enable_input(AdcChannel::_0, 1, p.GPIOA, p.GPIOB, p.GPIOC);
     adc.enable_input(AdcChannel::_1, 2, p.GPIOA, p.GPIOB, p.GPIOC);
     adc.enable_input(AdcChannel::_4, 3, p.GPIOA, p.GPIOB, p.GPIOC);
@@ -66,7 +67,11 @@ fn init(p: init::Peripherals, r: init::Resources) {
     adc.enable_input(AdcChannel::_11, 5, p.GPIOA, p.GPIOB, p.GPIOC);
     adc.enable_input(AdcChannel::_10, 6, p.GPIOA, p.GPIOB, p.GPIOC);
     adc.enable();
-    adc.start(r.BUFFER, p.DMA2, pwm).unwrap();
+
+    let streams = Dma2Streams::take(p.DMA2).unwrap();
+    let buffer: &'static mut [[u16; N]; 2] = &mut **r.BUFFER;
+
+    **r.TRANSFER = Some(adc.start(buffer, p.DMA2, streams.s0, pwm));
 }
 
 fn idle() -> ! {
@@ -76,7 +81,9 @@ fn idle() -> ! {
 }
 
 fn transfer_done(_t: &mut Threshold, r: DMA2_STREAM0::Resources) {
-    match r.BUFFER.read(r.DMA2, |x| {
+    let transfer = r.TRANSFER.as_mut().unwrap();
+
+    match transfer.read(r.DMA2, |x| {
         let buf: [u16; N] = x.clone();
         buf
     }) {
@@ -0,0 +1,74 @@
+//! Quadrature encoder on TIM4 (PB6 = A, PB7 = B), sampled every 10 ms
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+#[macro_use]
+extern crate cortex_m_debug;
+extern crate cortex_m_rtfm as rtfm;
+extern crate f4;
+
+use f4::encoder::{Config, Position, Sampler};
+use f4::prelude::*;
+use f4::time::Microseconds;
+use f4::{Encoder, Timer};
+use rtfm::{app, Threshold};
+
+const INTERVAL: Microseconds = Microseconds(10_000);
+
+app! {
+    device: f4::stm32f40x,
+
+    resources: {
+        static POSITION: Position = Position::new();
+        static SAMPLER: Sampler = Sampler::new(0);
+    },
+
+    tasks: {
+        // the encoder counter wrapped around
+        TIM4: {
+            path: wrap,
+            resources: [POSITION, TIM4],
+        },
+
+        // same priority as TIM4: `read` can't be preempted by `wrap`
+        TIM3: {
+            path: sample,
+            resources: [POSITION, SAMPLER, TIM3, TIM4],
+        },
+    },
+}
+
+fn init(p: init::Peripherals, _r: init::Resources) {
+    let mut config = Config::new();
+    config.pull_up = true;
+
+    let encoder = Encoder(p.TIM4);
+    encoder.init(&config, p.GPIOA, p.GPIOB, p.RCC);
+    encoder.listen();
+
+    let timer = Timer(p.TIM3);
+    timer.init(INTERVAL, p.RCC);
+    timer.resume();
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn wrap(_t: &mut Threshold, r: TIM4::Resources) {
+    Encoder(&**r.TIM4).update(r.POSITION);
+}
+
+fn sample(_t: &mut Threshold, r: TIM3::Resources) {
+    // Clear the interrupt flag (RM0368, 13.4.5)
+    r.TIM3.sr.modify(|_, w| w.uif().clear_bit());
+
+    let position = Encoder(&**r.TIM4).read(r.POSITION);
+    let motion = r.SAMPLER.sample(position, INTERVAL);
+
+    ipln!("{} counts, {} counts/s", position, motion.velocity);
+}
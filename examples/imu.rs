@@ -41,12 +41,12 @@ extern crate heapless;
 extern crate stm32f40x;
 
 use cortex_m::peripheral::SystClkSource;
-use core::ops::Deref;
 use core::f32;
 use f4::lsm9ds1::{ImuSettings, Lsm9ds1};
 use f4::Serial;
 use f4::Spi;
-use f4::dma::{Buffer, Dma1Stream5, Dma1Stream6};
+use f4::dma::{Dma1Stream5, Dma1Streams, Transfer};
+use f4::serial::TxBuffer;
 use f4::time::Hertz;
 use f4::clock;
 use f4::timer::Timer;
@@ -79,8 +79,10 @@ app! {
 
     resources: {
         // Serial
-        static TX_BUFFER: Buffer<[u8; MAX_TX_LEN], Dma1Stream6> = Buffer::new([0; MAX_TX_LEN]);
-        static RX_BUFFER: Buffer<[u8; 1], Dma1Stream5> = Buffer::new([0; 1]);
+        static TX_BUFFER: [u8; MAX_TX_LEN] = [0; MAX_TX_LEN];
+        static RX_BUFFER: [u8; 1] = [0; 1];
+        static TX: TxBuffer<[u8; MAX_TX_LEN]> = TxBuffer::Empty;
+        static RX: Option<Transfer<&'static mut [u8; 1], Dma1Stream5>> = None;
         static MODE: OutputMode = OutputMode::Euler;
         // IMU
         static FILTER: MadgwickAhrs = MadgwickAhrs::begin(SAMPLE_FREQUENCY as f32);
@@ -117,17 +119,17 @@ app! {
             path: sys_tick,
             priority: 1,
             resources: [ACC, GYRO, MAG, ORIENTATION, MAG_BIAS, MAG_SCL, MODE,
-                        TX_BUFFER, DMA1, USART2],
+                        TX, DMA1, USART2],
         },
         DMA1_STREAM5: {
             path: rx_done,
             priority: 2,
-            resources: [RX_BUFFER, MODE, DMA1, USART2],
+            resources: [RX, MODE, DMA1, USART2],
         },
         DMA1_STREAM6: {
             path: tx_done,
             priority: 3,
-            resources: [TX_BUFFER, DMA1],
+            resources: [TX, DMA1],
         },
         EXTI15_10: {
             path: button,
@@ -196,7 +198,9 @@ fn init(p: init::Peripherals, r: init::Resources) {
     timer.resume();
 
     // Listen to serial input on the receive DMA
-    serial.read_exact(p.DMA1, r.RX_BUFFER).unwrap();
+    let streams = Dma1Streams::take(p.DMA1).unwrap();
+    **r.TX = TxBuffer::Idle(&mut **r.TX_BUFFER, streams.s6);
+    **r.RX = Some(serial.read_exact(p.DMA1, streams.s5, &mut **r.RX_BUFFER));
 }
 
 // Handle user button press
@@ -330,16 +334,22 @@ fn sample_imu(t: &mut Threshold, mut r: TIM2::Resources) {
 // Handle user serial input
 fn rx_done(t: &mut Threshold, mut r: DMA1_STREAM5::Resources) {
     use rtfm::Resource;
-    let byte = r.RX_BUFFER.claim(t, |rx, t| {
-        // We need to unlock the DMA to use it again in the future
-        r.DMA1.claim(t, |dma, _| rx.release(dma).unwrap());
+    // RX is claimed mutably while DMA1 and USART2 are claimed
+    let dma1 = &r.DMA1;
+    let usart2 = &r.USART2;
+    let byte = r.RX.claim_mut(t, |rx, t| {
+        // Take the buffer and the DMA stream back from the finished transfer
+        let transfer = rx.take().unwrap();
+        let (buffer, stream) = dma1.claim(t, |dma, _| {
+            transfer.is_done(dma).unwrap();
+            transfer.wait(dma)
+        });
         // Read the single character in the input buffer
-        let byte = rx.deref().borrow()[0];
-        r.USART2.claim(t, |usart, t| {
+        let byte = buffer[0];
+        usart2.claim(t, |usart, t| {
             // Get ready to receive again
             let serial = Serial(&**usart);
-            r.DMA1
-                .claim(t, |dma, _| serial.read_exact(dma, rx).unwrap());
+            **rx = Some(dma1.claim(t, |dma, _| serial.read_exact(dma, stream, buffer)));
         });
         byte
     });
@@ -375,7 +385,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Acc: {} {} {}\r\n",
                 a.x,
                 a.y,
@@ -387,7 +397,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Gyro: {} {} {}\r\n",
                 g.x,
                 g.y,
@@ -399,7 +409,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Mag: {} {} {}\r\n",
                 m.x,
                 m.y,
@@ -411,7 +421,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Orientation: {} {} {}\r\n",
                 e.x,
                 e.y,
@@ -423,7 +433,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Quaternion: {} {} {} {}\r\n",
                 q.x,
                 q.y,
@@ -436,7 +446,7 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
                 t,
                 r.USART2,
                 r.DMA1,
-                r.TX_BUFFER,
+                r.TX,
                 "Mag_bias: {} {} {} Mag_scl: {} {} {}\r\n",
                 mag_bias.x,
                 mag_bias.y,
@@ -450,16 +460,12 @@ fn sys_tick(t: &mut Threshold, mut r: SYS_TICK::Resources) {
 }
 
 // Interrupt for serial transmit DMA
-fn tx_done(t: &mut Threshold, r: DMA1_STREAM6::Resources) {
+fn tx_done(t: &mut Threshold, mut r: DMA1_STREAM6::Resources) {
     use rtfm::Resource;
-    // We need to unlock the DMA to use it again in the future
-    r.TX_BUFFER.claim(t, |tx, t| {
-        r.DMA1.claim(t, |dma, _| tx.release(dma).unwrap());
-    });
-    // Clear the transmit buffer so we do not retransmit old stuff
-    r.TX_BUFFER.claim_mut(t, |tx, _| {
-        let array = &**tx.deref();
-        array.borrow_mut()[..MAX_TX_LEN].clone_from_slice(&[0; MAX_TX_LEN]);
+    // Take the transmit buffer back so that it can be used again
+    let dma1 = &r.DMA1;
+    r.TX.claim_mut(t, |tx, t| {
+        dma1.claim(t, |dma, _| tx.release(dma).unwrap());
     });
 }
 
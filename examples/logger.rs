@@ -0,0 +1,75 @@
+//! Logs through the `log` facade to the ITM port 0 and to a RAM ring buffer
+//!
+//! See the `itm` example for how to capture the ITM output. The SysTick
+//! handler logs at the `trace` level, which is only enabled for its module.
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![feature(const_fn)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate cortex_m;
+extern crate cortex_m_rtfm as rtfm;
+extern crate f4;
+#[macro_use]
+extern crate log;
+
+use cortex_m::peripheral::SystClkSource;
+use f4::logger::{self, Itm, Logger, Ram};
+use log::LevelFilter;
+use rtfm::{app, Threshold};
+
+static LOGGER: Logger<(Itm, Ram<[u8; 512]>)> = Logger::new(
+    (Itm::new(0), Ram::new([0; 512])),
+    LevelFilter::Info,
+    &[("logger::tick", LevelFilter::Trace)],
+);
+
+app! {
+    device: f4::stm32f40x,
+
+    resources: {
+        static TICKS: u32 = 0;
+    },
+
+    tasks: {
+        SYS_TICK: {
+            path: tick::handler,
+            resources: [TICKS],
+        },
+    },
+}
+
+fn init(p: init::Peripherals, _r: init::Resources) {
+    logger::init(&LOGGER).unwrap();
+    info!("init");
+    debug!("filtered out");
+
+    p.SYST.set_clock_source(SystClkSource::Core);
+    p.SYST.set_reload(16_000_000);
+    p.SYST.enable_interrupt();
+    p.SYST.enable_counter();
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+mod tick {
+    use rtfm::Threshold;
+
+    use super::{SYS_TICK, LOGGER};
+
+    pub fn handler(_t: &mut Threshold, r: SYS_TICK::Resources) {
+        **r.TICKS += 1;
+        trace!("tick {}", **r.TICKS);
+
+        if **r.TICKS % 10 == 0 {
+            // inspect the RAM copy of the log, e.g. from a debugger
+            let stored = LOGGER.sink().1.dump(|a, b| a.len() + b.len());
+            info!("{} bytes of log kept in RAM", stored);
+        }
+    }
+}
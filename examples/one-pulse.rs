@@ -0,0 +1,73 @@
+//! One-pulse mode
+//!
+//! - The LED turns on at startup and a one-shot timeout on TIM3 turns it off
+//!   3.2 ms later
+//! - Every rising edge on PA1 (TIM2 CH2) produces a 100 us pulse on PA0
+//!   (TIM2 CH1), 50 us after the edge
+
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate cortex_m_rtfm as rtfm;
+extern crate f4;
+
+use f4::led::{self, LED};
+use f4::time::Microseconds;
+use f4::timer::{Edge, Trigger};
+use f4::{Channel, Pwm, Timer};
+use rtfm::{app, Threshold};
+
+app! {
+    device: f4::stm32f40x,
+
+    tasks: {
+        TIM3: {
+            path: timeout,
+            resources: [TIM3],
+        },
+    },
+}
+
+fn init(p: init::Peripherals) {
+    led::init(p.GPIOA, p.RCC);
+
+    let pwm = Pwm(p.TIM2);
+    pwm.init(
+        Microseconds(1_000),
+        Channel::_1,
+        None,
+        p.GPIOA,
+        p.GPIOB,
+        p.GPIOC,
+        p.RCC,
+    );
+    pwm.pulse_on(
+        Channel::_1,
+        Microseconds(50),
+        Microseconds(100),
+        Trigger::Ti2(Edge::Rising),
+        p.GPIOA,
+        p.GPIOB,
+        p.GPIOC,
+        p.RCC,
+    );
+
+    let timer = Timer(p.TIM3);
+    timer.init(Microseconds(3_200), p.RCC);
+    LED.on();
+    timer.one_shot(Microseconds(3_200));
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn timeout(_t: &mut Threshold, r: TIM3::Resources) {
+    // Clear the interrupt flag (RM0368, 13.4.5)
+    r.TIM3.sr.modify(|_, w| w.uif().clear_bit());
+    LED.off();
+}
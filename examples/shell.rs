@@ -0,0 +1,119 @@
+//! A command line shell on the ST-LINK virtual COM port
+//!
+//! Connect a terminal emulator at 115200 baud and type `help`.
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![feature(const_fn)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate cortex_m_rtfm as rtfm;
+extern crate f4;
+
+use core::fmt;
+
+use f4::Serial;
+use f4::led::{self, LED};
+use f4::prelude::*;
+use f4::shell::{Args, Command, Error, Shell};
+use f4::stm32f40x;
+use f4::time::Hertz;
+use rtfm::{app, Threshold};
+
+const BAUD_RATE: Hertz = Hertz(115_200);
+
+/// State shared by the commands
+pub struct State {
+    sum: i32,
+}
+
+static COMMANDS: &[Command<State>] = &[
+    Command {
+        name: "led",
+        help: "led on|off|toggle - drive the user LED",
+        handler: cmd_led,
+    },
+    Command {
+        name: "add",
+        help: "add <int> - add to the running sum",
+        handler: cmd_add,
+    },
+    Command {
+        name: "scale",
+        help: "scale <float> <float> - multiply two numbers",
+        handler: cmd_scale,
+    },
+];
+
+app! {
+    device: f4::stm32f40x,
+
+    resources: {
+        static SHELL: Shell<State> = Shell::new("f4> ", COMMANDS);
+        static STATE: State = State { sum: 0 };
+    },
+
+    tasks: {
+        USART2: {
+            path: rx,
+            resources: [SHELL, STATE, USART2],
+        },
+    },
+}
+
+/// Blocking writer over the serial port
+struct Writer<'a>(Serial<'a, stm32f40x::USART2>);
+
+impl<'a> fmt::Write for Writer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.as_bytes() {
+            while self.0.write(*byte).is_err() {}
+        }
+        Ok(())
+    }
+}
+
+fn cmd_led(_: &mut State, args: &Args, _: &mut fmt::Write) -> Result<(), Error> {
+    match args.str(1)? {
+        "on" => LED.on(),
+        "off" => LED.off(),
+        "toggle" => LED.toggle(),
+        _ => return Err(Error::InvalidArgument(1)),
+    }
+    Ok(())
+}
+
+fn cmd_add(state: &mut State, args: &Args, out: &mut fmt::Write) -> Result<(), Error> {
+    state.sum = state.sum.wrapping_add(args.int(1)?);
+    writeln!(out, "sum = {}\r", state.sum)?;
+    Ok(())
+}
+
+fn cmd_scale(_: &mut State, args: &Args, out: &mut fmt::Write) -> Result<(), Error> {
+    writeln!(out, "{}\r", args.float(1)? * args.float(2)?)?;
+    Ok(())
+}
+
+fn init(p: init::Peripherals, r: init::Resources) {
+    led::init(p.GPIOA, p.RCC);
+
+    let serial = Serial(p.USART2);
+    serial.init(BAUD_RATE.invert(), None, p.GPIOA, p.RCC);
+    serial.listen(f4::serial::Event::Rxne);
+
+    r.SHELL.prompt(&mut Writer(serial)).ok();
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn rx(_t: &mut Threshold, r: USART2::Resources) {
+    let serial = Serial(&**r.USART2);
+
+    if let Ok(byte) = serial.read() {
+        r.SHELL.feed(byte, &mut **r.STATE, &mut Writer(serial)).ok();
+    }
+}
@@ -12,11 +12,11 @@ extern crate f4;
 extern crate heapless;
 
 use core::fmt::Write;
-use core::ops::Deref;
 use f4::Serial;
 use f4::U8Writer;
 use f4::prelude::*;
-use f4::dma::{Buffer, Dma1Stream5, Dma1Stream6};
+use f4::dma::{Dma1Stream5, Dma1Streams, Transfer};
+use f4::serial::TxBuffer;
 use f4::time::Hertz;
 use f4::clock;
 use heapless::Vec;
@@ -42,8 +42,10 @@ app! {
 
     resources: {
         static CMD_BUFFER: Vec<u8, [u8; MAX_CMD_LEN]> = Vec::new();
-        static RX_BUFFER: Buffer<[u8; MAX_RX_LEN], Dma1Stream5> = Buffer::new([0; MAX_RX_LEN]);
-        static TX_BUFFER: Buffer<[u8; MAX_TX_LEN], Dma1Stream6> = Buffer::new([0; MAX_TX_LEN]);
+        static RX_BUFFER: [u8; MAX_RX_LEN] = [0; MAX_RX_LEN];
+        static TX_BUFFER: [u8; MAX_TX_LEN] = [0; MAX_TX_LEN];
+        static RX: Option<Transfer<&'static mut [u8; MAX_RX_LEN], Dma1Stream5>> = None;
+        static TX: TxBuffer<[u8; MAX_TX_LEN]> = TxBuffer::Empty;
         static CNT: u8 = 0;
     },
 
@@ -51,12 +53,12 @@ app! {
         DMA1_STREAM5: {
             path: rx_done,
             priority: 1,
-            resources: [CMD_BUFFER, RX_BUFFER, TX_BUFFER, DMA1, USART2, CNT],
+            resources: [CMD_BUFFER, RX, TX, DMA1, USART2, CNT],
         },
         DMA1_STREAM6: {
             path: tx_done,
             priority: 2,
-            resources: [TX_BUFFER, DMA1],
+            resources: [TX, DMA1],
         },
     },
 }
@@ -68,14 +70,21 @@ fn rx_done(t: &mut Threshold, mut r: DMA1_STREAM5::Resources) {
     let mut byte: u8 = 0;
     let mut cmd_type: CmdType = CmdType::None;
 
-    r.RX_BUFFER.claim(t, |rx, t| {
-        // We need to unlock the DMA to use it again in the future
-        r.DMA1.claim(t, |dma, _| rx.release(dma).unwrap());
+    // RX is claimed mutably while DMA1 and USART2 are claimed
+    let dma1 = &r.DMA1;
+    let usart2 = &r.USART2;
+    r.RX.claim_mut(t, |rx, t| {
+        // Take the buffer and the DMA stream back from the finished transfer
+        let transfer = rx.take().unwrap();
+        let (buffer, stream) = dma1.claim(t, |dma, _| {
+            transfer.is_done(dma).unwrap();
+            transfer.wait(dma)
+        });
         // Read the single character in the input buffer
-        byte = rx.deref().borrow()[0];
+        byte = buffer[0];
         // Echo the character back to the sender.
         // We do not need to use DMA to transmit.
-        r.USART2.claim(t, |usart, t| {
+        usart2.claim(t, |usart, t| {
             let serial = Serial(&**usart);
             if serial.write(byte).is_err() {
                 rtfm::bkpt();
@@ -88,8 +97,7 @@ fn rx_done(t: &mut Threshold, mut r: DMA1_STREAM5::Resources) {
                 }
             }
             // Get ready to receive again
-            r.DMA1
-                .claim(t, |dma, _| serial.read_exact(dma, rx).unwrap());
+            **rx = Some(dma1.claim(t, |dma, _| serial.read_exact(dma, stream, buffer)));
         });
     });
     // Parse the user input
@@ -122,27 +130,23 @@ fn rx_done(t: &mut Threshold, mut r: DMA1_STREAM5::Resources) {
             **r.CNT = (**r.CNT).wrapping_add(1);
             let cnt: u8 = **r.CNT;
             // Print a response using DMA
-            uprint!(t, r.USART2, r.DMA1, r.TX_BUFFER, "Hi counter {}!\r\n", cnt);
+            uprint!(t, r.USART2, r.DMA1, r.TX, "Hi counter {}!\r\n", cnt);
         }
         CmdType::Unknown => {
             // Unknown command
-            uprint!(t, r.USART2, r.DMA1, r.TX_BUFFER, "That's no greeting.\r\n");
+            uprint!(t, r.USART2, r.DMA1, r.TX, "That's no greeting.\r\n");
         }
         _ => {}
     }
 }
 
 // Interrupt for serial transmit DMA
-fn tx_done(t: &mut Threshold, r: DMA1_STREAM6::Resources) {
+fn tx_done(t: &mut Threshold, mut r: DMA1_STREAM6::Resources) {
     use rtfm::Resource;
-    // We need to unlock the DMA to use it again in the future
-    r.TX_BUFFER.claim(t, |tx, t| {
-        r.DMA1.claim(t, |dma, _| tx.release(dma).unwrap());
-    });
-    // Clear the transmit buffer so we do not retransmit old stuff
-    r.TX_BUFFER.claim_mut(t, |tx, _| {
-        let array = &**tx.deref();
-        array.borrow_mut()[..MAX_TX_LEN].clone_from_slice(&[0; MAX_TX_LEN]);
+    // Take the transmit buffer back so that it can be used again
+    let dma1 = &r.DMA1;
+    r.TX.claim_mut(t, |tx, t| {
+        dma1.claim(t, |dma, _| tx.release(dma).unwrap());
     });
 }
 
@@ -154,18 +158,23 @@ fn init(p: init::Peripherals, r: init::Resources) {
     let serial = Serial(p.USART2);
     serial.init(BAUD_RATE.invert(), Some(p.DMA1), p.GPIOA, p.RCC);
 
+    let streams = Dma1Streams::take(p.DMA1).unwrap();
+
     // FIXME: We cannot use the uprint macro in the init since it needs Resources
     // and Threshold...
-    // Send a welcome message by borrowing a slice of the transmit buffer and
-    // writing a formatted string into it.
-    write!(
-        U8Writer::new(&mut r.TX_BUFFER.borrow_mut()[..MAX_TX_LEN]),
-        "Hello, world! Say hi to me!\r\n",
-    ).unwrap();
-    serial.write_all(p.DMA1, r.TX_BUFFER).unwrap();
+    // Send a welcome message by writing a formatted string into the transmit
+    // buffer.
+    **r.TX = TxBuffer::Idle(&mut **r.TX_BUFFER, streams.s6);
+    r.TX
+        .send(serial, p.DMA1, |buf| {
+            let mut writer = U8Writer::new(buf);
+            write!(writer, "Hello, world! Say hi to me!\r\n").unwrap();
+            writer.len()
+        })
+        .unwrap();
 
     // Listen to serial input on the receive DMA
-    serial.read_exact(p.DMA1, r.RX_BUFFER).unwrap();
+    **r.RX = Some(serial.read_exact(p.DMA1, streams.s5, &mut **r.RX_BUFFER));
 }
 
 fn idle() -> ! {
@@ -0,0 +1,92 @@
+//! Continuously receive variable length frames over serial using a circular
+//! DMA transfer. A frame ends when the RX line goes idle, or when half of the
+//! ring has been filled. Every frame is echoed back byte by byte.
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![feature(const_fn)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate cortex_m_rtfm as rtfm;
+extern crate f4;
+extern crate nb;
+
+use f4::Serial;
+use f4::dma::{Dma1Stream5, Dma1Streams, RingBuffer};
+use f4::prelude::*;
+use f4::time::Hertz;
+use rtfm::{app, Threshold};
+
+const BAUD_RATE: Hertz = Hertz(115_200);
+const RX_LEN: usize = 64;
+
+app! {
+    device: f4::stm32f40x,
+
+    resources: {
+        static RX_BUFFER: [u8; RX_LEN] = [0; RX_LEN];
+        static RX_RING: Option<RingBuffer<[u8; RX_LEN], Dma1Stream5>> = None;
+    },
+
+    tasks: {
+        DMA1_STREAM5: {
+            path: rx_half,
+            priority: 1,
+            resources: [RX_RING, DMA1, USART2],
+        },
+        USART2: {
+            path: rx_idle,
+            priority: 1,
+            resources: [RX_RING, DMA1, USART2],
+        },
+    },
+}
+
+fn init(p: init::Peripherals, r: init::Resources) {
+    let serial = Serial(p.USART2);
+
+    serial.init(BAUD_RATE.invert(), Some(p.DMA1), p.GPIOA, p.RCC);
+
+    let streams = Dma1Streams::take(p.DMA1).unwrap();
+    let buffer: &'static mut [u8; RX_LEN] = &mut **r.RX_BUFFER;
+
+    **r.RX_RING = Some(serial.read_circ(p.DMA1, streams.s5, buffer));
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn echo(serial: Serial<f4::stm32f40x::USART2>, first: &[u8], second: &[u8]) {
+    for byte in first.iter().chain(second) {
+        while serial.write(*byte).is_err() {}
+    }
+}
+
+// Half transfer or transfer complete
+fn rx_half(_t: &mut Threshold, r: DMA1_STREAM5::Resources) {
+    let serial = Serial(&**r.USART2);
+
+    let ring = r.RX_RING.as_mut().unwrap();
+
+    match ring.read(r.DMA1, |first, second| echo(serial, first, second)) {
+        Err(nb::Error::Other(_)) => rtfm::bkpt(),
+        _ => {}
+    }
+}
+
+// Idle line detected
+fn rx_idle(_t: &mut Threshold, r: USART2::Resources) {
+    let serial = Serial(&**r.USART2);
+
+    if serial.clear_idle() {
+        let ring = r.RX_RING.as_mut().unwrap();
+
+    match ring.read(r.DMA1, |first, second| echo(serial, first, second)) {
+            Err(nb::Error::Other(_)) => rtfm::bkpt(),
+            _ => {}
+        }
+    }
+}
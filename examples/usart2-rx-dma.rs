@@ -9,7 +9,7 @@ extern crate cortex_m_rtfm as rtfm;
 extern crate f4;
 
 use f4::Serial;
-use f4::dma::{Buffer, Dma1Stream5};
+use f4::dma::{Dma1Stream5, Dma1Streams, Transfer};
 use f4::time::Hertz;
 use rtfm::{app, Threshold};
 
@@ -19,13 +19,14 @@ app! {
     device: f4::stm32f40x,
 
     resources: {
-        static BUFFER: Buffer<[u8; 8], Dma1Stream5> = Buffer::new([0; 8]);
+        static BUFFER: [u8; 8] = [0; 8];
+        static TRANSFER: Option<Transfer<&'static mut [u8; 8], Dma1Stream5>> = None;
     },
 
     tasks: {
         DMA1_STREAM5: {
             path: transfer_done,
-            resources: [BUFFER, DMA1],
+            resources: [TRANSFER, DMA1],
         },
     },
 }
@@ -35,7 +36,10 @@ fn init(p: init::Peripherals, r: init::Resources) {
 
     serial.init(BAUD_RATE.invert(), Some(p.DMA1), p.GPIOA, p.RCC);
 
-    serial.read_exact(p.DMA1, r.BUFFER).unwrap();
+    let streams = Dma1Streams::take(p.DMA1).unwrap();
+    let buffer: &'static mut [u8; 8] = &mut **r.BUFFER;
+
+    **r.TRANSFER = Some(serial.read_exact(p.DMA1, streams.s5, buffer));
 }
 
 fn idle() -> ! {
@@ -45,7 +49,9 @@ fn idle() -> ! {
 }
 
 fn transfer_done(_t: &mut Threshold, r: DMA1_STREAM5::Resources) {
-    r.BUFFER.release(r.DMA1).unwrap();
+    let transfer = r.TRANSFER.take().unwrap();
+    transfer.is_done(r.DMA1).unwrap();
+    let (_buffer, _stream) = transfer.wait(r.DMA1);
 
     rtfm::bkpt();
 }
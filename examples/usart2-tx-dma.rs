@@ -9,23 +9,26 @@ extern crate cortex_m_rtfm as rtfm;
 extern crate f4;
 
 use f4::Serial;
-use f4::dma::{Buffer, Dma1Stream6};
+use f4::dma::{Completion, Dma1Stream6, Dma1Streams, Done};
 use f4::time::Hertz;
 use rtfm::{app, Threshold};
 
 const BAUD_RATE: Hertz = Hertz(115_200);
 
+// Holds the transfer until the DMA1_STREAM6 interrupt reports it done
+static TX: Completion<&'static mut [u8; 15], Dma1Stream6> = Completion::new();
+
 app! {
     device: f4::stm32f40x,
 
     resources: {
-        static BUFFER: Buffer<[u8; 15], Dma1Stream6> = Buffer::new([0; 15]);
+        static BUFFER: [u8; 15] = [0; 15];
     },
 
     tasks: {
         DMA1_STREAM6: {
             path: transfer_done,
-            resources: [BUFFER, DMA1],
+            resources: [DMA1],
         },
     },
 }
@@ -34,9 +37,12 @@ fn init(p: init::Peripherals, r: init::Resources) {
     let serial = Serial(p.USART2);
 
     serial.init(BAUD_RATE.invert(), Some(p.DMA1), p.GPIOA, p.RCC);
-    r.BUFFER.borrow_mut().clone_from_slice(b"Hello, world!\r\n");
 
-    serial.write_all(p.DMA1, r.BUFFER).unwrap();
+    let streams = Dma1Streams::take(p.DMA1).unwrap();
+    let buffer: &'static mut [u8; 15] = &mut **r.BUFFER;
+    buffer.clone_from_slice(b"Hello, world!\r\n");
+
+    TX.start(serial.write_all(p.DMA1, streams.s6, buffer), Some(sent));
 }
 
 fn idle() -> ! {
@@ -46,7 +52,12 @@ fn idle() -> ! {
 }
 
 fn transfer_done(_t: &mut Threshold, r: DMA1_STREAM6::Resources) {
-    r.BUFFER.release(r.DMA1).unwrap();
+    TX.on_interrupt(r.DMA1);
+}
+
+// Called back with the buffer and the stream once the transfer is done
+fn sent(done: Done<&'static mut [u8; 15], Dma1Stream6>) {
+    done.result.unwrap();
 
     rtfm::bkpt();
 }
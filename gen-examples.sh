@@ -10,21 +10,26 @@ main() {
         button
         capture4
         concurrency
+        encoder
         hello-world
         hello
         imu
         itm
         led
+        logger
         loopback
         mco
+        one-pulse
         preemption
         pwm-control
         pwm1
         resource
         roulette
+        shell
         spi1
         timer-interrupt
         usart2-dma
+        usart2-rx-circ
         usart2-rx-dma
         usart2-tx-dma
         ws2812
@@ -22,11 +22,9 @@
 
 use core::marker::Unsize;
 
-use cast::u16;
 use hal::prelude::*;
-use static_ref::Static;
 
-use dma::{self, CircBuffer, Dma2Stream0};
+use dma::{self, CircBuffer, Dma2Stream0, DoubleBuffer};
 use stm32f40x::{ADC1, DMA2, TIM2, GPIOA, GPIOB, GPIOC, RCC};
 use {Channel, Pwm};
 
@@ -67,6 +65,19 @@ pub enum AdcChannel {
     _15 = 15,
 }
 
+// ADC1: DMA2 stream 0, channel 0 (RM0368 9.3.3 Table 28); 16-bit items,
+// circular, with the half transfer and transfer complete interrupts
+fn dma_config() -> dma::Config {
+    let mut config = dma::Config::new(dma::Channel::_0, dma::Direction::PeripheralToMemory);
+    config.memory_size = dma::Size::HalfWord;
+    config.peripheral_size = dma::Size::HalfWord;
+    config.circular = true;
+    config.half_transfer_interrupt = true;
+    config.transfer_complete_interrupt = true;
+    config.transfer_error_interrupt = true;
+    config
+}
+
 /// ADC1
 pub struct Adc<'a>(pub &'a ADC1);
 
@@ -197,7 +208,7 @@ impl<'a> Adc<'a> {
     ///
     /// NOTE `Pwm<TIM2>.init` must be called before this method because both
     /// methods configure the PA1 pin (one as input and the other as output :-/)
-    pub fn init(&self, dma2: &DMA2, rcc: &RCC) {
+    pub fn init(&self, rcc: &RCC) {
         let adc1 = self.0;
 
         // enable ADC1, DMA1, GPIOA, TIM2
@@ -212,42 +223,6 @@ impl<'a> Adc<'a> {
         // Sample time: 55.5 + 12.5 = 68 cycles
         adc1.smpr2.modify(|_, w| unsafe { w.smpx_x().bits(0) });
 
-        // ADC1
-        // chsel: Channel 0 (RM0368 9.3.3 Table 27)
-        // pl: Medium priority
-        // msize: Memory size = 16 bits
-        // psize: Peripheral size = 16 bits
-        // minc: Memory increment mode enabled
-        // pinc: Peripheral increment mode disabled
-        // circ: Circular mode enabled
-        // dir: Transfer from peripheral to memory
-        // htie: Half transfer interrupt enabled
-        // tceie: Transfer complete interrupt enabled
-        // en: Disabled
-        dma2.s0cr.write(|w| unsafe {
-            w.chsel()
-                .bits(0)
-                .pl()
-                .bits(0b01)
-                .msize()
-                .bits(0b01)
-                .psize()
-                .bits(0b01)
-                .minc()
-                .set_bit()
-                .circ()
-                .set_bit()
-                .pinc()
-                .clear_bit()
-                .dir()
-                .bits(0)
-                .htie()
-                .set_bit()
-                .tcie()
-                .set_bit()
-                .en()
-                .clear_bit()
-        });
         // RM0368 11.12.3
         // exten: Conversion on external trigger rising edge
         // extsel: Timer 2 CC2 event
@@ -290,39 +265,81 @@ impl<'a> Adc<'a> {
     /// Starts an analog to digital conversion that will be periodically
     /// triggered by the channel 2 of TIM2
     ///
-    /// The conversions will be stored in the circular `buffer`
+    /// The conversions will be stored in the circular `buffer`. Each half is
+    /// complete when the DMA2 stream 0 interrupt fires; read it with
+    /// `CircBuffer::read`, or with `CircBuffer::on_events` from the callback
+    /// of a `dma::Handler`. `CircBuffer::stop` hands the `buffer` and the
+    /// `stream` back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA2 stream 0 is in use
     pub fn start<B>(
         &self,
-        buffer: &Static<CircBuffer<B, Dma2Stream0>>,
+        buffer: &'static mut [B; 2],
         dma2: &DMA2,
+        stream: Dma2Stream0,
         pwm: Pwm<TIM2>,
-    ) -> Result<(), dma::Error>
+    ) -> CircBuffer<B, Dma2Stream0>
     where
         B: Unsize<[u16]>,
     {
         let adc1 = self.0;
 
-        if dma2.s0cr.read().en().bit_is_set() {
-            return Err(dma::Error::InUse);
-        }
-
         pwm.disable(Channel::_2);
         pwm.set_duty(Channel::_2, 1);
 
-        let buffer: &[u16] = &buffer.lock()[0];
+        let transfer = CircBuffer::start(
+            dma2,
+            stream,
+            &dma_config(),
+            &adc1.dr as *const _ as u32,
+            buffer,
+        );
 
-        dma2.s0ndtr
-            .write(|w| unsafe { w.ndt().bits(u16(buffer.len() * 2).unwrap()) });
+        pwm.enable(Channel::_2);
+
+        transfer
+    }
+
+    /// Like `start`, but the conversions are stored alternately in the two
+    /// `buffers` using the DMA double buffer mode
+    ///
+    /// Each buffer is handed out by `DoubleBuffer::swap` as soon as it's
+    /// full, in exchange for a fresh one. Call `swap` from the DMA2 stream 0
+    /// (transfer complete) interrupt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA2 stream 0 is in use
+    pub fn start_double_buffered<B>(
+        &self,
+        buffers: [&'static mut B; 2],
+        dma2: &DMA2,
+        stream: Dma2Stream0,
+        pwm: Pwm<TIM2>,
+    ) -> DoubleBuffer<B, Dma2Stream0>
+    where
+        B: Unsize<[u16]>,
+    {
+        let adc1 = self.0;
 
-        dma2.s0par
-            .write(|w| unsafe { w.bits(&adc1.dr as *const _ as u32) });
+        pwm.disable(Channel::_2);
+        pwm.set_duty(Channel::_2, 1);
 
-        dma2.s0m0ar
-            .write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
+        let mut config = dma_config();
+        // the halves of a double buffer are not split
+        config.half_transfer_interrupt = false;
+        let transfer = DoubleBuffer::start(
+            dma2,
+            stream,
+            &config,
+            &adc1.dr as *const _ as u32,
+            buffers,
+        );
 
-        dma2.s0cr.modify(|_, w| w.en().set_bit());
         pwm.enable(Channel::_2);
 
-        Ok(())
+        transfer
     }
 }
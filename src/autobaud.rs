@@ -0,0 +1,381 @@
+//! Automatic baud rate detection on the USART2 RX pin (PA3)
+//!
+//! The edges of the first received character are timestamped with the DWT
+//! cycle counter from the EXTI3 interrupt, and the bit time is derived from
+//! them (see `Method`). The result is rounded to the nearest standard baud
+//! rate, which can then be applied with `Serial::set_baud_rate`:
+//!
+//! ``` ignore
+//! // EXTI3 interrupt
+//! match autobaud.on_interrupt(exti) {
+//!     Ok(baud_rate) => serial.set_baud_rate(Hertz(baud_rate).invert()),
+//!     Err(nb::Error::WouldBlock) => {}
+//!     Err(nb::Error::Other(_)) => autobaud.start(exti),
+//! }
+//! ```
+//!
+//! PA3 keeps its USART configuration; the EXTI line listens to the pin in
+//! parallel. The remote device must send the first character while the
+//! detection is running, and the EXTI3 handler must run within one bit time
+//! (so the highest detectable rate depends on the interrupt latency; about
+//! 460800 baud at 84 MHz with `Method::StartBit`).
+//!
+//! TIM2 CH4 / TIM5 CH4 input capture could time the edges in hardware, but
+//! those channels are not supported by `Capture` yet (see `capture`).
+//!
+//! `Detector`, `baud_rate` and `nearest_standard` are pure and don't touch
+//! any peripheral.
+
+use nb;
+use stm32f40x::{DCB, DWT, EXTI, GPIOA, RCC, SYSCFG};
+
+use time::Seconds;
+
+/// Standard baud rates, in increasing order
+pub const STANDARD_RATES: [u32; 14] = [
+    300, 600, 1_200, 2_400, 4_800, 9_600, 14_400, 19_200, 38_400, 57_600, 115_200, 230_400,
+    460_800, 921_600,
+];
+
+/// Largest deviation from a standard rate that is accepted, in percent
+pub const TOLERANCE: u32 = 5;
+
+/// Autobaud error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The edges don't match the expected character
+    Pattern,
+    /// The measured rate is not close to any standard rate
+    NoMatch,
+    #[doc(hidden)] _Extensible,
+}
+
+/// What the detector measures
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    /// Width of the start bit of the first character. Requires the least
+    /// significant bit of that character to be 1 (e.g. `\r`, 0x55, 0x7F, 'a')
+    StartBit,
+    /// Time from the start bit to the stop bit of a 0x55 or 0x7F sync
+    /// character (9 bit times), which averages the edge jitter
+    SyncChar,
+}
+
+/// Turns edge timestamps into a bit time
+pub struct Detector {
+    method: Method,
+    edges: u8,
+    // timestamp of the falling edge of the start bit
+    start: u32,
+    // width of the start bit
+    bit: u32,
+}
+
+impl Detector {
+    /// Creates a detector waiting for the first falling edge
+    pub const fn new(method: Method) -> Self {
+        Detector {
+            method: method,
+            edges: 0,
+            start: 0,
+            bit: 0,
+        }
+    }
+
+    /// Forgets the edges seen so far
+    pub fn reset(&mut self) {
+        self.edges = 0;
+    }
+
+    /// Records an edge at `timestamp` (in timer ticks or cycles)
+    ///
+    /// Edges must alternate, starting with the falling edge of the start
+    /// bit, i.e. the line must be idle when the detection starts. Returns the
+    /// bit time in ticks once it's known. After an error the detector is
+    /// reset.
+    pub fn edge(&mut self, timestamp: u32) -> nb::Result<u32, Error> {
+        let n = self.edges;
+        self.edges = n.saturating_add(1);
+
+        if n == 0 {
+            self.start = timestamp;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let elapsed = timestamp.wrapping_sub(self.start);
+
+        if n == 1 {
+            if elapsed == 0 {
+                self.reset();
+                return Err(nb::Error::Other(Error::Pattern));
+            }
+
+            self.bit = elapsed;
+            return match self.method {
+                Method::StartBit => {
+                    self.reset();
+                    Ok(elapsed)
+                }
+                Method::SyncChar => Err(nb::Error::WouldBlock),
+            };
+        }
+
+        // only rising edges can end the character
+        if n % 2 == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let expected = 9 * self.bit;
+        let margin = self.bit / 2;
+
+        if elapsed + margin < expected {
+            // 0x55: rising edges at 3, 5 and 7 bit times
+            Err(nb::Error::WouldBlock)
+        } else if elapsed <= expected + margin {
+            self.reset();
+            Ok((elapsed + 4) / 9)
+        } else {
+            self.reset();
+            Err(nb::Error::Other(Error::Pattern))
+        }
+    }
+}
+
+/// Baud rate corresponding to a bit time of `bit` ticks of a `clock` Hz
+/// time base
+pub fn baud_rate(clock: u32, bit: u32) -> u32 {
+    assert!(bit != 0);
+
+    ((clock as u64 + bit as u64 / 2) / bit as u64) as u32
+}
+
+/// The standard rate closest to `baud_rate`, if it's within `TOLERANCE`
+pub fn nearest_standard(baud_rate: u32) -> Result<u32, Error> {
+    let mut best = STANDARD_RATES[0];
+    let mut best_error = u32::max_value();
+
+    for rate in STANDARD_RATES.iter() {
+        let error = if baud_rate > *rate {
+            baud_rate - *rate
+        } else {
+            *rate - baud_rate
+        };
+
+        // compare relative errors: error / rate
+        if (error as u64) * (best as u64) < (best_error as u64) * (*rate as u64) {
+            best = *rate;
+            best_error = error;
+        }
+    }
+
+    if best_error as u64 * 100 <= best as u64 * TOLERANCE as u64 {
+        Ok(best)
+    } else {
+        Err(Error::NoMatch)
+    }
+}
+
+/// Baud rate detection on PA3 using EXTI3 and the DWT cycle counter
+pub struct Autobaud {
+    detector: Detector,
+}
+
+impl Autobaud {
+    /// Creates a detector that uses `method`
+    pub const fn new(method: Method) -> Self {
+        Autobaud {
+            detector: Detector::new(method),
+        }
+    }
+
+    /// Configures EXTI3 on PA3 (both edges) and starts the cycle counter
+    ///
+    /// `start` must be called next
+    pub fn init(&self, gpioa: &GPIOA, rcc: &RCC, syscfg: &SYSCFG) {
+        rcc.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+        // RX idles high; keep a pull-up in case nothing is connected yet
+        gpioa.pupdr.modify(|_, w| unsafe { w.pupdr3().bits(0b01) });
+
+        // System configuration controller clock enable
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+        // EXTI3 = PA3, RM0368 7.2.3
+        syscfg.exticr1.modify(|_, w| unsafe { w.exti3().bits(0b0000) });
+
+        // NOTE(unsafe) read-modify-write of debug registers nobody else
+        // writes
+        unsafe {
+            // DEMCR.TRCENA, DWT_CTRL.CYCCNTENA
+            (*DCB.get()).demcr.modify(|r| r | (1 << 24));
+            (*DWT.get()).ctrl.modify(|r| r | 1);
+        }
+    }
+
+    /// Starts a detection; the line must be idle
+    pub fn start(&mut self, exti: &EXTI) {
+        self.detector.reset();
+
+        // RM0368 10.3 both edges, clear a stale pending bit, unmask
+        exti.rtsr.modify(|_, w| w.tr3().set_bit());
+        exti.ftsr.modify(|_, w| w.tr3().set_bit());
+        exti.pr.write(|w| w.pr3().set_bit());
+        exti.imr.modify(|_, w| w.mr3().set_bit());
+    }
+
+    /// Stops the detection
+    pub fn stop(&self, exti: &EXTI) {
+        exti.imr.modify(|_, w| w.mr3().clear_bit());
+        exti.rtsr.modify(|_, w| w.tr3().clear_bit());
+        exti.ftsr.modify(|_, w| w.tr3().clear_bit());
+    }
+
+    /// Handles the EXTI3 interrupt
+    ///
+    /// Returns the detected standard baud rate, after which the detection is
+    /// stopped. After an error the detection is stopped too; call `start`
+    /// again to retry.
+    pub fn on_interrupt(&mut self, exti: &EXTI) -> nb::Result<u32, Error> {
+        // NOTE(unsafe) atomic read with no side effects
+        let now = unsafe { (*DWT.get()).cyccnt.read() };
+        exti.pr.write(|w| w.pr3().set_bit());
+
+        let result = self.detector.edge(now).and_then(|bit| {
+            let clock: u32 = ::ahb1::Ticks::from(Seconds(1)).into();
+            nearest_standard(baud_rate(clock, bit)).map_err(nb::Error::Other)
+        });
+
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            _ => self.stop(exti),
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const CLOCK: u32 = 84_000_000;
+
+    // Feeds the edges of `byte` (8N1, LSB first) starting at `start`, with a
+    // bit time of `bit` ticks; `jitter` is added to every other edge
+    fn char_edges(byte: u8, start: u32, bit: f64, jitter: i32) -> Vec<u32> {
+        // line level of the start bit, data bits and stop bit
+        let mut levels = [false; 10];
+        for i in 0..8 {
+            levels[1 + i] = byte & (1 << i) != 0;
+        }
+        levels[9] = true;
+
+        let mut edges = Vec::new();
+        let mut level = true;
+        for (i, l) in levels.iter().enumerate() {
+            if *l != level {
+                level = *l;
+                let jitter = if edges.len() % 2 == 0 { 0 } else { jitter };
+                let offset = (i as f64 * bit) as i32 + jitter;
+                edges.push(start.wrapping_add(offset as u32));
+            }
+        }
+        edges
+    }
+
+    fn detect(detector: &mut Detector, edges: &[u32]) -> nb::Result<u32, Error> {
+        let mut result = Err(nb::Error::WouldBlock);
+        for edge in edges {
+            result = detector.edge(*edge);
+            match result {
+                Err(nb::Error::WouldBlock) => {}
+                _ => break,
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn start_bit() {
+        let mut detector = Detector::new(Method::StartBit);
+        let bit = CLOCK as f64 / 115_200.;
+
+        for byte in &[b'\r', 0x55, 0x7f, b'a'] {
+            let edges = char_edges(*byte, 1_000, bit, 0);
+            assert_eq!(detect(&mut detector, &edges), Ok(729));
+        }
+
+        // across a wrap around of the counter
+        let edges = char_edges(b'\r', u32::max_value() - 100, bit, 0);
+        assert_eq!(detect(&mut detector, &edges), Ok(729));
+
+        // two edges at the same time
+        assert_eq!(
+            detect(&mut detector, &[5, 5]),
+            Err(nb::Error::Other(Error::Pattern))
+        );
+        assert_eq!(detect(&mut detector, &[5, 10]), Ok(5));
+    }
+
+    #[test]
+    fn sync_char() {
+        let mut detector = Detector::new(Method::SyncChar);
+
+        for &(rate, bit) in &[(9_600, 8_750), (115_200, 729), (460_800, 182)] {
+            let exact = CLOCK as f64 / rate as f64;
+            for byte in &[0x55, 0x7f] {
+                let edges = char_edges(*byte, 12_345, exact, 0);
+                assert_eq!(detect(&mut detector, &edges), Ok(bit));
+
+                // rising edges 5% of a bit early or late; the jitter of the
+                // last edge is spread over 9 bits
+                for sign in &[-1., 1.] {
+                    let jitter = (sign * exact / 20.) as i32;
+                    let edges = char_edges(*byte, 12_345, exact, jitter);
+                    let measured = detect(&mut detector, &edges).unwrap();
+                    assert!((measured as i32 - bit as i32).abs() <= bit as i32 / 100);
+                    assert_eq!(nearest_standard(baud_rate(CLOCK, measured)), Ok(rate));
+                }
+            }
+        }
+
+        // no rising edge around 9 bit times: a break
+        assert_eq!(
+            detect(&mut detector, &[0, 100, 200, 1_000]),
+            Err(nb::Error::Other(Error::Pattern))
+        );
+        // the detector starts over after an error
+        let edges = char_edges(0x55, 0, 729.17, 0);
+        assert_eq!(detect(&mut detector, &edges), Ok(729));
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(baud_rate(CLOCK, 729), 115_226);
+        assert_eq!(baud_rate(CLOCK, 8_750), 9_600);
+        // rounded to the nearest
+        assert_eq!(baud_rate(10, 3), 3);
+        assert_eq!(baud_rate(11, 2), 6);
+        assert_eq!(baud_rate(u32::max_value(), 1), u32::max_value());
+    }
+
+    #[test]
+    fn standard_rates() {
+        for rate in STANDARD_RATES.iter() {
+            assert_eq!(nearest_standard(*rate), Ok(*rate));
+            // within the tolerance, in both directions
+            assert_eq!(nearest_standard(*rate / 100 * 104), Ok(*rate));
+            assert_eq!(nearest_standard(*rate / 100 * 96), Ok(*rate));
+        }
+
+        assert_eq!(nearest_standard(115_226), Ok(115_200));
+        // between 14400 and 19200, but too far from both
+        assert_eq!(nearest_standard(16_800), Err(Error::NoMatch));
+        assert_eq!(nearest_standard(9_800), Ok(9_600));
+        assert_eq!(nearest_standard(11_800), Err(Error::NoMatch));
+        assert_eq!(nearest_standard(0), Err(Error::NoMatch));
+        assert_eq!(nearest_standard(280), Err(Error::NoMatch));
+        assert_eq!(nearest_standard(1_000_000), Err(Error::NoMatch));
+        assert_eq!(nearest_standard(u32::max_value()), Err(Error::NoMatch));
+    }
+}
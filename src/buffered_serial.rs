@@ -0,0 +1,233 @@
+//! Interrupt driven buffered serial interface
+//!
+//! The USART interrupt handler moves bytes between the data register and two
+//! statically allocated `spsc::Queue`s, so the application can read and
+//! write without polling the peripheral and without a DMA stream.
+//!
+//! ``` ignore
+//! static mut RX: Queue<u8, [u8; 64]> = Queue::new([0; 64]);
+//! static mut TX: Queue<u8, [u8; 128]> = Queue::new([0; 128]);
+//! static COUNTERS: Counters = Counters::new();
+//!
+//! let (port, handler) = buffered_serial::split(
+//!     Serial(usart2),
+//!     unsafe { &mut RX },
+//!     unsafe { &mut TX },
+//!     &COUNTERS,
+//! );
+//! // move `handler` to the USART2 interrupt and call `handler.on_interrupt()`
+//! // from it; use `port` everywhere else
+//! ```
+
+use core::any::Any;
+use core::marker::Unsize;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use nb;
+
+use serial::{Event, Serial, Usart};
+use spsc::{Consumer, Producer, Queue};
+
+/// Overflow and error counters of a buffered serial port
+pub struct Counters {
+    rx_overflow: AtomicUsize,
+    overrun: AtomicUsize,
+}
+
+impl Counters {
+    /// Creates a set of zeroed counters
+    pub const fn new() -> Self {
+        Counters {
+            rx_overflow: AtomicUsize::new(0),
+            overrun: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of received bytes dropped because the RX queue was full
+    pub fn rx_overflow(&self) -> usize {
+        self.rx_overflow.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes lost because the interrupt handler didn't read the
+    /// data register in time (USART overrun)
+    pub fn overrun(&self) -> usize {
+        self.overrun.load(Ordering::Relaxed)
+    }
+
+    /// Resets all the counters to zero
+    pub fn reset(&self) {
+        self.rx_overflow.store(0, Ordering::Relaxed);
+        self.overrun.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Splits a serial interface into its application and interrupt halves
+///
+/// RX interrupts are enabled right away; TX interrupts are enabled whenever
+/// there is data to send.
+pub fn split<'a, U, RB, TB>(
+    serial: Serial<'a, U>,
+    rx: &'a mut Queue<u8, RB>,
+    tx: &'a mut Queue<u8, TB>,
+    counters: &'a Counters,
+) -> (Port<'a, U, RB, TB>, Handler<'a, U, RB, TB>)
+where
+    U: Any + Usart,
+    RB: Unsize<[u8]>,
+    TB: Unsize<[u8]>,
+{
+    let (rx_producer, rx_consumer) = rx.split();
+    let (tx_producer, tx_consumer) = tx.split();
+
+    serial.listen(Event::Rxne);
+
+    (
+        Port {
+            serial: serial,
+            rx: rx_consumer,
+            tx: tx_producer,
+            counters: counters,
+        },
+        Handler {
+            serial: serial,
+            rx: rx_producer,
+            tx: tx_consumer,
+            counters: counters,
+        },
+    )
+}
+
+/// Application half of a buffered serial interface
+pub struct Port<'a, U, RB, TB>
+where
+    U: 'a + Any + Usart,
+    RB: 'a + Unsize<[u8]>,
+    TB: 'a + Unsize<[u8]>,
+{
+    serial: Serial<'a, U>,
+    rx: Consumer<'a, u8, RB>,
+    tx: Producer<'a, u8, TB>,
+    counters: &'a Counters,
+}
+
+impl<'a, U, RB, TB> Port<'a, U, RB, TB>
+where
+    U: Any + Usart,
+    RB: Unsize<[u8]>,
+    TB: Unsize<[u8]>,
+{
+    /// Moves received bytes into `buffer`, returns how many were copied
+    ///
+    /// Returns `WouldBlock` if no data has been received
+    pub fn read(&self, buffer: &mut [u8]) -> nb::Result<usize, !> {
+        let mut n = 0;
+
+        for slot in buffer.iter_mut() {
+            match self.rx.dequeue() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            n += 1;
+        }
+
+        if n == 0 && !buffer.is_empty() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(n)
+        }
+    }
+
+    /// Queues all of `bytes` for transmission
+    ///
+    /// Nothing is queued, and `WouldBlock` is returned, if the TX queue
+    /// doesn't have room for all of `bytes` right now
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than the TX queue capacity
+    pub fn write_all(&self, bytes: &[u8]) -> nb::Result<(), !> {
+        assert!(bytes.len() <= self.tx.capacity());
+
+        if self.tx.free() < bytes.len() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        for byte in bytes {
+            // NOTE(ok) only the interrupt handler dequeues so there's still
+            // room for every byte
+            self.tx.enqueue(*byte).ok();
+        }
+
+        self.serial.listen(Event::Txe);
+
+        Ok(())
+    }
+
+    /// Waits until every queued byte has left the shift register
+    pub fn flush(&self) -> nb::Result<(), !> {
+        if self.tx.free() == self.tx.capacity() && self.serial.0.sr.read().tc().bit_is_set() {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Overflow counters shared with the interrupt handler
+    pub fn counters(&self) -> &Counters {
+        self.counters
+    }
+}
+
+/// Interrupt half of a buffered serial interface
+pub struct Handler<'a, U, RB, TB>
+where
+    U: 'a + Any + Usart,
+    RB: 'a + Unsize<[u8]>,
+    TB: 'a + Unsize<[u8]>,
+{
+    serial: Serial<'a, U>,
+    rx: Producer<'a, u8, RB>,
+    tx: Consumer<'a, u8, TB>,
+    counters: &'a Counters,
+}
+
+impl<'a, U, RB, TB> Handler<'a, U, RB, TB>
+where
+    U: Any + Usart,
+    RB: Unsize<[u8]>,
+    TB: Unsize<[u8]>,
+{
+    /// Services the RXNE and TXE events; call this from the USART interrupt
+    pub fn on_interrupt(&self) {
+        let usart = self.serial.0;
+        let sr = usart.sr.read();
+
+        // NOTE reading DR clears RXNE and, following the SR read above, the
+        // ORE, NF and FE flags as well
+        if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+            if sr.ore().bit_is_set() {
+                self.counters.overrun.fetch_add(1, Ordering::Relaxed);
+            }
+            // also keep the per-USART statistics, see `Serial::errors`
+            self.serial.count_errors(&sr);
+
+            // NOTE(read_volatile) see NOTE in `Serial::read`
+            let byte = unsafe { ptr::read_volatile(&usart.dr as *const _ as *const u8) };
+
+            if sr.rxne().bit_is_set() && self.rx.enqueue(byte).is_err() {
+                self.counters.rx_overflow.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        if sr.txe().bit_is_set() && usart.cr1.read().txeie().bit_is_set() {
+            match self.tx.dequeue() {
+                // NOTE(write_volatile) see NOTE in `Serial::read`
+                Some(byte) => unsafe {
+                    ptr::write_volatile(&usart.dr as *const _ as *mut u8, byte)
+                },
+                None => self.serial.unlisten(Event::Txe),
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Cyclic Redundancy Checks
+//!
+//! Bitwise software implementations of the CRCs used by the serial
+//! protocols in this crate. The `*_update` functions work on the raw CRC
+//! register so that a checksum can be computed over data that arrives in
+//! pieces; the other functions compute the checksum of a whole slice.
+
+/// CRC-16/CCITT-FALSE: poly 0x1021, init 0xFFFF, not reflected
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    crc16_ccitt_update(0xffff, data)
+}
+
+/// CRC-16/XMODEM: poly 0x1021, init 0x0000, not reflected
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+    crc16_ccitt_update(0x0000, data)
+}
+
+/// Feeds `data` to a CRC register using the CCITT polynomial 0x1021 (MSB
+/// first)
+pub fn crc16_ccitt_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-16/MODBUS: poly 0x8005 reflected (0xA001), init 0xFFFF
+///
+/// The result is transmitted low byte first
+pub fn crc16_modbus(data: &[u8]) -> u16 {
+    crc16_modbus_update(0xffff, data)
+}
+
+/// Feeds `data` to a CRC register using the reflected polynomial 0xA001
+/// (LSB first)
+pub fn crc16_modbus_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 0x0001 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and Ethernet)
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Feeds `data` to a CRC-32 register using the reflected polynomial
+/// 0xEDB88320. Start with `!0` and invert the final value.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The "check" values of the Catalogue of parametrised CRC algorithms
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn check() {
+        assert_eq!(crc16_ccitt(CHECK), 0x29b1);
+        assert_eq!(crc16_xmodem(CHECK), 0x31c3);
+        assert_eq!(crc16_modbus(CHECK), 0x4b37);
+        assert_eq!(crc32(CHECK), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(crc16_ccitt(&[]), 0xffff);
+        assert_eq!(crc16_xmodem(&[]), 0);
+        assert_eq!(crc16_modbus(&[]), 0xffff);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn pieces() {
+        let (a, b) = CHECK.split_at(4);
+
+        assert_eq!(crc16_ccitt_update(crc16_ccitt(a), b), 0x29b1);
+        assert_eq!(crc16_ccitt_update(crc16_xmodem(a), b), 0x31c3);
+        assert_eq!(crc16_modbus_update(crc16_modbus(a), b), 0x4b37);
+        assert_eq!(!crc32_update(crc32_update(!0, a), b), 0xcbf4_3926);
+    }
+}
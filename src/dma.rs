@@ -1,14 +1,71 @@
 //! Direct Memory Access (DMA)
+//!
+//! Each of the 16 streams (8 per controller) is a type that implements
+//! `Stream`. A driver picks the stream and the channel (request) it needs
+//! from RM0368 9.3.3 Tables 27 and 28, writes a `Config` with
+//! `Stream::configure` and starts transfers with `Stream::start`:
+//!
+//! ``` ignore
+//! let mut config = Config::new(Channel::_4, Direction::MemoryToPeripheral);
+//! config.transfer_complete_interrupt = true;
+//! stream.configure(dma1, &config);
+//! stream.start(dma1, &usart2.dr as *const _ as u32, address, len);
+//! ```
+//!
+//! Streams run in direct mode unless `Config::fifo_threshold` is set; the
+//! FIFO allows bursts (`Config::memory_burst`) and packing items of
+//! different sizes. `Stream::status` decodes the flags of a stream, and the
+//! transfer types report FIFO and direct mode errors as well as transfer
+//! errors.
+//!
+//! The stream types are also tokens: `Dma1Streams::take` and
+//! `Dma2Streams::take` hand out one instance of each, once, and `configure`
+//! and `start` need the token. Drivers that start one-shot transfers take
+//! the token and a `&'static mut` buffer and return a `Transfer`, which
+//! gives both back when it's over:
+//!
+//! ``` ignore
+//! let streams = Dma1Streams::take(dma1).unwrap();
+//! let transfer = serial.write_all(dma1, streams.s6, buffer);
+//! // `buffer` can't be touched while the DMA reads it
+//! let (buffer, stream) = transfer.wait(dma1);
+//! ```
+//!
+//! Instead of polling, the interrupt handler of a stream can finish a
+//! transfer: a `Completion` hands the buffer and the stream of a one-shot
+//! transfer to a callback, and a `Handler` calls a function on every event
+//! of a circular one.
+//!
+//! For continuous transfers, `CircBuffer` splits one buffer in halves at
+//! the half transfer event, `RingBuffer` hands out whatever arrived since the
+//! last read and `DoubleBuffer` uses the hardware double buffer mode, where
+//! the halves are separate buffers that can be swapped. Like `Transfer`, they
+//! own the buffer and the stream until they are stopped.
+//!
+//! `copy` and `fill` are `memcpy` and `memset` on a DMA2 stream:
+//!
+//! ``` ignore
+//! let config = CopyConfig::new();
+//! let transfer = dma::fill(dma2, streams.s1, &config, 0u32, framebuffer, rcc);
+//! // ..
+//! let (framebuffer, stream) = transfer.wait(dma2);
+//! ```
 
 use core::cell::{Cell, UnsafeCell};
-use core::marker::PhantomData;
-use core::ops;
+use core::marker::{PhantomData, Unsize};
+use core::{cmp, mem};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
+use cast::{u16, usize};
+use cortex_m::interrupt;
 use nb;
-use stm32f40x::{DMA1, DMA2};
+use stm32f40x::{DMA1, DMA2, RCC};
+use volatile_register::{RO, RW, WO};
+
+use ring::RingIndex;
 
 /// DMA error
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Error {
     /// DMA channel in use
     InUse,
@@ -17,458 +74,1857 @@ pub enum Error {
     Overrun,
     /// Transfer error
     Transfer,
+    /// FIFO overrun or underrun
+    Fifo,
+    /// Direct mode error: a new request arrived before the previous item
+    /// was transferred
+    DirectMode,
 }
 
-/// Stream 1 of DMA1
-pub struct Dma1Stream1 {
-    _0: (),
+// RM0368 9.5.11 DMA register map
+#[repr(C)]
+struct Registers {
+    // LISR, HISR
+    isr: [RO<u32>; 2],
+    // LIFCR, HIFCR
+    ifcr: [WO<u32>; 2],
+    streams: [StreamRegisters; 8],
 }
 
-/// Stream 2 of DMA1
-pub struct Dma1Stream2 {
-    _0: (),
+#[repr(C)]
+struct StreamRegisters {
+    cr: RW<u32>,
+    ndtr: RW<u32>,
+    par: RW<u32>,
+    m0ar: RW<u32>,
+    m1ar: RW<u32>,
+    fcr: RW<u32>,
 }
 
-/// Stream 4 of DMA1
-pub struct Dma1Stream4 {
-    _0: (),
+// SxCR bits, RM0368 9.5.5
+const CR_EN: u32 = 1 << 0;
+const CR_DMEIE: u32 = 1 << 1;
+const CR_TEIE: u32 = 1 << 2;
+const CR_HTIE: u32 = 1 << 3;
+const CR_TCIE: u32 = 1 << 4;
+const CR_CIRC: u32 = 1 << 8;
+const CR_PINC: u32 = 1 << 9;
+const CR_MINC: u32 = 1 << 10;
+const CR_DBM: u32 = 1 << 18;
+const CR_CT: u32 = 1 << 19;
+
+// SxFCR bits, RM0368 9.5.10
+const FCR_DMDIS: u32 = 1 << 2;
+const FCR_FEIE: u32 = 1 << 7;
+
+/// Interrupt status flag of a stream
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flag {
+    /// FIFO error (FEIF)
+    FifoError,
+    /// Direct mode error (DMEIF)
+    DirectModeError,
+    /// Transfer error (TEIF)
+    TransferError,
+    /// Half transfer (HTIF)
+    HalfTransfer,
+    /// Transfer complete (TCIF)
+    TransferComplete,
 }
 
-/// Stream 5 of DMA1
-pub struct Dma1Stream5 {
-    _0: (),
+impl Flag {
+    // position in the 6-bit group of a stream
+    fn mask(&self) -> u32 {
+        match *self {
+            Flag::FifoError => 1 << 0,
+            Flag::DirectModeError => 1 << 2,
+            Flag::TransferError => 1 << 3,
+            Flag::HalfTransfer => 1 << 4,
+            Flag::TransferComplete => 1 << 5,
+        }
+    }
 }
 
-/// Stream 6 of DMA1
-pub struct Dma1Stream6 {
-    _0: (),
-}
+// all the flags of a stream
+const ALL_FLAGS: u32 = 0b11_1101;
 
-/// Stream 0 of DMA2
-pub struct Dma2Stream0 {
-    _0: (),
+/// FIFO level of a stream
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FifoLevel {
+    /// Empty
+    Empty,
+    /// Less than 1/4 full
+    BelowQuarter,
+    /// Between 1/4 and 1/2 full
+    BelowHalf,
+    /// Between 1/2 and 3/4 full
+    BelowThreeQuarters,
+    /// More than 3/4 full
+    BelowFull,
+    /// Full
+    Full,
 }
 
-/// Buffer to be used with a certain DMA `STREAM`
-// NOTE(packed) workaround for rust-lang/rust#41315
-#[repr(packed)]
-pub struct Buffer<T, STREAM> {
-    data: UnsafeCell<T>,
-    flag: Cell<BorrowFlag>,
-    state: Cell<State>,
-    _marker: PhantomData<STREAM>,
+/// The interrupt flags and FIFO level of a stream
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Status {
+    /// FIFO error (FEIF)
+    pub fifo_error: bool,
+    /// Direct mode error (DMEIF)
+    pub direct_mode_error: bool,
+    /// Transfer error (TEIF)
+    pub transfer_error: bool,
+    /// Half transfer (HTIF)
+    pub half_transfer: bool,
+    /// Transfer complete (TCIF)
+    pub transfer_complete: bool,
+    /// FIFO level, `None` in direct mode
+    pub fifo_level: Option<FifoLevel>,
 }
 
+/// Events of a stream, decoded from its flags by `Stream::events`
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum State {
-    // A new `Buffer` starts in this state. We set it to zero to place this
-    // buffer in the .bss section
-    Unlocked = 0,
+pub struct Events {
+    /// Half transfer
+    pub half_transfer: bool,
+    /// Transfer complete
+    pub transfer_complete: bool,
+    /// Error, see `Status::error`
+    pub error: Option<Error>,
+}
 
-    Locked,
-    MutLocked,
+impl Status {
+    /// The error reported by the flags, if any
+    ///
+    /// FIFO errors are only reported in FIFO mode and direct mode errors in
+    /// direct mode; the other flag is meaningless in each mode.
+    pub fn error(&self) -> Option<Error> {
+        if self.transfer_error {
+            Some(Error::Transfer)
+        } else if self.fifo_level.is_some() && self.fifo_error {
+            Some(Error::Fifo)
+        } else if self.fifo_level.is_none() && self.direct_mode_error {
+            Some(Error::DirectMode)
+        } else {
+            None
+        }
+    }
 }
 
-type BorrowFlag = usize;
+/// Channel (request) selection, see RM0368 9.3.3 Tables 27 and 28
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Channel {
+    /// Channel 0
+    _0,
+    /// Channel 1
+    _1,
+    /// Channel 2
+    _2,
+    /// Channel 3
+    _3,
+    /// Channel 4
+    _4,
+    /// Channel 5
+    _5,
+    /// Channel 6
+    _6,
+    /// Channel 7
+    _7,
+}
 
-const UNUSED: BorrowFlag = 0;
-const WRITING: BorrowFlag = !0;
+/// Stream priority
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// Low
+    Low,
+    /// Medium
+    Medium,
+    /// High
+    High,
+    /// Very high
+    VeryHigh,
+}
 
-/// Wraps a borrowed reference to a value in a `Buffer`
-pub struct Ref<'a, T>
-where
-    T: 'a,
-{
-    data: &'a T,
-    flag: &'a Cell<BorrowFlag>,
+/// Transfer direction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the peripheral (PAR) to memory (M0AR)
+    PeripheralToMemory,
+    /// From memory (M0AR) to the peripheral (PAR)
+    MemoryToPeripheral,
+    /// From PAR to M0AR, both memory; DMA2 only
+    MemoryToMemory,
 }
 
-impl<'a, T> ops::Deref for Ref<'a, T> {
-    type Target = T;
+/// Size of a data item
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Size {
+    /// 8 bits
+    Byte,
+    /// 16 bits
+    HalfWord,
+    /// 32 bits
+    Word,
+}
 
-    fn deref(&self) -> &T {
-        self.data
+impl Size {
+    fn bytes(&self) -> usize {
+        match *self {
+            Size::Byte => 1,
+            Size::HalfWord => 2,
+            Size::Word => 4,
+        }
     }
-}
 
-impl<'a, T> Drop for Ref<'a, T> {
-    fn drop(&mut self) {
-        self.flag.set(self.flag.get() - 1);
+    fn bits(&self) -> u32 {
+        match *self {
+            Size::Byte => 0b00,
+            Size::HalfWord => 0b01,
+            Size::Word => 0b10,
+        }
     }
 }
 
-/// A wrapper type for a mutably borrowed value from a `Buffer``
-pub struct RefMut<'a, T>
-where
-    T: 'a,
-{
-    data: &'a mut T,
-    flag: &'a Cell<BorrowFlag>,
+/// Burst length of the FIFO transfers, in data items
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Burst {
+    /// One item at a time
+    Single,
+    /// 4 items
+    Incr4,
+    /// 8 items
+    Incr8,
+    /// 16 items
+    Incr16,
 }
 
-impl<'a, T> ops::Deref for RefMut<'a, T> {
-    type Target = T;
+impl Burst {
+    fn beats(&self) -> usize {
+        match *self {
+            Burst::Single => 1,
+            Burst::Incr4 => 4,
+            Burst::Incr8 => 8,
+            Burst::Incr16 => 16,
+        }
+    }
 
-    fn deref(&self) -> &T {
-        self.data
+    fn bits(&self) -> u32 {
+        match *self {
+            Burst::Single => 0b00,
+            Burst::Incr4 => 0b01,
+            Burst::Incr8 => 0b10,
+            Burst::Incr16 => 0b11,
+        }
     }
-}
 
-impl<'a, T> ops::DerefMut for RefMut<'a, T> {
-    fn deref_mut(&mut self) -> &mut T {
-        self.data
+    // the longest burst of at most `beats` items
+    fn at_most(beats: usize) -> Self {
+        match beats {
+            0...3 => Burst::Single,
+            4...7 => Burst::Incr4,
+            8...15 => Burst::Incr8,
+            _ => Burst::Incr16,
+        }
     }
 }
 
-impl<'a, T> Drop for RefMut<'a, T> {
-    fn drop(&mut self) {
-        self.flag.set(UNUSED);
-    }
+/// FIFO threshold: the FIFO level that triggers a memory transfer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Threshold {
+    /// 1/4 full, 4 bytes
+    Quarter,
+    /// 1/2 full, 8 bytes
+    Half,
+    /// 3/4 full, 12 bytes
+    ThreeQuarters,
+    /// Full, 16 bytes
+    Full,
 }
 
-impl<T, STREAM> Buffer<T, STREAM> {
-    /// Creates a new buffer
-    pub const fn new(data: T) -> Self {
-        Buffer {
-            _marker: PhantomData,
-            data: UnsafeCell::new(data),
-            flag: Cell::new(0),
-            state: Cell::new(State::Unlocked),
+impl Threshold {
+    fn bytes(&self) -> usize {
+        match *self {
+            Threshold::Quarter => 4,
+            Threshold::Half => 8,
+            Threshold::ThreeQuarters => 12,
+            Threshold::Full => 16,
         }
     }
 
-    /// Immutably borrows the wrapped value.
-    ///
-    /// The borrow lasts until the returned `Ref` exits scope. Multiple
-    /// immutable borrows can be taken out at the same time.
-    ///
-    /// # Panics
+    fn bits(&self) -> u32 {
+        match *self {
+            Threshold::Quarter => 0b00,
+            Threshold::Half => 0b01,
+            Threshold::ThreeQuarters => 0b10,
+            Threshold::Full => 0b11,
+        }
+    }
+}
+
+/// Stream configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Request that drives the stream
+    pub channel: Channel,
+    /// Priority against the other streams of the same controller
+    pub priority: Priority,
+    /// Transfer direction
+    pub direction: Direction,
+    /// Size of the memory data items
+    pub memory_size: Size,
+    /// Size of the peripheral data items; NDTR counts these
+    pub peripheral_size: Size,
+    /// Increment the memory address after each item
+    pub memory_increment: bool,
+    /// Increment the peripheral address after each item
+    pub peripheral_increment: bool,
+    /// Restart from the beginning of the buffer after the last item
+    pub circular: bool,
+    /// Interrupt on the half transfer event
+    pub half_transfer_interrupt: bool,
+    /// Interrupt on the transfer complete event
+    pub transfer_complete_interrupt: bool,
+    /// Interrupt on transfer errors
+    pub transfer_error_interrupt: bool,
+    /// FIFO threshold, or `None` for direct mode, where each item goes
+    /// straight from the source to the destination
     ///
-    /// Panics if the value is currently mutably borrowed.
-    pub fn borrow(&self) -> Ref<T> {
-        unsafe {
-            assert_ne!(self.flag.get(), WRITING);
+    /// Memory-to-memory transfers need the FIFO.
+    pub fifo_threshold: Option<Threshold>,
+    /// Burst length on the memory side; FIFO mode only
+    pub memory_burst: Burst,
+    /// Burst length on the peripheral side; FIFO mode only
+    pub peripheral_burst: Burst,
+    /// Interrupt on FIFO errors
+    pub fifo_error_interrupt: bool,
+    /// Interrupt on direct mode errors
+    pub direct_mode_error_interrupt: bool,
+}
 
-            self.flag.set(self.flag.get() + 1);
+impl Config {
+    /// Byte transfers in `direction` through `channel`: medium priority,
+    /// memory address increment, not circular, direct mode and no
+    /// interrupts
+    pub fn new(channel: Channel, direction: Direction) -> Self {
+        Config {
+            channel: channel,
+            priority: Priority::Medium,
+            direction: direction,
+            memory_size: Size::Byte,
+            peripheral_size: Size::Byte,
+            memory_increment: true,
+            peripheral_increment: false,
+            circular: false,
+            half_transfer_interrupt: false,
+            transfer_complete_interrupt: false,
+            transfer_error_interrupt: false,
+            fifo_threshold: None,
+            memory_burst: Burst::Single,
+            peripheral_burst: Burst::Single,
+            fifo_error_interrupt: false,
+            direct_mode_error_interrupt: false,
+        }
+    }
 
-            Ref {
-                data: &*self.data.get(),
-                flag: &self.flag,
+    /// `true` if the FIFO threshold is compatible with the memory burst
+    ///
+    /// A memory burst must fit in the FIFO and the threshold must be a
+    /// whole number of bursts (RM0368 9.3.14 Table 43). Always `true` in
+    /// direct mode, where bursts are not used.
+    pub fn is_valid(&self) -> bool {
+        match self.fifo_threshold {
+            None => true,
+            Some(threshold) => {
+                let burst = self.memory_burst.beats() * self.memory_size.bytes();
+
+                burst <= 16 && threshold.bytes() % burst == 0
             }
         }
     }
 
-    /// Mutably borrows the wrapped value.
-    ///
-    /// The borrow lasts until the returned `RefMut` exits scope. The value
-    /// cannot be borrowed while this borrow is active.
+    // SxCR value, EN cleared
+    fn cr(&self) -> u32 {
+        let dir = match self.direction {
+            Direction::PeripheralToMemory => 0b00,
+            Direction::MemoryToPeripheral => 0b01,
+            Direction::MemoryToMemory => 0b10,
+        };
+        let pl = match self.priority {
+            Priority::Low => 0b00,
+            Priority::Medium => 0b01,
+            Priority::High => 0b10,
+            Priority::VeryHigh => 0b11,
+        };
+
+        let mut cr = (self.channel as u32) << 25 | pl << 16 | self.memory_size.bits() << 13
+            | self.peripheral_size.bits() << 11 | dir << 6;
+
+        if self.memory_increment {
+            cr |= CR_MINC;
+        }
+        if self.peripheral_increment {
+            cr |= CR_PINC;
+        }
+        if self.circular {
+            cr |= CR_CIRC;
+        }
+        if self.half_transfer_interrupt {
+            cr |= CR_HTIE;
+        }
+        if self.transfer_complete_interrupt {
+            cr |= CR_TCIE;
+        }
+        if self.transfer_error_interrupt {
+            cr |= CR_TEIE;
+        }
+        if self.direct_mode_error_interrupt {
+            cr |= CR_DMEIE;
+        }
+        if self.fifo_threshold.is_some() {
+            cr |= self.memory_burst.bits() << 23 | self.peripheral_burst.bits() << 21;
+        }
+
+        cr
+    }
+
+    // SxFCR value
+    fn fcr(&self) -> u32 {
+        let mut fcr = match self.fifo_threshold {
+            Some(threshold) => FCR_DMDIS | threshold.bits(),
+            // reset value
+            None => Threshold::Half.bits(),
+        };
+
+        if self.fifo_error_interrupt {
+            fcr |= FCR_FEIE;
+        }
+
+        fcr
+    }
+}
+
+/// A DMA stream
+///
+/// Implemented by the stream types of this module (`Dma1Stream0` to
+/// `Dma2Stream7`); the registers and interrupt flags of the stream are
+/// derived from its number. The methods take a reference to the DMA
+/// controller as proof of access and must not be overridden. `configure` and
+/// `start` also take the stream token, so that a stream owned by a running
+/// transfer can't be reconfigured or restarted.
+pub unsafe trait Stream {
+    /// DMA controller this stream belongs to (`DMA1` or `DMA2`)
+    type Dma;
+
+    /// Stream number, 0 - 7
+    const NUMBER: u8;
+
+    #[doc(hidden)]
+    fn address() -> usize;
+
+    /// Writes the configuration; the stream is stopped first and its flags
+    /// cleared
     ///
     /// # Panics
     ///
-    /// Panics if the value is currently borrowed.
-    pub fn borrow_mut(&self) -> RefMut<T> {
-        unsafe {
-            assert_eq!(self.flag.get(), UNUSED);
+    /// Panics if the configuration is not valid (see `Config::is_valid`)
+    fn configure(&mut self, dma: &Self::Dma, config: &Config) {
+        assert!(config.is_valid(), "FIFO threshold incompatible with the burst");
 
-            self.flag.set(WRITING);
+        Self::stop(dma);
+        Self::clear_all(dma);
 
-            RefMut {
-                data: &mut *self.data.get(),
-                flag: &self.flag,
-            }
+        // NOTE(unsafe) the stream is disabled, its registers are writable
+        unsafe {
+            stream::<Self>().cr.write(config.cr());
+            stream::<Self>().fcr.write(config.fcr());
         }
     }
 
-    pub(crate) fn lock(&self) -> &T {
-        assert_eq!(self.state.get(), State::Unlocked);
-        unsafe { assert_ne!(self.flag.get(), WRITING) };
+    /// Starts a transfer of `len` items between `peripheral` and `memory`
+    /// using the current configuration
+    ///
+    /// For memory-to-memory transfers `peripheral` is the source address.
+    fn start(&mut self, dma: &Self::Dma, peripheral: u32, memory: u32, len: u16) {
+        let stream = stream::<Self>();
+
+        Self::clear_all(dma);
 
-        unsafe { self.flag.set(self.flag.get() + 1) };
-        self.state.set(State::Locked);
+        // the memory writes that precede the transfer must not be reordered
+        // after the enable
+        compiler_fence(Ordering::Release);
 
-        unsafe { &*self.data.get() }
+        // NOTE(unsafe) the caller guarantees that the addresses are valid for
+        // the whole transfer
+        unsafe {
+            stream.ndtr.write(len as u32);
+            stream.par.write(peripheral);
+            stream.m0ar.write(memory);
+            stream.cr.modify(|r| r | CR_EN);
+        }
     }
 
-    pub(crate) fn lock_mut(&self) -> &mut T {
-        assert_eq!(self.state.get(), State::Unlocked);
-        unsafe { assert_eq!(self.flag.get(), UNUSED) };
+    /// Stops the stream, waiting until the current item has been transferred
+    fn stop(_dma: &Self::Dma) {
+        let stream = stream::<Self>();
 
-        unsafe { self.flag.set(WRITING) };
-        self.state.set(State::MutLocked);
+        // NOTE(unsafe) clearing EN is always allowed
+        unsafe { stream.cr.modify(|r| r & !CR_EN) }
+        while stream.cr.read() & CR_EN != 0 {}
+    }
 
-        unsafe { &mut *self.data.get() }
+    /// `true` if the stream is enabled, i.e. a transfer is in progress
+    fn is_enabled(_dma: &Self::Dma) -> bool {
+        stream::<Self>().cr.read() & CR_EN != 0
     }
 
-    unsafe fn unlock(&self, state: State) {
-        match state {
-            State::Locked => self.flag.set(self.flag.get() - 1),
-            State::MutLocked => self.flag.set(UNUSED),
-            _ => { /* unreachable!() */ }
+    /// Number of items left to transfer (NDTR)
+    fn remaining(_dma: &Self::Dma) -> u16 {
+        stream::<Self>().ndtr.read() as u16
+    }
+
+    /// Decodes the flags and the FIFO level of the stream
+    fn status(_dma: &Self::Dma) -> Status {
+        decode::<Self>(flags::<Self>())
+    }
+
+    /// Decodes and clears the interrupt flags of the stream; meant for its
+    /// interrupt handler
+    ///
+    /// Only the flags that were seen are cleared. The types that poll the
+    /// flags (`Transfer`, `CircBuffer::read`, `RingBuffer`, `DoubleBuffer`)
+    /// don't see the events consumed by this method; use `Completion`,
+    /// `Handler` and `CircBuffer::on_events` instead.
+    fn events(_dma: &Self::Dma) -> Events {
+        let flags = flags::<Self>();
+        clear_flags::<Self>(flags);
+
+        let status = decode::<Self>(flags);
+
+        Events {
+            half_transfer: status.half_transfer,
+            transfer_complete: status.transfer_complete,
+            error: status.error(),
         }
+    }
 
-        self.state.set(State::Unlocked);
+    /// `true` if `flag` is set
+    fn is_set(_dma: &Self::Dma, flag: Flag) -> bool {
+        flags::<Self>() & flag.mask() != 0
+    }
+
+    /// Clears `flag`
+    fn clear(_dma: &Self::Dma, flag: Flag) {
+        clear_flags::<Self>(flag.mask())
+    }
+
+    /// Clears all the flags of the stream
+    fn clear_all(_dma: &Self::Dma) {
+        clear_flags::<Self>(ALL_FLAGS)
     }
 }
 
-// FIXME these `release` methods probably want some of sort of barrier
-impl<T> Buffer<T, Dma1Stream2> {
-    /// Waits until the DMA releases this buffer
-    pub fn release(&self, dma1: &DMA1) -> nb::Result<(), Error> {
-        let state = self.state.get();
+// Decodes `flags` (see `flags`) and the FIFO level of `S`
+fn decode<S>(flags: u32) -> Status
+where
+    S: Stream + ?Sized,
+{
+    let set = |flag: Flag| flags & flag.mask() != 0;
+
+    let fcr = stream::<S>().fcr.read();
+    // FS, RM0368 9.5.10
+    let fifo_level = if fcr & FCR_DMDIS == 0 {
+        None
+    } else {
+        Some(match (fcr >> 3) & 0b111 {
+            0b000 => FifoLevel::BelowQuarter,
+            0b001 => FifoLevel::BelowHalf,
+            0b010 => FifoLevel::BelowThreeQuarters,
+            0b011 => FifoLevel::BelowFull,
+            0b100 => FifoLevel::Empty,
+            _ => FifoLevel::Full,
+        })
+    };
+
+    Status {
+        fifo_error: set(Flag::FifoError),
+        direct_mode_error: set(Flag::DirectModeError),
+        transfer_error: set(Flag::TransferError),
+        half_transfer: set(Flag::HalfTransfer),
+        transfer_complete: set(Flag::TransferComplete),
+        fifo_level: fifo_level,
+    }
+}
 
-        if state == State::Unlocked {
-            return Ok(());
+// The error flagged by the stream, if any. The stream stops on its own after
+// a transfer error only; FIFO and direct mode errors are cleared once
+// reported, so that the transfer can go on.
+fn check<S>(dma: &S::Dma) -> Result<(), Error>
+where
+    S: Stream + ?Sized,
+{
+    match S::status(dma).error() {
+        None => Ok(()),
+        Some(Error::Transfer) => Err(Error::Transfer),
+        Some(error) => {
+            clear_flags::<S>(Flag::FifoError.mask() | Flag::DirectModeError.mask());
+            Err(error)
         }
+    }
+}
 
-        if dma1.lisr.read().teif2().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else if dma1.lisr.read().tcif2().bit_is_set() {
-            unsafe { self.unlock(state) }
-            dma1.lifcr.write(|w| w.ctcif2().set_bit());
-            dma1.s2cr.modify(|_, w| w.en().clear_bit());
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
-        }
+fn registers<S>() -> &'static Registers
+where
+    S: Stream + ?Sized,
+{
+    unsafe { &*(S::address() as *const Registers) }
+}
+
+fn stream<S>() -> &'static StreamRegisters
+where
+    S: Stream + ?Sized,
+{
+    &registers::<S>().streams[S::NUMBER as usize]
+}
+
+// LISR / HISR hold the flags of streams 0-3 / 4-7 at these offsets
+fn flag_offset(number: u8) -> u32 {
+    [0, 6, 16, 22][number as usize % 4]
+}
+
+// flags of the stream, shifted down to bit 0
+fn flags<S>() -> u32
+where
+    S: Stream + ?Sized,
+{
+    let isr = registers::<S>().isr[S::NUMBER as usize / 4].read();
+
+    (isr >> flag_offset(S::NUMBER)) & ALL_FLAGS
+}
+
+fn clear_flags<S>(mask: u32)
+where
+    S: Stream + ?Sized,
+{
+    // NOTE(unsafe) write 1 to clear; the flags of the other streams are not
+    // affected
+    unsafe { registers::<S>().ifcr[S::NUMBER as usize / 4].write(mask << flag_offset(S::NUMBER)) }
+}
+
+macro_rules! streams {
+    ($($Stream:ident: ($DMA:ident, $number:expr, $doc:expr),)+) => {
+        $(
+            #[doc = $doc]
+            pub struct $Stream {
+                _0: (),
+            }
+
+            unsafe impl Stream for $Stream {
+                type Dma = $DMA;
+
+                const NUMBER: u8 = $number;
+
+                fn address() -> usize {
+                    $DMA.get() as usize
+                }
+            }
+        )+
     }
 }
 
-impl<T> Buffer<T, Dma1Stream4> {
-    /// Waits until the DMA releases this buffer
-    pub fn release(&self, dma1: &DMA1) -> nb::Result<(), Error> {
-        let state = self.state.get();
+streams! {
+    Dma1Stream0: (DMA1, 0, "Stream 0 of DMA1"),
+    Dma1Stream1: (DMA1, 1, "Stream 1 of DMA1"),
+    Dma1Stream2: (DMA1, 2, "Stream 2 of DMA1"),
+    Dma1Stream3: (DMA1, 3, "Stream 3 of DMA1"),
+    Dma1Stream4: (DMA1, 4, "Stream 4 of DMA1"),
+    Dma1Stream5: (DMA1, 5, "Stream 5 of DMA1"),
+    Dma1Stream6: (DMA1, 6, "Stream 6 of DMA1"),
+    Dma1Stream7: (DMA1, 7, "Stream 7 of DMA1"),
+    Dma2Stream0: (DMA2, 0, "Stream 0 of DMA2"),
+    Dma2Stream1: (DMA2, 1, "Stream 1 of DMA2"),
+    Dma2Stream2: (DMA2, 2, "Stream 2 of DMA2"),
+    Dma2Stream3: (DMA2, 3, "Stream 3 of DMA2"),
+    Dma2Stream4: (DMA2, 4, "Stream 4 of DMA2"),
+    Dma2Stream5: (DMA2, 5, "Stream 5 of DMA2"),
+    Dma2Stream6: (DMA2, 6, "Stream 6 of DMA2"),
+    Dma2Stream7: (DMA2, 7, "Stream 7 of DMA2"),
+}
 
-        if state == State::Unlocked {
-            return Ok(());
+macro_rules! controller {
+    ($Streams:ident, $DMA:ident, $TAKEN:ident, $doc:expr,
+     $($field:ident: $Stream:ident,)+) => {
+        static $TAKEN: AtomicBool = AtomicBool::new(false);
+
+        #[doc = $doc]
+        pub struct $Streams {
+            $(
+                #[allow(missing_docs)]
+                pub $field: $Stream,
+            )+
         }
 
-        if dma1.hisr.read().teif4().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else if dma1.hisr.read().tcif4().bit_is_set() {
-            unsafe { self.unlock(state) }
-            dma1.hifcr.write(|w| w.ctcif4().set_bit());
-            dma1.s2cr.modify(|_, w| w.en().clear_bit());
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
+        impl $Streams {
+            /// Hands out the streams; returns `None` if they have already
+            /// been taken
+            pub fn take(_dma: &$DMA) -> Option<Self> {
+                if $TAKEN.swap(true, Ordering::Relaxed) {
+                    None
+                } else {
+                    Some($Streams {
+                        $($field: $Stream { _0: () },)+
+                    })
+                }
+            }
         }
     }
 }
 
-impl<T> Buffer<T, Dma1Stream5> {
-    /// Waits until the DMA releases this buffer
-    pub fn release(&self, dma1: &DMA1) -> nb::Result<(), Error> {
-        let state = self.state.get();
+controller! {
+    Dma1Streams, DMA1, DMA1_TAKEN, "The streams of DMA1",
+    s0: Dma1Stream0,
+    s1: Dma1Stream1,
+    s2: Dma1Stream2,
+    s3: Dma1Stream3,
+    s4: Dma1Stream4,
+    s5: Dma1Stream5,
+    s6: Dma1Stream6,
+    s7: Dma1Stream7,
+}
 
-        if state == State::Unlocked {
-            return Ok(());
-        }
+controller! {
+    Dma2Streams, DMA2, DMA2_TAKEN, "The streams of DMA2",
+    s0: Dma2Stream0,
+    s1: Dma2Stream1,
+    s2: Dma2Stream2,
+    s3: Dma2Stream3,
+    s4: Dma2Stream4,
+    s5: Dma2Stream5,
+    s6: Dma2Stream6,
+    s7: Dma2Stream7,
+}
 
-        if dma1.hisr.read().teif5().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else if dma1.hisr.read().tcif5().bit_is_set() {
-            unsafe { self.unlock(state) }
-            dma1.hifcr.write(|w| w.ctcif5().set_bit());
-            dma1.s2cr.modify(|_, w| w.en().clear_bit());
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
+/// A transfer in progress
+///
+/// Starting a transfer moves the buffer and the stream into the `Transfer`,
+/// so the memory the DMA is working on can't be touched and the stream
+/// can't be reused until `wait` or `release` hand both back.
+pub struct Transfer<BUFFER, STREAM> {
+    buffer: BUFFER,
+    stream: STREAM,
+}
+
+impl<BUFFER, STREAM> Transfer<BUFFER, STREAM>
+where
+    STREAM: Stream,
+{
+    /// Starts a transfer of `len` items between `peripheral` and `memory`
+    /// (see `Stream::start`) using the current configuration of `stream`
+    ///
+    /// # Safety
+    ///
+    /// The `len` items at `memory` must lie within `buffer`, and the buffer
+    /// must stay at the same address when moved (e.g. a `&'static mut`)
+    pub unsafe fn start(
+        dma: &STREAM::Dma,
+        mut stream: STREAM,
+        buffer: BUFFER,
+        peripheral: u32,
+        memory: u32,
+        len: u16,
+    ) -> Self {
+        stream.start(dma, peripheral, memory, len);
+
+        Transfer {
+            buffer: buffer,
+            stream: stream,
         }
     }
+
+    /// `true` once the transfer is complete
+    ///
+    /// Returns the error flagged by the stream, if any (see `Status::error`).
+    /// After a `Transfer` error the stream has stopped on its own; after a
+    /// FIFO or direct mode error the transfer goes on, but some data has
+    /// been lost.
+    pub fn is_done(&self, dma: &STREAM::Dma) -> Result<bool, Error> {
+        check::<STREAM>(dma)?;
+
+        Ok(STREAM::is_set(dma, Flag::TransferComplete))
+    }
+
+    /// Waits until the transfer is over and returns the buffer and the
+    /// stream
+    ///
+    /// Also returns after a transfer error (see `is_done`). Circular
+    /// transfers never complete; use `release` to stop them.
+    pub fn wait(self, dma: &STREAM::Dma) -> (BUFFER, STREAM) {
+        while !STREAM::is_set(dma, Flag::TransferComplete)
+            && !STREAM::is_set(dma, Flag::TransferError)
+        {}
+
+        self.release(dma)
+    }
+
+    /// Stops the transfer, wherever it is, and returns the buffer and the
+    /// stream
+    pub fn release(self, dma: &STREAM::Dma) -> (BUFFER, STREAM) {
+        STREAM::stop(dma);
+        STREAM::clear_all(dma);
+
+        // the DMA writes to the buffer must not be reordered after this point
+        compiler_fence(Ordering::Acquire);
+
+        (self.buffer, self.stream)
+    }
 }
 
-impl<T> Buffer<T, Dma1Stream6> {
-    /// Waits until the DMA releases this buffer
-    pub fn release(&self, dma1: &DMA1) -> nb::Result<(), Error> {
-        let state = self.state.get();
+/// A finished one-shot transfer
+pub struct Done<BUFFER, STREAM> {
+    /// The first error flagged during the transfer, if any
+    pub result: Result<(), Error>,
+    /// The buffer
+    pub buffer: BUFFER,
+    /// The stream
+    pub stream: STREAM,
+}
 
-        if state == State::Unlocked {
-            return Ok(());
-        }
+enum CompletionState<BUFFER, STREAM> {
+    Idle,
+    Running(Transfer<BUFFER, STREAM>),
+    Done(Done<BUFFER, STREAM>),
+}
 
-        if dma1.hisr.read().teif6().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else if dma1.hisr.read().tcif6().bit_is_set() {
-            unsafe { self.unlock(state) }
-            dma1.hifcr.write(|w| w.ctcif6().set_bit());
-            dma1.s2cr.modify(|_, w| w.en().clear_bit());
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
+/// Holds a one-shot transfer until the stream interrupt reports its end
+///
+/// `on_interrupt`, called from the interrupt handler of the stream, hands
+/// the buffer and the stream back: to the callback registered with `start`,
+/// or to whoever polls `take`, e.g. a task woken by the callback.
+///
+/// ``` ignore
+/// static TX: Completion<&'static mut [u8; 16], Dma1Stream6> = Completion::new();
+///
+/// TX.start(serial.write_all(dma1, stream, buffer), Some(sent));
+///
+/// // DMA1_STREAM6 interrupt
+/// TX.on_interrupt(dma1);
+/// ```
+///
+/// The transfer must have the transfer complete and transfer error
+/// interrupts enabled.
+pub struct Completion<BUFFER, STREAM> {
+    state: UnsafeCell<CompletionState<BUFFER, STREAM>>,
+    callback: Cell<Option<fn(Done<BUFFER, STREAM>)>>,
+    // first FIFO or direct mode error; these don't stop the stream
+    error: Cell<Option<Error>>,
+}
+
+// NOTE(unsafe) the state is only accessed with interrupts disabled
+unsafe impl<BUFFER, STREAM> Sync for Completion<BUFFER, STREAM> {}
+
+impl<BUFFER, STREAM> Completion<BUFFER, STREAM> {
+    /// Creates an empty slot
+    pub const fn new() -> Self {
+        Completion {
+            state: UnsafeCell::new(CompletionState::Idle),
+            callback: Cell::new(None),
+            error: Cell::new(None),
         }
     }
+
+    /// Takes `transfer` over until it's complete
+    ///
+    /// `callback` is called from the stream interrupt with the finished
+    /// transfer; without it the finished transfer is kept for `take`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot holds a transfer, running or finished
+    pub fn start(
+        &self,
+        transfer: Transfer<BUFFER, STREAM>,
+        callback: Option<fn(Done<BUFFER, STREAM>)>,
+    ) {
+        interrupt::free(|_| {
+            let state = unsafe { &mut *self.state.get() };
+
+            match *state {
+                CompletionState::Idle => {}
+                _ => panic!("DMA completion in use"),
+            }
+
+            *state = CompletionState::Running(transfer);
+            self.callback.set(callback);
+            self.error.set(None);
+        })
+    }
+
+    /// `true` if a transfer is running
+    pub fn is_running(&self) -> bool {
+        interrupt::free(|_| match unsafe { &*self.state.get() } {
+            &CompletionState::Running(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Takes the finished transfer
+    pub fn take(&self) -> nb::Result<Done<BUFFER, STREAM>, !> {
+        interrupt::free(|_| {
+            let state = unsafe { &mut *self.state.get() };
+
+            match mem::replace(state, CompletionState::Idle) {
+                CompletionState::Done(done) => Ok(done),
+                other => {
+                    *state = other;
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+        })
+    }
 }
 
-impl<T> Buffer<T, Dma2Stream0> {
-    /// Waits until the DMA releases this buffer
-    pub fn release(&self, dma2: &DMA2) -> nb::Result<(), Error> {
-        let state = self.state.get();
+impl<BUFFER, STREAM> Completion<BUFFER, STREAM>
+where
+    STREAM: Stream,
+{
+    /// Handles the interrupt of the stream
+    ///
+    /// Decodes and clears the flags (see `Stream::events`) and finishes the
+    /// transfer on the transfer complete event or on a transfer error.
+    /// Returns the events.
+    pub fn on_interrupt(&self, dma: &STREAM::Dma) -> Events {
+        let events = STREAM::events(dma);
+
+        let done = interrupt::free(|_| {
+            let state = unsafe { &mut *self.state.get() };
+
+            if let Some(error) = events.error {
+                if self.error.get().is_none() {
+                    self.error.set(Some(error));
+                }
+            }
 
-        if state == State::Unlocked {
-            return Ok(());
-        }
+            let end = events.transfer_complete || events.error == Some(Error::Transfer);
+            if !end {
+                return None;
+            }
 
-        if dma2.lisr.read().teif0().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else if dma2.lisr.read().tcif0().bit_is_set() {
-            unsafe { self.unlock(state) }
-            dma2.lifcr.write(|w| w.ctcif0().set_bit());
-            dma2.s2cr.modify(|_, w| w.en().clear_bit());
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
+            match mem::replace(state, CompletionState::Idle) {
+                CompletionState::Running(transfer) => {
+                    let (buffer, stream) = transfer.release(dma);
+                    let done = Done {
+                        result: self.error.get().map_or(Ok(()), Err),
+                        buffer: buffer,
+                        stream: stream,
+                    };
+
+                    match self.callback.get() {
+                        Some(callback) => Some((callback, done)),
+                        None => {
+                            *state = CompletionState::Done(done);
+                            None
+                        }
+                    }
+                }
+                other => {
+                    *state = other;
+                    None
+                }
+            }
+        });
+
+        // the callback runs with interrupts enabled
+        if let Some((callback, done)) = done {
+            callback(done);
         }
+
+        events
     }
 }
 
-/// A circular buffer associated to a DMA `STREAM`
-pub struct CircBuffer<B, STREAM> {
+/// Calls a function on every event of a stream
+///
+/// Meant for circular transfers, which never end: the callback registered
+/// with `register` is called from `on_interrupt`, in the interrupt handler of
+/// the stream, with the decoded events.
+pub struct Handler<STREAM> {
     _marker: PhantomData<STREAM>,
-    buffer: UnsafeCell<[B; 2]>,
-    state: Cell<CircState>,
+    callback: Cell<Option<fn(Events)>>,
+}
+
+// NOTE(unsafe) the callback is a plain function pointer, set and read
+// with interrupts disabled
+unsafe impl<STREAM> Sync for Handler<STREAM> {}
+
+impl<STREAM> Handler<STREAM> {
+    /// Creates a handler with no callback
+    pub const fn new() -> Self {
+        Handler {
+            _marker: PhantomData,
+            callback: Cell::new(None),
+        }
+    }
+
+    /// Sets the callback
+    pub fn register(&self, callback: fn(Events)) {
+        interrupt::free(|_| self.callback.set(Some(callback)))
+    }
+
+    /// Removes the callback
+    pub fn unregister(&self) {
+        interrupt::free(|_| self.callback.set(None))
+    }
 }
 
-#[allow(dead_code)]
-impl<B, STREAM> CircBuffer<B, STREAM> {
-    pub(crate) fn lock(&self) -> &[B; 2] {
-        assert_eq!(self.state.get(), CircState::Free);
+impl<STREAM> Handler<STREAM>
+where
+    STREAM: Stream,
+{
+    /// Handles the interrupt of the stream: decodes and clears the flags
+    /// (see `Stream::events`) and calls the callback, if any
+    pub fn on_interrupt(&self, dma: &STREAM::Dma) -> Events {
+        let events = STREAM::events(dma);
 
-        self.state.set(CircState::MutatingFirstHalf);
+        if let Some(callback) = interrupt::free(|_| self.callback.get()) {
+            callback(events);
+        }
 
-        unsafe { &*self.buffer.get() }
+        events
     }
 }
 
+/// A circular transfer into a buffer made of two halves
+///
+/// The DMA fills the halves alternately; `read` hands out the half it just
+/// completed while it works on the other one. The buffer and the stream are
+/// owned by the transfer until `stop` hands them back.
+pub struct CircBuffer<B, STREAM>
+where
+    B: 'static,
+{
+    stream: STREAM,
+    buffer: &'static mut [B; 2],
+    state: CircState,
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum CircState {
-    /// Not in use by the DMA
-    Free,
     /// The DMA is mutating the first half of the buffer
     MutatingFirstHalf,
     /// The DMA is mutating the second half of the buffer
     MutatingSecondHalf,
 }
-impl<B, STREAM> CircBuffer<B, STREAM> {
-    /// Constructs a circular buffer from two halves
-    pub const fn new(buffer: [B; 2]) -> Self {
+
+impl<B, STREAM> CircBuffer<B, STREAM>
+where
+    STREAM: Stream,
+{
+    /// Starts a circular transfer between the peripheral register at
+    /// address `peripheral` and `buffer`
+    ///
+    /// `config` is used as is except that the circular mode is forced. Each
+    /// half holds `size_of::<B>()` bytes, which must be a multiple of the
+    /// peripheral data size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stream is in use or if the buffer is too large
+    pub fn start(
+        dma: &STREAM::Dma,
+        mut stream: STREAM,
+        config: &Config,
+        peripheral: u32,
+        buffer: &'static mut [B; 2],
+    ) -> Self {
+        assert!(!STREAM::is_enabled(dma), "DMA stream in use");
+
+        let bytes = mem::size_of::<B>();
+        let item = config.peripheral_size.bytes();
+        assert!(bytes % item == 0 && 2 * bytes / item <= 0xffff);
+
+        stream.configure(dma, config);
+
+        // NOTE(unsafe) the stream is disabled
+        unsafe { self::stream::<STREAM>().cr.modify(|r| r | CR_CIRC) }
+
+        let address = &*buffer as *const [B; 2] as u32;
+        stream.start(dma, peripheral, address, (2 * bytes / item) as u16);
+
         CircBuffer {
-            _marker: PhantomData,
-            buffer: UnsafeCell::new(buffer),
-            state: Cell::new(CircState::Free),
+            stream: stream,
+            buffer: buffer,
+            state: CircState::MutatingFirstHalf,
         }
     }
-}
 
-impl<B> CircBuffer<B, Dma1Stream1> {
     /// Yields read access to the half of the circular buffer that's not
     /// currently being mutated by the DMA
-    pub fn read<R, F>(&self, dma1: &DMA1, f: F) -> nb::Result<R, Error>
+    pub fn read<R, F>(&mut self, dma: &STREAM::Dma, f: F) -> nb::Result<R, Error>
     where
         F: FnOnce(&B) -> R,
     {
-        let state = self.state.get();
+        check::<STREAM>(dma).map_err(nb::Error::Other)?;
 
-        assert_ne!(state, CircState::Free);
+        let ht = STREAM::is_set(dma, Flag::HalfTransfer);
+        let tc = STREAM::is_set(dma, Flag::TransferComplete);
 
-        let isr = dma1.lisr.read();
+        // the data of a completed half must be read after the flags
+        compiler_fence(Ordering::Acquire);
 
-        if isr.teif1().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
-        } else {
-            match state {
-                CircState::MutatingFirstHalf => if isr.tcif1().bit_is_set() {
-                    Err(nb::Error::Other(Error::Overrun))
-                } else if isr.htif1().bit_is_set() {
-                    dma1.lifcr.write(|w| w.chtif1().set_bit());
+        match self.state {
+            CircState::MutatingFirstHalf => if tc {
+                Err(nb::Error::Other(Error::Overrun))
+            } else if ht {
+                STREAM::clear(dma, Flag::HalfTransfer);
 
-                    self.state.set(CircState::MutatingSecondHalf);
+                self.state = CircState::MutatingSecondHalf;
 
-                    let ret = f(unsafe { &(*self.buffer.get())[0] });
+                let ret = f(&self.buffer[0]);
 
-                    if isr.tcif1().bit_is_set() {
-                        Err(nb::Error::Other(Error::Overrun))
-                    } else {
-                        Ok(ret)
-                    }
-                } else {
-                    Err(nb::Error::WouldBlock)
-                },
-                CircState::MutatingSecondHalf => if isr.htif1().bit_is_set() {
+                if STREAM::is_set(dma, Flag::TransferComplete) {
                     Err(nb::Error::Other(Error::Overrun))
-                } else if isr.tcif1().bit_is_set() {
-                    dma1.lifcr.write(|w| w.ctcif1().set_bit());
+                } else {
+                    Ok(ret)
+                }
+            } else {
+                Err(nb::Error::WouldBlock)
+            },
+            CircState::MutatingSecondHalf => if ht {
+                Err(nb::Error::Other(Error::Overrun))
+            } else if tc {
+                STREAM::clear(dma, Flag::TransferComplete);
 
-                    self.state.set(CircState::MutatingFirstHalf);
+                self.state = CircState::MutatingFirstHalf;
 
-                    let ret = f(unsafe { &(*self.buffer.get())[1] });
+                let ret = f(&self.buffer[1]);
 
-                    if isr.htif1().bit_is_set() {
-                        Err(nb::Error::Other(Error::Overrun))
-                    } else {
-                        Ok(ret)
-                    }
+                if STREAM::is_set(dma, Flag::HalfTransfer) {
+                    Err(nb::Error::Other(Error::Overrun))
                 } else {
-                    Err(nb::Error::WouldBlock)
-                },
-                _ => unreachable!(),
+                    Ok(ret)
+                }
+            } else {
+                Err(nb::Error::WouldBlock)
+            },
+        }
+    }
+
+    /// Like `read`, but driven by the `events` of the stream (see
+    /// `Stream::events`) instead of its flags
+    ///
+    /// An overrun that happens while `f` runs is reported by the next call.
+    pub fn on_events<R, F>(&mut self, events: &Events, f: F) -> nb::Result<R, Error>
+    where
+        F: FnOnce(&B) -> R,
+    {
+        if let Some(error) = events.error {
+            return Err(nb::Error::Other(error));
+        }
+
+        let half = match (self.state, events.half_transfer, events.transfer_complete) {
+            (_, false, false) => return Err(nb::Error::WouldBlock),
+            (CircState::MutatingFirstHalf, true, false) => 0,
+            (CircState::MutatingSecondHalf, false, true) => 1,
+            _ => return Err(nb::Error::Other(Error::Overrun)),
+        };
+
+        self.state = if half == 0 {
+            CircState::MutatingSecondHalf
+        } else {
+            CircState::MutatingFirstHalf
+        };
+
+        // the data of a completed half must be read after the flags
+        compiler_fence(Ordering::Acquire);
+
+        Ok(f(&self.buffer[half]))
+    }
+
+    /// Stops the transfer and returns the buffer and the stream
+    pub fn stop(self, dma: &STREAM::Dma) -> (&'static mut [B; 2], STREAM) {
+        STREAM::stop(dma);
+        STREAM::clear_all(dma);
+
+        compiler_fence(Ordering::Acquire);
+
+        (self.buffer, self.stream)
+    }
+}
+
+/// A ring buffer that's continuously filled by a circular DMA `STREAM`
+///
+/// Unlike `CircBuffer`, the data is not handed out in fixed halves but as
+/// whatever arrived since the last `read`, which makes it suitable for
+/// variable length frames delimited by an idle line. The buffer and the
+/// stream are owned by the transfer until `stop` hands them back.
+pub struct RingBuffer<B, STREAM>
+where
+    B: 'static,
+{
+    stream: STREAM,
+    buffer: &'static mut B,
+    index: RingIndex,
+}
+
+impl<B, STREAM> RingBuffer<B, STREAM>
+where
+    B: Unsize<[u8]>,
+    STREAM: Stream,
+{
+    /// Starts a circular transfer from the peripheral register at address
+    /// `peripheral` into `buffer`
+    ///
+    /// `config` is used as is except that the circular mode is forced; its
+    /// data sizes must be bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stream is in use, if the data sizes are not bytes or if
+    /// the buffer is shorter than 2 or longer than 65535 bytes
+    pub fn start(
+        dma: &STREAM::Dma,
+        mut stream: STREAM,
+        config: &Config,
+        peripheral: u32,
+        buffer: &'static mut B,
+    ) -> Self {
+        assert!(!STREAM::is_enabled(dma), "DMA stream in use");
+        assert!(config.memory_size == Size::Byte && config.peripheral_size == Size::Byte);
+
+        let (address, len) = {
+            let slice: &[u8] = &*buffer;
+            (slice.as_ptr() as u32, slice.len())
+        };
+        assert!(len >= 2 && len <= 0xffff);
+
+        stream.configure(dma, config);
+
+        // NOTE(unsafe) the stream is disabled
+        unsafe { self::stream::<STREAM>().cr.modify(|r| r | CR_CIRC) }
+
+        stream.start(dma, peripheral, address, len as u16);
+
+        RingBuffer {
+            stream: stream,
+            buffer: buffer,
+            index: RingIndex::new(len),
+        }
+    }
+
+    /// Yields the bytes the DMA wrote since the last call
+    ///
+    /// The data is passed to `f` as two slices because it may wrap around
+    /// the end of the ring; the second slice is empty otherwise. Call this
+    /// from the USART IDLE interrupt and from the stream's half transfer and
+    /// transfer complete interrupts so that the DMA can't lap the reader.
+    pub fn read<R, F>(&mut self, dma: &STREAM::Dma, f: F) -> nb::Result<R, Error>
+    where
+        F: FnOnce(&[u8], &[u8]) -> R,
+    {
+        check::<STREAM>(dma).map_err(nb::Error::Other)?;
+
+        let ht = STREAM::is_set(dma, Flag::HalfTransfer);
+        let tc = STREAM::is_set(dma, Flag::TransferComplete);
+
+        // only clear the flags we have seen; see `RingIndex::advance`
+        if ht {
+            STREAM::clear(dma, Flag::HalfTransfer);
+        }
+        if tc {
+            STREAM::clear(dma, Flag::TransferComplete);
+        }
+
+        let ndtr = usize(STREAM::remaining(dma));
+
+        let chunk = self.index.advance(ndtr, ht, tc);
+
+        // the data must be read after NDTR
+        compiler_fence(Ordering::Acquire);
+
+        match chunk {
+            Err(_) => Err(nb::Error::Other(Error::Overrun)),
+            Ok(ref chunk) if chunk.is_empty() => Err(nb::Error::WouldBlock),
+            Ok(chunk) => {
+                let buffer: &[u8] = &*self.buffer;
+
+                Ok(f(&buffer[chunk.first], &buffer[chunk.second]))
             }
         }
     }
+
+    /// Stops the circular transfer and returns the buffer and the stream
+    pub fn stop(self, dma: &STREAM::Dma) -> (&'static mut B, STREAM) {
+        STREAM::stop(dma);
+        STREAM::clear_all(dma);
+
+        compiler_fence(Ordering::Acquire);
+
+        (self.buffer, self.stream)
+    }
 }
 
-impl<B> CircBuffer<B, Dma2Stream0> {
-    /// Yields read access to the half of the circular buffer that's not
-    /// currently being mutated by the DMA
-    pub fn read<R, F>(&self, dma2: &DMA2, f: F) -> nb::Result<R, Error>
+/// A transfer in hardware double buffer mode (DBM)
+///
+/// The stream fills (or drains) two memory targets alternately, switching
+/// to the other target (M0AR / M1AR) on its own when one is complete. `swap`
+/// hands out the target that was just completed and puts a fresh one in its
+/// place, so the data is never copied and the application has a whole
+/// target's worth of time to do the swap. Once swapped out, a buffer can be
+/// kept as long as needed.
+pub struct DoubleBuffer<B, STREAM>
+where
+    B: 'static,
+{
+    stream: STREAM,
+    buffers: [&'static mut B; 2],
+    // target the DMA completes next
+    next: usize,
+}
+
+impl<B, STREAM> DoubleBuffer<B, STREAM>
+where
+    STREAM: Stream,
+{
+    /// Starts a double buffer transfer between the peripheral register at
+    /// address `peripheral` and the two `buffers`
+    ///
+    /// `config` is used as is except that circular and double buffer modes
+    /// are forced. Each target holds `size_of::<B>()` bytes, which must be a
+    /// multiple of the peripheral data size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stream is in use or if the buffers are too large
+    pub fn start(
+        dma: &STREAM::Dma,
+        mut stream: STREAM,
+        config: &Config,
+        peripheral: u32,
+        buffers: [&'static mut B; 2],
+    ) -> Self {
+        assert!(!STREAM::is_enabled(dma), "DMA stream in use");
+
+        let bytes = mem::size_of::<B>();
+        let item = config.peripheral_size.bytes();
+        assert!(bytes % item == 0 && bytes / item <= 0xffff);
+
+        stream.configure(dma, config);
+
+        let registers = self::stream::<STREAM>();
+
+        // see `Stream::start`
+        compiler_fence(Ordering::Release);
+
+        // NOTE(unsafe) the stream is disabled; the buffers are owned by the
+        // transfer until `stop`
+        unsafe {
+            registers.cr.modify(|r| (r | CR_DBM | CR_CIRC) & !CR_CT);
+            registers.ndtr.write((bytes / item) as u32);
+            registers.par.write(peripheral);
+            registers.m0ar.write(&*buffers[0] as *const B as u32);
+            registers.m1ar.write(&*buffers[1] as *const B as u32);
+            registers.cr.modify(|r| r | CR_EN);
+        }
+
+        DoubleBuffer {
+            stream: stream,
+            buffers: buffers,
+            next: 0,
+        }
+    }
+
+    /// `true` if a target is complete and can be swapped out
+    pub fn is_ready(&self, dma: &STREAM::Dma) -> bool {
+        STREAM::is_set(dma, Flag::TransferComplete)
+    }
+
+    /// Replaces the target the DMA just completed with the buffer returned
+    /// by `fresh`, and returns the completed one
+    ///
+    /// `fresh` is only called when a target is complete. Returns `Overrun`
+    /// if the DMA has completed the other target as well since the last
+    /// swap; the data of the missed target is lost and the transfer goes
+    /// on, so the next call can succeed again.
+    pub fn swap<F>(&mut self, dma: &STREAM::Dma, fresh: F) -> nb::Result<&'static mut B, Error>
     where
-        F: FnOnce(&B) -> R,
+        F: FnOnce() -> &'static mut B,
     {
-        let state = self.state.get();
+        check::<STREAM>(dma).map_err(nb::Error::Other)?;
+
+        if !STREAM::is_set(dma, Flag::TransferComplete) {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        STREAM::clear(dma, Flag::TransferComplete);
 
-        assert_ne!(state, CircState::Free);
+        let stream = stream::<STREAM>();
+        // target the DMA is working on now
+        let current = if stream.cr.read() & CR_CT != 0 { 1 } else { 0 };
 
-        let isr = dma2.lisr.read();
+        if current == self.next {
+            // it already went through the other target and is now back at
+            // the one we were going to swap out, which completes next
+            return Err(nb::Error::Other(Error::Overrun));
+        }
 
-        if isr.teif0().bit_is_set() {
-            Err(nb::Error::Other(Error::Transfer))
+        // the completed target must be read after CT
+        compiler_fence(Ordering::Acquire);
+
+        let buffer = fresh();
+
+        // NOTE(unsafe) the target being replaced is not the current one;
+        // writing the address of the current target would disable the stream
+        // (RM0368 9.3.9)
+        unsafe {
+            let address = &*buffer as *const B as u32;
+            if self.next == 0 {
+                stream.m0ar.write(address);
+            } else {
+                stream.m1ar.write(address);
+            }
+        }
+
+        let filled = mem::replace(&mut self.buffers[self.next], buffer);
+        self.next = current;
+
+        Ok(filled)
+    }
+
+    /// Stops the transfer and returns the two targets, M0AR's first, and
+    /// the stream
+    pub fn stop(self, dma: &STREAM::Dma) -> ([&'static mut B; 2], STREAM) {
+        STREAM::stop(dma);
+        STREAM::clear_all(dma);
+
+        // NOTE(unsafe) clearing DBM is allowed now that EN is cleared
+        unsafe { stream::<STREAM>().cr.modify(|r| r & !CR_DBM) }
+
+        compiler_fence(Ordering::Acquire);
+
+        (self.buffers, self.stream)
+    }
+}
+
+/// Data item of a memory-to-memory transfer
+pub unsafe trait Item: Copy {
+    #[doc(hidden)]
+    const SIZE: Size;
+}
+
+unsafe impl Item for u8 {
+    const SIZE: Size = Size::Byte;
+}
+
+unsafe impl Item for i8 {
+    const SIZE: Size = Size::Byte;
+}
+
+unsafe impl Item for u16 {
+    const SIZE: Size = Size::HalfWord;
+}
+
+unsafe impl Item for i16 {
+    const SIZE: Size = Size::HalfWord;
+}
+
+unsafe impl Item for u32 {
+    const SIZE: Size = Size::Word;
+}
+
+unsafe impl Item for i32 {
+    const SIZE: Size = Size::Word;
+}
+
+unsafe impl Item for f32 {
+    const SIZE: Size = Size::Word;
+}
+
+/// Configuration of the memory-to-memory transfers (`copy` and `fill`)
+#[derive(Clone, Copy, Debug)]
+pub struct CopyConfig {
+    /// Priority against the other streams of DMA2
+    pub priority: Priority,
+    /// Longest burst to use
+    ///
+    /// Bursts are shortened to fit the 16 byte FIFO and so that they don't
+    /// cross a 1 KB boundary (RM0368 9.3.11).
+    pub burst: Burst,
+    /// Interrupt when the transfer is complete or has failed; otherwise
+    /// completion is polled with `Transfer::is_done` or `Transfer::wait`
+    pub interrupt: bool,
+}
+
+impl CopyConfig {
+    /// Low priority, 4 item bursts, no interrupt
+    pub fn new() -> Self {
+        CopyConfig {
+            priority: Priority::Low,
+            burst: Burst::Incr4,
+            interrupt: false,
+        }
+    }
+}
+
+// How a block of `len` bytes is split between the CPU and the DMA: the CPU
+// handles the `head` bytes before the DMA part and the bytes after it, the
+// DMA `items` items of `size` in bursts of `burst`
+#[derive(Clone, Copy, Debug)]
+struct Plan {
+    head: usize,
+    size: Size,
+    burst: Burst,
+    items: usize,
+}
+
+impl Plan {
+    // `src` and `dst` are aligned to `item` bytes; `len` is a multiple of
+    // `item`
+    fn new(src: usize, dst: usize, len: usize, item: Size, burst: Burst) -> Self {
+        // the widest items both addresses can be aligned to; the DMA needs
+        // addresses aligned to the item size
+        let size = if (src ^ dst) % 4 == 0 {
+            Size::Word
+        } else if (src ^ dst) % 2 == 0 {
+            Size::HalfWord
         } else {
-            match state {
-                CircState::MutatingFirstHalf => if isr.tcif0().bit_is_set() {
-                    Err(nb::Error::Other(Error::Overrun))
-                } else if isr.htif0().bit_is_set() {
-                    dma2.lifcr.write(|w| w.chtif0().set_bit());
+            Size::Byte
+        };
+        let width = size.bytes();
+
+        let head = cmp::min((width - dst % width) % width, len);
+
+        // 16 byte FIFO, full threshold: bursts of up to 16 bytes (RM0368
+        // 9.3.14 Table 43); a burst starting on a multiple of its length
+        // doesn't cross a 1 KB boundary
+        let mut beats = cmp::min(burst.beats(), 16 / width);
+        while beats > 1
+            && ((src + head) % (beats * width) != 0 || (dst + head) % (beats * width) != 0)
+        {
+            beats /= 2;
+        }
+        let burst = Burst::at_most(beats);
+        let beats = burst.beats();
+
+        let items = (len - head) / (beats * width) * beats;
+
+        if items == 0 {
+            // too short: move the last item with the DMA, so that there's a
+            // transfer to complete, and the rest with the CPU
+            Plan {
+                head: len - item.bytes(),
+                size: item,
+                burst: Burst::Single,
+                items: 1,
+            }
+        } else {
+            Plan {
+                head: head,
+                size: size,
+                burst: burst,
+                items: items,
+            }
+        }
+    }
 
-                    self.state.set(CircState::MutatingSecondHalf);
+    // end of the DMA part, in bytes
+    fn end(&self) -> usize {
+        self.head + self.items * self.size.bytes()
+    }
 
-                    let ret = f(unsafe { &(*self.buffer.get())[0] });
+    // The plan without its first burst, which the CPU handles; requires more
+    // than one burst
+    fn skip_burst(&self) -> Self {
+        let beats = self.burst.beats();
 
-                    if isr.tcif0().bit_is_set() {
-                        Err(nb::Error::Other(Error::Overrun))
-                    } else {
-                        Ok(ret)
-                    }
-                } else {
-                    Err(nb::Error::WouldBlock)
-                },
-                CircState::MutatingSecondHalf => if isr.htif0().bit_is_set() {
-                    Err(nb::Error::Other(Error::Overrun))
-                } else if isr.tcif0().bit_is_set() {
-                    dma2.lifcr.write(|w| w.ctcif0().set_bit());
+        Plan {
+            head: self.head + beats * self.size.bytes(),
+            items: self.items - beats,
+            ..*self
+        }
+    }
+}
+
+// Enables DMA2 and configures a memory-to-memory transfer on `STREAM`
+fn configure_copy<STREAM>(
+    dma2: &DMA2,
+    stream: &mut STREAM,
+    config: &CopyConfig,
+    plan: &Plan,
+    fixed_source: bool,
+    rcc: &RCC,
+) where
+    STREAM: Stream<Dma = DMA2>,
+{
+    // nothing else needs DMA2, so it may still be off
+    rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
+
+    let mut cr = Config::new(Channel::_0, Direction::MemoryToMemory);
+    cr.priority = config.priority;
+    cr.memory_size = plan.size;
+    cr.peripheral_size = plan.size;
+    cr.peripheral_increment = !fixed_source;
+    cr.transfer_complete_interrupt = config.interrupt;
+    cr.transfer_error_interrupt = config.interrupt;
+    // memory-to-memory transfers require the FIFO (RM0368 9.3.6); the full
+    // threshold fits any burst the plan picks
+    cr.fifo_threshold = Some(Threshold::Full);
+    cr.memory_burst = plan.burst;
+    // a fixed source is read one item at a time
+    cr.peripheral_burst = if fixed_source {
+        Burst::Single
+    } else {
+        plan.burst
+    };
+
+    stream.configure(dma2, &cr);
+}
+
+/// Copies `src` into `dst` using a DMA2 stream
+///
+/// The CPU copies the few items that come before and after the part that
+/// can use the widest items and the longest bursts (see `CopyConfig`); the
+/// DMA copies the rest in the background. Items are moved as words when
+/// `src` and `dst` have the same alignment, as half words or bytes
+/// otherwise. The DMA2 clock is enabled here.
+///
+/// # Panics
+///
+/// Panics if the slices are empty or have different lengths, if the stream
+/// is in use or if the DMA part is longer than 65535 items
+pub fn copy<T, S, D, STREAM>(
+    dma2: &DMA2,
+    mut stream: STREAM,
+    config: &CopyConfig,
+    src: &'static S,
+    dst: &'static mut D,
+    rcc: &RCC,
+) -> Transfer<(&'static S, &'static mut D), STREAM>
+where
+    T: Item,
+    S: Unsize<[T]>,
+    D: Unsize<[T]>,
+    STREAM: Stream<Dma = DMA2>,
+{
+    assert!(!STREAM::is_enabled(dma2), "DMA stream in use");
+
+    let (plan, source, destination) = {
+        let from: &[T] = src;
+        let to: &mut [T] = dst;
+
+        assert!(!from.is_empty() && from.len() == to.len());
+
+        let item = T::SIZE.bytes();
+        let (source, destination) = (from.as_ptr() as usize, to.as_ptr() as usize);
+        let plan = Plan::new(source, destination, from.len() * item, T::SIZE, config.burst);
+
+        let (head, end) = (plan.head / item, plan.end() / item);
+        to[..head].copy_from_slice(&from[..head]);
+        to[end..].copy_from_slice(&from[end..]);
+
+        (plan, source + plan.head, destination + plan.head)
+    };
+
+    configure_copy(dma2, &mut stream, config, &plan, false, rcc);
+
+    // NOTE(unsafe) the DMA part lies within the `'static` slices
+    unsafe {
+        Transfer::start(
+            dma2,
+            stream,
+            (src, dst),
+            source as u32,
+            destination as u32,
+            u16(plan.items).unwrap(),
+        )
+    }
+}
+
+/// Sets every item of `dst` to `value` using a DMA2 stream
+///
+/// The CPU writes the items before the part that can use the widest items
+/// and the longest bursts, the first burst of that part and the items after
+/// it; the DMA replicates the first item of the part over the rest in the
+/// background. See `copy`.
+///
+/// # Panics
+///
+/// Panics if `dst` is empty, if the stream is in use or if the DMA part is
+/// longer than 65535 items
+pub fn fill<T, D, STREAM>(
+    dma2: &DMA2,
+    mut stream: STREAM,
+    config: &CopyConfig,
+    value: T,
+    dst: &'static mut D,
+    rcc: &RCC,
+) -> Transfer<&'static mut D, STREAM>
+where
+    T: Item,
+    D: Unsize<[T]>,
+    STREAM: Stream<Dma = DMA2>,
+{
+    assert!(!STREAM::is_enabled(dma2), "DMA stream in use");
+
+    let (plan, source, destination) = {
+        let to: &mut [T] = dst;
 
-                    self.state.set(CircState::MutatingFirstHalf);
+        assert!(!to.is_empty());
 
-                    let ret = f(unsafe { &(*self.buffer.get())[1] });
+        let item = T::SIZE.bytes();
+        let start = to.as_ptr() as usize;
+        let plan = Plan::new(start, start, to.len() * item, T::SIZE, config.burst);
 
-                    if isr.htif0().bit_is_set() {
-                        Err(nb::Error::Other(Error::Overrun))
-                    } else {
-                        Ok(ret)
+        if plan.items > plan.burst.beats() {
+            // the CPU also writes the first burst of the DMA part, whose
+            // first item becomes the source
+            let rest = plan.skip_burst();
+            let (pattern, end) = (rest.head / item, rest.end() / item);
+
+            let (part, tail) = to.split_at_mut(end);
+            for x in part[..pattern].iter_mut().chain(tail) {
+                *x = value;
+            }
+
+            (rest, start + plan.head, start + rest.head)
+        } else {
+            for x in to.iter_mut() {
+                *x = value;
+            }
+
+            // everything is written; copy the first item over the last one
+            // so that there's a transfer to complete
+            let plan = Plan {
+                head: 0,
+                size: T::SIZE,
+                burst: Burst::Single,
+                items: 1,
+            };
+
+            (plan, start, start + (to.len() - 1) * item)
+        }
+    };
+
+    configure_copy(dma2, &mut stream, config, &plan, true, rcc);
+
+    // NOTE(unsafe) the DMA part lies within the `'static` slice
+    unsafe {
+        Transfer::start(
+            dma2,
+            stream,
+            dst,
+            source as u32,
+            destination as u32,
+            u16(plan.items).unwrap(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BURSTS: [Burst; 4] = [Burst::Single, Burst::Incr4, Burst::Incr8, Burst::Incr16];
+    const SIZES: [Size; 3] = [Size::Byte, Size::HalfWord, Size::Word];
+
+    // Checks the DMA part of `plan` for a block of `len` bytes whose DMA part
+    // is read from `src` (`None` for a fixed source) and written to `dst`
+    fn check(plan: &Plan, src: Option<usize>, dst: usize, len: usize, item: Size) {
+        let (size, beats) = (plan.size.bytes(), plan.burst.beats());
+
+        // the CPU handles whole items before and after the DMA part, which
+        // together cover the block exactly once
+        assert!(plan.items >= 1);
+        assert!(plan.end() <= len);
+        assert_eq!(plan.head % item.bytes(), 0);
+        assert_eq!(plan.end() % item.bytes(), 0);
+        assert_eq!(plan.head + plan.items * size + (len - plan.end()), len);
+
+        // whole bursts, aligned to their length
+        assert_eq!(plan.items % beats, 0);
+        for &start in src.iter().chain(Some(dst).iter()) {
+            let start = start + plan.head;
+            assert_eq!(start % size, 0);
+            assert_eq!(start % (beats * size), 0);
+
+            // no burst crosses a 1 KB boundary
+            for burst in 0..plan.items / beats {
+                let first = start + burst * beats * size;
+                assert_eq!(first / 1024, (first + beats * size - 1) / 1024);
+            }
+        }
+    }
+
+    #[test]
+    fn copy_plans() {
+        // the second base is just below a 1 KB boundary
+        for &base in &[0x2000_0000, 0x2000_03f0] {
+            for &item in &SIZES {
+                let width = item.bytes();
+
+                for src in (0..16).filter(|offset| offset % width == 0) {
+                    for dst in (0..16).filter(|offset| offset % width == 0) {
+                        for n in 1..200 {
+                            for &burst in &BURSTS {
+                                let (src, dst, len) = (base + src, base + dst, n * width);
+                                let plan = Plan::new(src, dst, len, item, burst);
+
+                                check(&plan, Some(src), dst, len, item);
+                                // bursts don't get longer than asked for
+                                assert!(plan.burst.beats() <= burst.beats());
+                            }
+                        }
                     }
-                } else {
-                    Err(nb::Error::WouldBlock)
-                },
-                _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_plans() {
+        for &base in &[0x2000_0000, 0x2000_03f0] {
+            for &item in &SIZES {
+                let width = item.bytes();
+
+                for dst in (0..16).filter(|offset| offset % width == 0) {
+                    for n in 1..200 {
+                        for &burst in &BURSTS {
+                            let (dst, len) = (base + dst, n * width);
+                            let plan = Plan::new(dst, dst, len, item, burst);
+
+                            check(&plan, Some(dst), dst, len, item);
+
+                            if plan.items > plan.burst.beats() {
+                                // the first burst becomes the pattern, read
+                                // from a fixed source
+                                let rest = plan.skip_burst();
+
+                                check(&rest, None, dst, len, item);
+                                assert_eq!(rest.end(), plan.end());
+                            }
+                        }
+                    }
+                }
             }
         }
     }
+
+    #[test]
+    fn widest_items() {
+        // same alignment: words after 3 bytes, in bursts of 4
+        let plan = Plan::new(0x2000_000d, 0x2000_010d, 64, Size::Byte, Burst::Incr4);
+        assert_eq!((plan.head, plan.size, plan.burst), (3, Size::Word, Burst::Incr4));
+        assert_eq!(plan.items, 12);
+
+        // the burst is shortened until it starts on a multiple of its length
+        let plan = Plan::new(0x2000_0001, 0x2000_0101, 64, Size::Byte, Burst::Incr4);
+        assert_eq!((plan.head, plan.size, plan.burst), (3, Size::Word, Burst::Single));
+        assert_eq!(plan.items, 15);
+
+        // addresses 2 bytes apart: half words
+        let plan = Plan::new(0x2000_0000, 0x2000_0002, 64, Size::Byte, Burst::Single);
+        assert_eq!(plan.size, Size::HalfWord);
+
+        // too short for a burst: only the last item goes through the DMA
+        let plan = Plan::new(0x2000_0001, 0x2000_0001, 3, Size::Byte, Burst::Incr4);
+        assert_eq!((plan.head, plan.size, plan.items), (2, Size::Byte, 1));
+    }
 }
@@ -0,0 +1,472 @@
+//! Quadrature encoder interface
+//!
+//! You can use the `Encoder` interface with these TIM instances:
+//!
+//! # TIM1
+//!
+//! - A = CH1 = PA8 (5V tolerant)
+//! - B = CH2 = PA9 (5V tolerant)
+//!
+//! # TIM2
+//!
+//! - A = CH1 = PA0
+//! - B = CH2 = PA1
+//!
+//! # TIM3
+//!
+//! - A = CH1 = PA6
+//! - B = CH2 = PA7
+//!
+//! # TIM4
+//!
+//! - A = CH1 = PB6 (5V tolerant)
+//! - B = CH2 = PB7 (5V tolerant)
+//!
+//! # TIM5
+//!
+//! - A = CH1 = PA0
+//! - B = CH2 = PA1
+//!
+//! The counter counts the edges of the A and / or B signals, up or down
+//! depending on the level of the other signal (encoder interface mode,
+//! RM0368 13.3.16). It is 16 bits wide, except on TIM2 and TIM5; `Position`
+//! extends it to 64 bits using the update interrupt, which fires when the
+//! counter wraps around:
+//!
+//! ``` ignore
+//! // TIMx update interrupt
+//! encoder.update(&mut position);
+//!
+//! // anywhere else, with the update interrupt masked
+//! let counts: i64 = encoder.read(&mut position);
+//! ```
+//!
+//! `Sampler` turns positions taken at a fixed interval into a direction and a
+//! velocity. `Position` and `Sampler` don't touch any peripheral.
+
+use core::any::{Any, TypeId};
+
+use stm32f40x::{TIM1, TIM2, TIM3, TIM4, TIM5, GPIOA, GPIOB, RCC};
+
+use prescaler::Counter;
+use time::Microseconds;
+use timer::{CR1_CEN, CR1_URS, EGR_UG};
+
+/// Signals that are counted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Edges of A (SMS = 001): 2 counts per cycle
+    Ti1,
+    /// Edges of B (SMS = 010): 2 counts per cycle
+    Ti2,
+    /// Edges of A and B (SMS = 011): 4 counts per cycle
+    Both,
+}
+
+impl Mode {
+    fn sms(&self) -> u32 {
+        match *self {
+            Mode::Ti1 => 0b001,
+            Mode::Ti2 => 0b010,
+            Mode::Both => 0b011,
+        }
+    }
+}
+
+/// Direction of rotation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The counter counts up
+    Forward,
+    /// The counter counts down
+    Backward,
+}
+
+/// Encoder configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Signals that are counted
+    pub mode: Mode,
+    /// Digital filter of both inputs, ICxF (0 - 15): an edge is only taken
+    /// once the input has been stable for a number of samples (RM0368
+    /// 13.4.7)
+    pub filter: u8,
+    /// Inverts A, which reverses the counting direction
+    pub invert: bool,
+    /// Enables the internal pull-ups, e.g. for open collector encoders
+    pub pull_up: bool,
+}
+
+impl Config {
+    /// 4 counts per cycle; 8 samples at f_CK_INT filter; no inversion; no
+    /// pull-ups
+    pub fn new() -> Self {
+        Config {
+            mode: Mode::Both,
+            filter: 0b0011,
+            invert: false,
+            pull_up: false,
+        }
+    }
+}
+
+/// Counter wraps seen by `Encoder::update`
+///
+/// Starts at position 0, i.e. right after `Encoder::init` or
+/// `Encoder::reset`.
+pub struct Position {
+    wraps: i64,
+}
+
+impl Position {
+    /// No wrap seen yet
+    pub const fn new() -> Self {
+        Position { wraps: 0 }
+    }
+
+    /// Forgets the wraps seen so far
+    pub fn reset(&mut self) {
+        self.wraps = 0;
+    }
+
+    /// Position for a hardware `count` of a counter of width `counter`
+    pub fn extend(&self, count: u32, counter: Counter) -> i64 {
+        self.wraps * (counter.max() as i64 + 1) + count as i64
+    }
+
+    // Accounts a wrap; `count` was read after it
+    fn wrap(&mut self, count: u32, counter: Counter) {
+        // a wrap happened at most half a range ago
+        if count <= counter.max() / 2 {
+            self.wraps += 1;
+        } else {
+            self.wraps -= 1;
+        }
+    }
+}
+
+/// Motion over one sampling interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Motion {
+    /// Counts since the previous sample
+    pub delta: i64,
+    /// `None` if the encoder didn't move
+    pub direction: Option<Direction>,
+    /// Counts per second
+    pub velocity: f32,
+}
+
+/// Measures the motion between positions sampled at a fixed interval
+pub struct Sampler {
+    last: i64,
+}
+
+impl Sampler {
+    /// Starts from `position`
+    pub const fn new(position: i64) -> Self {
+        Sampler { last: position }
+    }
+
+    /// Records the `position` read `interval` after the previous one
+    pub fn sample(&mut self, position: i64, interval: Microseconds) -> Motion {
+        assert!(interval.0 != 0);
+
+        let delta = position - self.last;
+        self.last = position;
+
+        Motion {
+            delta: delta,
+            direction: if delta > 0 {
+                Some(Direction::Forward)
+            } else if delta < 0 {
+                Some(Direction::Backward)
+            } else {
+                None
+            },
+            velocity: delta as f32 * 1_000_000. / interval.0 as f32,
+        }
+    }
+}
+
+/// Quadrature encoder interface
+pub struct Encoder<'a, T>(pub &'a T)
+where
+    T: 'a;
+
+impl<'a, T> Clone for Encoder<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for Encoder<'a, T> {}
+
+macro_rules! impl_Encoder {
+    ($TIM:ident) => {
+        impl<'a> Encoder<'a, $TIM>
+        {
+            /// Initializes the encoder interface and starts counting from 0
+            ///
+            /// The update interrupt is not enabled; see `listen`.
+            pub fn init(&self, config: &Config, gpioa: &GPIOA, gpiob: &GPIOB, rcc: &RCC) {
+                let tim = self.0;
+
+                assert!(config.filter < 16);
+
+                // enable TIMx and GPIOx
+                if tim.get_type_id() == TypeId::of::<TIM1>() {
+                    rcc.apb2enr.modify(|_, w| w.tim1en().set_bit());
+                } else if tim.get_type_id() == TypeId::of::<TIM2>() {
+                    rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+                } else if tim.get_type_id() == TypeId::of::<TIM3>() {
+                    rcc.apb1enr.modify(|_, w| w.tim3en().set_bit());
+                } else if tim.get_type_id() == TypeId::of::<TIM4>() {
+                    rcc.apb1enr.modify(|_, w| w.tim4en().set_bit());
+                } else if tim.get_type_id() == TypeId::of::<TIM5>() {
+                    rcc.apb1enr.modify(|_, w| w.tim5en().set_bit());
+                }
+
+                let pupd = if config.pull_up { 0b0101 } else { 0b0000 };
+
+                // See datasheet DM00115249 Table 9. Alternate function mapping
+                // AFRx: 4 bits per pin; MODER, PUPDR: 2 bits per pin
+                if tim.get_type_id() == TypeId::of::<TIM4>() {
+                    rcc.ahb1enr.modify(|_, w| w.gpioben().set_bit());
+
+                    // PB6, PB7: AF2
+                    gpiob.afrl.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0xff << 24)) | 0x22 << 24)
+                    });
+                    gpiob.pupdr.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0b1111 << 12)) | pupd << 12)
+                    });
+                    gpiob.moder.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0b1111 << 12)) | 0b1010 << 12)
+                    });
+                } else {
+                    rcc.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+
+                    // TIM1: PA8, PA9 (AF1); TIM2: PA0, PA1 (AF1); TIM3: PA6,
+                    // PA7 (AF2); TIM5: PA0, PA1 (AF2)
+                    let (pin, af) = if tim.get_type_id() == TypeId::of::<TIM1>() {
+                        (8, 0x11)
+                    } else if tim.get_type_id() == TypeId::of::<TIM2>() {
+                        (0, 0x11)
+                    } else if tim.get_type_id() == TypeId::of::<TIM3>() {
+                        (6, 0x22)
+                    } else {
+                        (0, 0x22)
+                    };
+
+                    if pin < 8 {
+                        gpioa.afrl.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0xff << (4 * pin))) | af << (4 * pin))
+                        });
+                    } else {
+                        gpioa.afrh.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !0xff) | af)
+                        });
+                    }
+                    gpioa.pupdr.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0b1111 << (2 * pin))) | pupd << (2 * pin))
+                    });
+                    gpioa.moder.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0b1111 << (2 * pin))) | 0b1010 << (2 * pin))
+                    });
+                }
+
+                // counter stopped while the slave mode changes
+                tim.cr1.write(|w| unsafe { w.bits(0) });
+                tim.smcr.write(|w| unsafe { w.bits(0) });
+
+                // RM0368 13.3.16
+                // CC1S = 01, CC2S = 01: CC1 on TI1, CC2 on TI2; IC1F, IC2F
+                let filter = u32::from(config.filter);
+                tim.ccmr1_output.write(|w| unsafe {
+                    w.bits(filter << 12 | 0b01 << 8 | filter << 4 | 0b01)
+                });
+                // CC1P: invert TI1; CC1NP = CC2P = CC2NP = 0
+                tim.ccer.write(|w| unsafe { w.bits(if config.invert { 1 << 1 } else { 0 }) });
+                tim.smcr.write(|w| unsafe { w.bits(config.mode.sms()) });
+
+                tim.psc.write(|w| unsafe { w.bits(0) });
+                tim.arr.write(|w| unsafe { w.bits(self.counter().max()) });
+
+                // URS: only the counter wrapping sets UIF, not the UG that
+                // loads the prescaler and clears the counter
+                tim.cr1.write(|w| unsafe { w.bits(CR1_URS) });
+                tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+                tim.sr.modify(|_, w| w.uif().clear_bit());
+                tim.cr1.write(|w| unsafe { w.bits(CR1_URS | CR1_CEN) });
+            }
+
+            /// Width of the counter
+            pub fn counter(&self) -> Counter {
+                let tim = self.0;
+
+                if tim.get_type_id() == TypeId::of::<TIM2>()
+                    || tim.get_type_id() == TypeId::of::<TIM5>()
+                {
+                    Counter::Bits32
+                } else {
+                    Counter::Bits16
+                }
+            }
+
+            /// Hardware count
+            pub fn count(&self) -> u32 {
+                self.0.cnt.read().bits()
+            }
+
+            /// Signed hardware count; wraps around at the counter width
+            pub fn position(&self) -> i32 {
+                match self.counter() {
+                    Counter::Bits16 => self.count() as i16 as i32,
+                    Counter::Bits32 => self.count() as i32,
+                }
+            }
+
+            /// Direction of the last counted edge (CR1.DIR)
+            pub fn direction(&self) -> Direction {
+                // NOTE DIR is read-only in encoder mode
+                if self.0.cr1.read().bits() & (1 << 4) == 0 {
+                    Direction::Forward
+                } else {
+                    Direction::Backward
+                }
+            }
+
+            /// Sets the count back to 0; reset the `Position` too
+            pub fn reset(&self) {
+                self.0.cnt.write(|w| unsafe { w.bits(0) });
+            }
+
+            /// Enables the update interrupt, which fires when the counter
+            /// wraps around
+            pub fn listen(&self) {
+                self.0.dier.modify(|_, w| w.uie().set_bit());
+            }
+
+            /// Disables the update interrupt
+            pub fn unlisten(&self) {
+                self.0.dier.modify(|_, w| w.uie().clear_bit());
+            }
+
+            /// Accounts a pending counter wrap in `position`
+            ///
+            /// Call this from the update interrupt. It must run within half a
+            /// counter range (32768 counts on 16-bit timers) of the wrap.
+            pub fn update(&self, position: &mut Position) {
+                let tim = self.0;
+
+                if tim.sr.read().uif().bit_is_set() {
+                    tim.sr.modify(|_, w| w.uif().clear_bit());
+                    position.wrap(self.count(), self.counter());
+                }
+            }
+
+            /// 64-bit position
+            ///
+            /// Accounts a pending wrap first, so the update interrupt must
+            /// not preempt this call.
+            pub fn read(&self, position: &mut Position) -> i64 {
+                loop {
+                    self.update(position);
+
+                    let count = self.count();
+
+                    // no wrap between `update` and the read above
+                    if self.0.sr.read().uif().bit_is_clear() {
+                        return position.extend(count, self.counter());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl_Encoder!(TIM1);
+impl_Encoder!(TIM2);
+impl_Encoder!(TIM3);
+impl_Encoder!(TIM4);
+impl_Encoder!(TIM5);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTERS: [Counter; 2] = [Counter::Bits16, Counter::Bits32];
+
+    #[test]
+    fn wraps() {
+        for &counter in COUNTERS.iter() {
+            let range = i64::from(counter.max()) + 1;
+            let mut position = Position::new();
+
+            assert_eq!(position.extend(counter.max(), counter), range - 1);
+
+            // forward: max -> 0
+            position.wrap(0, counter);
+            assert_eq!(position.extend(0, counter), range);
+            position.wrap(3, counter);
+            assert_eq!(position.extend(3, counter), 2 * range + 3);
+
+            // backward: 0 -> max
+            position.wrap(counter.max(), counter);
+            assert_eq!(position.extend(counter.max(), counter), 2 * range - 1);
+            position.wrap(counter.max() - 3, counter);
+            position.wrap(counter.max() - 3, counter);
+            assert_eq!(position.extend(counter.max() - 3, counter), -4);
+
+            position.reset();
+            assert_eq!(position.extend(7, counter), 7);
+        }
+    }
+
+    #[test]
+    fn underflow() {
+        for &counter in COUNTERS.iter() {
+            let mut position = Position::new();
+
+            // one count down from 0
+            position.wrap(counter.max(), counter);
+            assert_eq!(position.extend(counter.max(), counter), -1);
+        }
+    }
+
+    #[test]
+    fn half_range() {
+        for &counter in COUNTERS.iter() {
+            let range = i64::from(counter.max()) + 1;
+            let half = counter.max() / 2;
+
+            let mut position = Position::new();
+            position.wrap(half, counter);
+            assert_eq!(position.extend(half, counter), range + i64::from(half));
+
+            let mut position = Position::new();
+            position.wrap(half + 1, counter);
+            assert_eq!(position.extend(half + 1, counter), -range + i64::from(half) + 1);
+        }
+    }
+
+    #[test]
+    fn sampler() {
+        let mut sampler = Sampler::new(-10);
+
+        let motion = sampler.sample(40, Microseconds(10_000));
+        assert_eq!(motion.delta, 50);
+        assert_eq!(motion.direction, Some(Direction::Forward));
+        assert_eq!(motion.velocity, 5_000.);
+
+        let motion = sampler.sample(-60, Microseconds(1_000_000));
+        assert_eq!(motion.delta, -100);
+        assert_eq!(motion.direction, Some(Direction::Backward));
+        assert_eq!(motion.velocity, -100.);
+
+        let motion = sampler.sample(-60, Microseconds(1));
+        assert_eq!(motion.delta, 0);
+        assert_eq!(motion.direction, None);
+        assert_eq!(motion.velocity, 0.);
+    }
+}
@@ -0,0 +1,457 @@
+//! Framed binary packets for serial links
+//!
+//! Each packet is protected by a CRC and then encoded with Consistent
+//! Overhead Byte Stuffing (COBS), which removes every `0x00` from the data so
+//! that a single `0x00` can delimit frames:
+//!
+//! ``` text
+//! COBS(payload ++ CRC, little endian) ++ 0x00
+//! ```
+//!
+//! A receiver that starts listening in the middle of a frame, or that sees
+//! corrupted bytes, resynchronises on the next `0x00`. Consecutive delimiters
+//! are ignored. To send a frame, pass the size returned by `encode` to
+//! `Serial::write_prefix` so that only the encoded bytes go out.
+//!
+//! The encoder and the decoder don't touch any peripheral.
+
+use core::marker::Unsize;
+
+use nb;
+
+use crc;
+
+/// Framing error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The CRC of a received frame doesn't match its contents
+    Crc,
+    /// A received frame is not valid COBS or is too short to hold a CRC
+    Encoding,
+    /// The frame doesn't fit in the buffer
+    Overflow,
+    #[doc(hidden)] _Extensible,
+}
+
+/// Checksum appended to every frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Checksum {
+    /// CRC-16/CCITT-FALSE, 2 bytes
+    Crc16,
+    /// CRC-32 (IEEE 802.3), 4 bytes
+    Crc32,
+}
+
+impl Checksum {
+    /// Size of the checksum in bytes
+    pub fn len(&self) -> usize {
+        match *self {
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    fn compute(&self, data: &[u8]) -> [u8; 4] {
+        match *self {
+            Checksum::Crc16 => {
+                let crc = crc::crc16_ccitt(data);
+                [crc as u8, (crc >> 8) as u8, 0, 0]
+            }
+            Checksum::Crc32 => {
+                let crc = crc::crc32(data);
+                [crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]
+            }
+        }
+    }
+}
+
+/// Worst case size of an encoded frame carrying `payload` bytes, including
+/// the delimiter
+pub fn max_encoded_len(payload: usize, checksum: Checksum) -> usize {
+    let n = payload + checksum.len();
+    // one code byte per started block of 254 bytes, plus the delimiter
+    n + n / 254 + 1 + 1
+}
+
+/// Encodes `payload` into `out` and returns the size of the frame, including
+/// the trailing delimiter
+pub fn encode(payload: &[u8], checksum: Checksum, out: &mut [u8]) -> Result<usize, Error> {
+    let crc = checksum.compute(payload);
+
+    let mut encoder = Encoder::new(out)?;
+    for byte in payload.iter().chain(&crc[..checksum.len()]) {
+        encoder.push(*byte)?;
+    }
+    encoder.finish()
+}
+
+struct Encoder<'a> {
+    out: &'a mut [u8],
+    // where the code byte of the current block goes
+    code_index: usize,
+    code: u8,
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(out: &'a mut [u8]) -> Result<Self, Error> {
+        if out.is_empty() {
+            return Err(Error::Overflow);
+        }
+
+        Ok(Encoder {
+            out: out,
+            code_index: 0,
+            code: 1,
+            pos: 1,
+        })
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), Error> {
+        if byte == 0 {
+            return self.end_block();
+        }
+
+        if self.pos >= self.out.len() {
+            return Err(Error::Overflow);
+        }
+        self.out[self.pos] = byte;
+        self.pos += 1;
+        self.code += 1;
+
+        if self.code == 0xff {
+            self.end_block()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn end_block(&mut self) -> Result<(), Error> {
+        self.out[self.code_index] = self.code;
+
+        if self.pos >= self.out.len() {
+            return Err(Error::Overflow);
+        }
+        self.code_index = self.pos;
+        self.pos += 1;
+        self.code = 1;
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<usize, Error> {
+        self.out[self.code_index] = self.code;
+
+        if self.pos >= self.out.len() {
+            return Err(Error::Overflow);
+        }
+        self.out[self.pos] = 0;
+
+        Ok(self.pos + 1)
+    }
+}
+
+/// Incremental frame decoder
+///
+/// Feed it the received bytes one at a time; complete frames are decoded
+/// into the buffer `B`, which must be large enough for a payload plus its
+/// checksum.
+pub struct Decoder<B>
+where
+    B: Unsize<[u8]>,
+{
+    buffer: B,
+    checksum: Checksum,
+    len: usize,
+    // bytes left in the current COBS block, 0 = next byte is a code byte
+    remaining: u8,
+    // code of the current block, 0 = no block started yet
+    code: u8,
+    // drop everything until the next delimiter
+    discard: bool,
+}
+
+impl<B> Decoder<B>
+where
+    B: Unsize<[u8]>,
+{
+    /// Creates a decoder that uses `buffer` as storage
+    pub const fn new(buffer: B, checksum: Checksum) -> Self {
+        Decoder {
+            buffer: buffer,
+            checksum: checksum,
+            len: 0,
+            remaining: 0,
+            code: 0,
+            discard: false,
+        }
+    }
+
+    /// Forgets the frame being received
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.remaining = 0;
+        self.code = 0;
+        self.discard = false;
+    }
+
+    /// Feeds a received `byte` to the decoder
+    ///
+    /// Returns the payload once a complete frame with a valid checksum has
+    /// been received, and `WouldBlock` while the frame is incomplete. After
+    /// an error the bytes up to the next delimiter are dropped.
+    pub fn feed(&mut self, byte: u8) -> nb::Result<&[u8], Error> {
+        if byte == 0 {
+            return self.end_frame();
+        }
+
+        if self.discard {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        if self.remaining == 0 {
+            // a block shorter than 254 bytes stands for its data plus a zero
+            if self.code != 0 && self.code != 0xff {
+                self.push(0)?;
+            }
+            self.code = byte;
+            self.remaining = byte - 1;
+        } else {
+            self.push(byte)?;
+            self.remaining -= 1;
+        }
+
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn push(&mut self, byte: u8) -> nb::Result<(), Error> {
+        let buffer: &mut [u8] = &mut self.buffer;
+
+        if self.len == buffer.len() {
+            self.discard = true;
+            return Err(nb::Error::Other(Error::Overflow));
+        }
+
+        buffer[self.len] = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> nb::Result<&[u8], Error> {
+        let len = self.len;
+        let code = self.code;
+        let remaining = self.remaining;
+        let discard = self.discard;
+        self.reset();
+
+        if discard || code == 0 {
+            // end of a dropped frame, or back-to-back delimiters
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let n = self.checksum.len();
+        if remaining != 0 || len < n {
+            return Err(nb::Error::Other(Error::Encoding));
+        }
+
+        let buffer: &[u8] = &self.buffer;
+        let (payload, crc) = buffer[..len].split_at(len - n);
+
+        if self.checksum.compute(payload)[..n] == *crc {
+            Ok(payload)
+        } else {
+            Err(nb::Error::Other(Error::Crc))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use test_util::Xorshift32;
+
+    // Plain COBS, without checksum and delimiter handling
+    fn cobs(data: &[u8]) -> Vec<u8> {
+        let mut out = [0; 1024];
+        let mut encoder = Encoder::new(&mut out).unwrap();
+        for byte in data {
+            encoder.push(*byte).unwrap();
+        }
+        let n = encoder.finish().unwrap();
+        out[..n].to_vec()
+    }
+
+    // Feeds `bytes` and returns everything but `WouldBlock`
+    fn decode<B>(decoder: &mut Decoder<B>, bytes: &[u8]) -> Vec<Result<Vec<u8>, Error>>
+    where
+        B: Unsize<[u8]>,
+    {
+        let mut frames = Vec::new();
+        for byte in bytes {
+            match decoder.feed(*byte) {
+                Ok(payload) => frames.push(Ok(payload.to_vec())),
+                Err(nb::Error::Other(e)) => frames.push(Err(e)),
+                Err(nb::Error::WouldBlock) => {}
+            }
+        }
+        frames
+    }
+
+    fn frame(payload: &[u8], checksum: Checksum) -> Vec<u8> {
+        let mut out = [0; 1024];
+        let n = encode(payload, checksum, &mut out).unwrap();
+        out[..n].to_vec()
+    }
+
+    #[test]
+    fn cobs_vectors() {
+        assert_eq!(cobs(&[]), [0x01, 0x00]);
+        assert_eq!(cobs(&[0x00]), [0x01, 0x01, 0x00]);
+        assert_eq!(cobs(&[0x00, 0x00, 0x00]), [0x01, 0x01, 0x01, 0x01, 0x00]);
+        assert_eq!(
+            cobs(&[0x11, 0x22, 0x00, 0x33]),
+            [0x03, 0x11, 0x22, 0x02, 0x33, 0x00]
+        );
+        assert_eq!(cobs(&[0x11, 0x00, 0x00]), [0x02, 0x11, 0x01, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn cobs_long_runs() {
+        let data: Vec<u8> = (1..256).map(|i| i as u8).collect();
+
+        // 254 non-zero bytes fill a whole block
+        let out = cobs(&data[..254]);
+        assert_eq!(out.len(), 254 + 3);
+        assert_eq!(out[0], 0xff);
+        assert_eq!(out[1..255], data[..254]);
+        assert_eq!(out[255..], [0x01, 0x00]);
+
+        // the 255th starts a new one
+        let out = cobs(&data);
+        assert_eq!(out.len(), 255 + 3);
+        assert_eq!(out[0], 0xff);
+        assert_eq!(out[1..255], data[..254]);
+        assert_eq!(out[255..], [0x02, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn edge_cases() {
+        let long: Vec<u8> = (0..600).map(|i| (i % 255 + 1) as u8).collect();
+        let zeros = [0; 300];
+
+        for checksum in &[Checksum::Crc16, Checksum::Crc32] {
+            let checksum = *checksum;
+            let mut decoder = Decoder::new([0; 1024], checksum);
+
+            for payload in &[
+                &[][..],
+                &zeros[..1],
+                &zeros[..],
+                &long[..254],
+                &long[..255],
+                &long[..254 - checksum.len()],
+                &long[..255 - checksum.len()],
+                &long[..],
+            ] {
+                let bytes = frame(payload, checksum);
+                assert!(bytes.len() <= max_encoded_len(payload.len(), checksum));
+                assert!(bytes[..bytes.len() - 1].iter().all(|b| *b != 0));
+                assert_eq!(decode(&mut decoder, &bytes), [Ok(payload.to_vec())]);
+            }
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let mut out = [0; 8];
+        assert_eq!(
+            encode(&[1; 4], Checksum::Crc16, &mut out).map(|_| ()),
+            Ok(())
+        );
+        assert_eq!(
+            encode(&[1; 8], Checksum::Crc16, &mut out),
+            Err(Error::Overflow)
+        );
+        assert_eq!(encode(&[], Checksum::Crc16, &mut []), Err(Error::Overflow));
+
+        let mut decoder = Decoder::new([0; 6], Checksum::Crc16);
+        let mut bytes = frame(&[1; 5], Checksum::Crc16);
+        bytes.extend(frame(&[2; 4], Checksum::Crc16));
+        assert_eq!(
+            decode(&mut decoder, &bytes),
+            [Err(Error::Overflow), Ok([2; 4].to_vec())]
+        );
+    }
+
+    #[test]
+    fn errors_and_resync() {
+        let mut decoder = Decoder::new([0; 64], Checksum::Crc16);
+
+        let good = frame(b"hello", Checksum::Crc16);
+        let mut bad = good.clone();
+        bad[2] ^= 0x01;
+
+        let mut bytes = Vec::new();
+        // tail of a frame we started listening in the middle of
+        bytes.extend_from_slice(&good[3..]);
+        bytes.extend_from_slice(&[0, 0, 0]);
+        bytes.extend_from_slice(&bad);
+        // a code byte pointing past the end of the frame
+        bytes.extend_from_slice(&[0x05, 0x01, 0x02, 0x00]);
+        // too short to hold a CRC
+        bytes.extend_from_slice(&[0x02, 0x01, 0x00]);
+        bytes.extend_from_slice(&good);
+
+        let frames = decode(&mut decoder, &bytes);
+        // depending on where we started, the tail is a CRC or encoding error
+        assert!(frames[0].is_err());
+        assert_eq!(
+            frames[1..],
+            [
+                Err(Error::Crc),
+                Err(Error::Encoding),
+                Err(Error::Encoding),
+                Ok(b"hello".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Xorshift32::new(0x1234_5678);
+
+        let mut decoders = (
+            Decoder::new([0; 1024], Checksum::Crc16),
+            Decoder::new([0; 1024], Checksum::Crc32),
+        );
+
+        for i in 0..2_000 {
+            let len = rng.next_u32() as usize % 700;
+            // vary the density of zeros
+            let zeros = rng.next_u32() % 4;
+            let payload: Vec<u8> = (0..len)
+                .map(|_| {
+                    let x = rng.next_u32();
+                    if x % 4 < zeros {
+                        0
+                    } else {
+                        (x >> 8) as u8
+                    }
+                })
+                .collect();
+
+            let (checksum, decoder) = if i % 2 == 0 {
+                (Checksum::Crc16, &mut decoders.0)
+            } else {
+                (Checksum::Crc32, &mut decoders.1)
+            };
+
+            let bytes = frame(&payload, checksum);
+            assert!(bytes.len() <= max_encoded_len(len, checksum));
+            assert_eq!(decode(decoder, &bytes), [Ok(payload)]);
+        }
+    }
+}
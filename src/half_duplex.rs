@@ -0,0 +1,382 @@
+//! Half duplex USART modes
+//!
+//! - `SingleWire`: TX and RX share the TX pin (HDSEL), e.g. for smart servos
+//!   on a Dynamixel-style bus
+//! - `Irda`: IrDA SIR ENDEC, to drive an infrared transceiver
+//! - `Smartcard`: ISO 7816-3 asynchronous smartcard (e.g. SIM) interface
+//!
+//! Every mode wraps a `Serial` interface that has already been `init`ialized;
+//! its `init` reconfigures the USART (and pins) for the mode.
+//!
+//! # USART2 pins
+//!
+//! - Single wire: PA2 (open drain, needs a pull-up); PA3 is released
+//! - IrDA: TX = IrDA_OUT = PA2, RX = IrDA_IN = PA3
+//! - Smartcard: I/O = PA2 (open drain, needs a pull-up), CLK = PA4; PA3 is
+//!   released
+//!
+//! # Interrupts
+//!
+//! - USART TC: call `SingleWire::on_interrupt` to switch back to reception
+
+use core::any::{Any, TypeId};
+
+use hal;
+use nb;
+use stm32f40x::USART2;
+
+use serial::{self, Event, Serial, Usart};
+use time::Seconds;
+
+/// Direction of a half duplex link
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Receiver disabled, the transmitter owns the line
+    Transmit,
+    /// Receiver enabled, the transmitter is idle
+    Receive,
+}
+
+// RM0368 19.3.10 - 19.3.12: LINEN and CLKEN must be cleared in IrDA and
+// single wire mode, and LINEN in smartcard mode
+fn disable_lin<U>(usart: &U)
+where
+    U: Usart,
+{
+    usart.cr2.modify(|_, w| w.linen().clear_bit().clken().clear_bit());
+}
+
+// PA2 as open drain alternate function, PA3 back to input
+fn open_drain_tx<U>(usart: &U, gpio: &U::GPIO, pull_up: bool)
+where
+    U: Any + Usart,
+{
+    if usart.get_type_id() == TypeId::of::<USART2>() {
+        gpio.otyper.modify(|_, w| w.ot2().set_bit());
+        gpio.pupdr.modify(|_, w| unsafe {
+            w.pupdr2()
+                .bits(if pull_up { 0b01 } else { 0b00 })
+        });
+        gpio.moder.modify(|_, w| w.moder3().bits(0));
+    }
+}
+
+/// Half duplex single wire interface
+pub struct SingleWire<'a, U>(pub Serial<'a, U>)
+where
+    U: 'a + Any + Usart;
+
+impl<'a, U> SingleWire<'a, U>
+where
+    U: Any + Usart,
+{
+    /// Switches an initialized serial interface to single wire mode
+    ///
+    /// The TX pin becomes open drain; `pull_up` enables the internal (weak)
+    /// pull-up, an external one is recommended above 115200 baud. The
+    /// interface starts in the `Receive` direction.
+    pub fn init(&self, gpio: &U::GPIO, pull_up: bool) {
+        let usart = (self.0).0;
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        open_drain_tx(usart, gpio, pull_up);
+        disable_lin(usart);
+        // RM0368 19.3.10 Single-wire half-duplex communication
+        usart.cr3.modify(|_, w| {
+            w.scen()
+                .clear_bit()
+                .iren()
+                .clear_bit()
+                .hdsel()
+                .set_bit()
+        });
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+
+        self.set_direction(Direction::Receive);
+    }
+
+    /// Switches the direction of the line
+    ///
+    /// While transmitting the receiver is disabled so that the bytes on the
+    /// wire are not echoed back.
+    pub fn set_direction(&self, direction: Direction) {
+        let usart = (self.0).0;
+
+        match direction {
+            Direction::Transmit => usart.cr1.modify(|_, w| w.re().clear_bit().te().set_bit()),
+            Direction::Receive => usart.cr1.modify(|_, w| w.re().set_bit()),
+        }
+    }
+
+    /// Current direction of the line
+    pub fn direction(&self) -> Direction {
+        if (self.0).0.cr1.read().re().bit_is_set() {
+            Direction::Receive
+        } else {
+            Direction::Transmit
+        }
+    }
+
+    /// Switches to `Transmit` and arms the transmission complete interrupt,
+    /// which switches back to `Receive` through `on_interrupt`
+    pub fn start_transmission(&self) {
+        let usart = (self.0).0;
+
+        self.set_direction(Direction::Transmit);
+        // NOTE(write) the SR flags are cleared by writing zero; write ones to
+        // the others so that we don't clear RXNE by accident
+        usart
+            .sr
+            .write(|w| unsafe { w.bits(0xffff_ffff).tc().clear_bit() });
+        self.0.listen(Event::Tc);
+    }
+
+    /// Switches back to `Receive` once the last byte has been sent
+    ///
+    /// Call this from the USART interrupt. Returns `true` if the direction
+    /// changed.
+    pub fn on_interrupt(&self) -> bool {
+        let usart = (self.0).0;
+
+        if usart.cr1.read().tcie().bit_is_set() && usart.sr.read().tc().bit_is_set() {
+            self.0.unlisten(Event::Tc);
+            self.set_direction(Direction::Receive);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<'a, U> hal::serial::Read<u8> for SingleWire<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = serial::Error;
+
+    fn read(&self) -> serial::Result<u8> {
+        hal::serial::Read::read(&self.0)
+    }
+}
+
+impl<'a, U> hal::serial::Write<u8> for SingleWire<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = serial::Error;
+
+    fn write(&self, byte: u8) -> serial::Result<()> {
+        if (self.0).0.sr.read().txe().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        if self.direction() == Direction::Receive {
+            self.start_transmission();
+        }
+
+        hal::serial::Write::write(&self.0, byte)
+    }
+}
+
+/// IrDA SIR power mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IrdaMode {
+    /// Pulses are 3/16 of a bit period
+    Normal,
+    /// Pulses are 3 periods of the low power clock (~1.6 us at 1.8432 MHz),
+    /// independently of the baud rate
+    LowPower,
+}
+
+/// IrDA SIR interface
+pub struct Irda<'a, U>(pub Serial<'a, U>)
+where
+    U: 'a + Any + Usart;
+
+impl<'a, U> Irda<'a, U>
+where
+    U: Any + Usart,
+{
+    /// Switches an initialized serial interface to IrDA SIR mode
+    ///
+    /// The baud rate set by `Serial::init` must not exceed 115200.
+    pub fn init(&self, mode: IrdaMode) {
+        let usart = (self.0).0;
+
+        // RM0368 19.3.12 IrDA SIR ENDEC block: PSC must be 1 in normal mode;
+        // in low power mode it divides the APB clock down to ~1.8432 MHz
+        let psc = match mode {
+            IrdaMode::Normal => 1,
+            IrdaMode::LowPower => low_power_prescaler(),
+        };
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr2.modify(|_, w| unsafe { w.stop().bits(0b00) });
+        disable_lin(usart);
+        usart.gtpr.modify(|_, w| unsafe { w.psc().bits(psc) });
+        usart.cr3.modify(|_, w| {
+            w.scen()
+                .clear_bit()
+                .hdsel()
+                .clear_bit()
+                .irlp()
+                .bit(mode == IrdaMode::LowPower)
+                .iren()
+                .set_bit()
+        });
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+}
+
+// APB1 clock / 1.8432 MHz, rounded to the nearest prescaler
+fn low_power_prescaler() -> u8 {
+    let pclk: u32 = ::apb1::Ticks::from(Seconds(1)).into();
+    let psc = (pclk + 921_600) / 1_843_200;
+
+    assert!(psc >= 1 && psc <= 255);
+    psc as u8
+}
+
+/// Smartcard configuration
+#[derive(Clone, Copy, Debug)]
+pub struct SmartcardConfig {
+    prescaler: u8,
+    /// Guard time after each transmitted character, in bit periods (ETUs)
+    pub guard_time: u8,
+    /// Signal parity errors to the card with a NACK
+    pub nack: bool,
+}
+
+impl SmartcardConfig {
+    /// Configuration with a card clock of APB clock / (2 * `prescaler`), a
+    /// guard time of 2 ETUs (12 ETU characters, the ISO 7816-3 default) and
+    /// NACK enabled
+    ///
+    /// Returns `None` if `prescaler` is not 1 - 31
+    pub fn new(prescaler: u8) -> Option<Self> {
+        if prescaler >= 1 && prescaler <= 31 {
+            Some(SmartcardConfig {
+                prescaler: prescaler,
+                guard_time: 2,
+                nack: true,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Card clock prescaler, 1 - 31
+    pub fn prescaler(&self) -> u8 {
+        self.prescaler
+    }
+
+    /// Frequency of the card clock for an APB clock of `pclk` Hz
+    pub fn clock(&self, pclk: u32) -> u32 {
+        pclk / (2 * self.prescaler as u32)
+    }
+
+    /// Baud rate for the default ISO 7816-3 elementary time unit (ETU) of
+    /// 372 card clock cycles, to be passed to `Serial::init`
+    pub fn baud_rate(&self, pclk: u32) -> u32 {
+        self.clock(pclk) / 372
+    }
+}
+
+/// ISO 7816-3 smartcard interface
+pub struct Smartcard<'a, U>(pub Serial<'a, U>)
+where
+    U: 'a + Any + Usart;
+
+impl<'a, U> Smartcard<'a, U>
+where
+    U: Any + Usart,
+{
+    /// Switches an initialized serial interface to smartcard mode
+    ///
+    /// The baud rate set by `Serial::init` must match the ETU, see
+    /// `SmartcardConfig::baud_rate`. The I/O pin becomes open drain with the
+    /// internal pull-up enabled and the card clock is output on CK.
+    pub fn init(&self, config: SmartcardConfig, gpio: &U::GPIO) {
+        let usart = (self.0).0;
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+
+        open_drain_tx(usart, gpio, true);
+        if usart.get_type_id() == TypeId::of::<USART2>() {
+            // DM00102166 - Alternate function AF7, Table 9
+            // PA4 = CK (alternate function push-pull)
+            gpio.afrl.modify(|_, w| w.afrl4().bits(7));
+            gpio.ospeedr.modify(|_, w| w.ospeedr4().bits(0b11));
+            gpio.moder.modify(|_, w| w.moder4().bits(2));
+        }
+
+        // RM0368 19.3.11 Smartcard: 9-bit words with even parity, 1.5 stop
+        // bits, clock output enabled
+        usart
+            .gtpr
+            .write(|w| unsafe { w.gt().bits(config.guard_time).psc().bits(config.prescaler) });
+        usart.cr2.modify(|_, w| unsafe {
+            w.linen()
+                .clear_bit()
+                .stop()
+                .bits(0b11)
+                .clken()
+                .set_bit()
+        });
+        usart.cr3.modify(|_, w| {
+            w.hdsel()
+                .clear_bit()
+                .iren()
+                .clear_bit()
+                .nack()
+                .bit(config.nack)
+                .scen()
+                .set_bit()
+        });
+        usart.cr1.modify(|_, w| {
+            w.m()
+                .set_bit()
+                .pce()
+                .set_bit()
+                .ps()
+                .clear_bit()
+                .ue()
+                .set_bit()
+        });
+    }
+
+    /// Holds (`true`) or releases (`false`) the card clock
+    ///
+    /// The clock can only be changed while the USART is idle
+    pub fn clock_enable(&self, enable: bool) {
+        let usart = (self.0).0;
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr2.modify(|_, w| w.clken().bit(enable));
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+}
+
+impl<'a, U> hal::serial::Read<u8> for Smartcard<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = serial::Error;
+
+    fn read(&self) -> serial::Result<u8> {
+        // NOTE the parity bit is checked by the hardware; with `nack` set the
+        // card is asked to repeat a corrupted character
+        hal::serial::Read::read(&self.0)
+    }
+}
+
+impl<'a, U> hal::serial::Write<u8> for Smartcard<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = serial::Error;
+
+    fn write(&self, byte: u8) -> serial::Result<()> {
+        hal::serial::Write::write(&self.0, byte)
+    }
+}
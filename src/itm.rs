@@ -0,0 +1,254 @@
+//! Instrumentation Trace Macrocell (ITM) output over SWO
+//!
+//! `init` sets up the trace chain: DBGMCU trace pin, TPIU (SWO protocol and
+//! prescaler derived from the core clock), ITM (stimulus ports, timestamps)
+//! and optionally the DWT packets (PC sampling, exception trace).
+//!
+//! A `Port` writes to one stimulus port; by convention this crate uses
+//! `TEXT` for strings and `DATA` for binary data so that the host can tell
+//! them apart. The `swo` module decodes the captured stream.
+//!
+//! # Pins
+//!
+//! - SWO = PB3 (must be left in its reset configuration, AF0)
+//!
+//! NOTE A debugger (e.g. OpenOCD's `tpiu config`) can configure the same
+//! registers; calling `init` overrides them.
+
+use core::fmt;
+use core::ptr;
+
+use cortex_m::itm;
+use stm32f40x::{DCB, DWT, ITM, TPIU};
+use time::Seconds;
+
+/// Stimulus port for text
+pub const TEXT: u8 = 0;
+
+/// Stimulus port for binary data
+pub const DATA: u8 = 1;
+
+/// ITM error
+#[derive(Debug)]
+pub enum Error {
+    /// The SWO baud rate can't be derived from the core clock within 3%
+    BaudRate,
+    /// Stimulus port number out of range or port disabled
+    Port,
+    #[doc(hidden)] _Extensible,
+}
+
+/// SWO line protocol
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    /// Manchester encoding
+    Manchester,
+    /// NRZ, i.e. UART 8N1; what most probes and USB-UART adapters expect
+    Nrz,
+}
+
+/// DWT periodic PC sampling rate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PcSampling {
+    /// One sample every 64 * `n` core cycles, `n` = 1 - 16
+    Every64(u8),
+    /// One sample every 1024 * `n` core cycles, `n` = 1 - 16
+    Every1024(u8),
+}
+
+/// Trace configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// SWO baud rate
+    pub baud_rate: u32,
+    /// SWO line protocol
+    pub protocol: Protocol,
+    /// Bit mask of the stimulus ports to enable
+    pub ports: u32,
+    /// Emit local timestamp packets
+    pub timestamps: bool,
+    /// Emit periodic PC samples
+    pub pc_sampling: Option<PcSampling>,
+    /// Emit exception entry / exit / return packets
+    pub exception_trace: bool,
+}
+
+impl Config {
+    /// Text and data ports over 2 Mbaud NRZ, no timestamps and no DWT
+    /// packets
+    pub fn new() -> Self {
+        Config {
+            baud_rate: 2_000_000,
+            protocol: Protocol::Nrz,
+            ports: 1 << TEXT | 1 << DATA,
+            timestamps: false,
+            pc_sampling: None,
+            exception_trace: false,
+        }
+    }
+}
+
+/// Computes the TPIU ACPR value (prescaler - 1) that produces `baud_rate`
+/// from `core_clock`
+///
+/// Fails if the actual baud rate would be more than 3% off.
+pub fn swo_prescaler(core_clock: u32, baud_rate: u32) -> Result<u16, Error> {
+    if baud_rate == 0 || baud_rate > core_clock {
+        return Err(Error::BaudRate);
+    }
+
+    // round to the nearest divider
+    let div = (core_clock + baud_rate / 2) / baud_rate;
+    // ACPR.SWOSCALER is 16 bits wide and holds the divider minus one
+    if div == 0 || div > 0x1_0000 {
+        return Err(Error::BaudRate);
+    }
+
+    let actual = core_clock / div;
+    let error = if actual > baud_rate {
+        actual - baud_rate
+    } else {
+        baud_rate - actual
+    };
+    if error as u64 * 100 > baud_rate as u64 * 3 {
+        return Err(Error::BaudRate);
+    }
+
+    Ok((div - 1) as u16)
+}
+
+// DBGMCU_CR, not part of the device crate
+const DBGMCU_CR: *mut u32 = 0xE004_2004 as *mut u32;
+
+/// Configures the trace chain using the current core clock
+pub fn init(config: &Config) -> Result<(), Error> {
+    let core_clock: u32 = ::ahb1::Ticks::from(Seconds(1)).into();
+    let acpr = swo_prescaler(core_clock, config.baud_rate)?;
+
+    // NOTE(unsafe) debug registers nobody else in this crate writes
+    unsafe {
+        let dcb = &*DCB.get();
+        let tpiu = &*TPIU.get();
+        let itm = &*ITM.get();
+        let dwt = &*DWT.get();
+
+        // DEMCR.TRCENA: power the trace blocks
+        dcb.demcr.modify(|r| r | (1 << 24));
+
+        // RM0368 23.16.3 DBGMCU_CR: TRACE_IOEN, asynchronous (SWO) mode
+        let cr = ptr::read_volatile(DBGMCU_CR);
+        ptr::write_volatile(DBGMCU_CR, (cr & !(0b11 << 6)) | (1 << 5));
+
+        // TPIU: 1-bit port, formatter bypassed, SWO protocol and prescaler
+        tpiu.cspsr.write(1);
+        tpiu.ffcr.write(1 << 8);
+        tpiu.sppr.write(match config.protocol {
+            Protocol::Manchester => 1,
+            Protocol::Nrz => 2,
+        });
+        tpiu.acpr.write(acpr as u32);
+
+        // DWT: cycle counter (time base of the PC sampling), PC sampling and
+        // exception trace
+        let mut ctrl = dwt.ctrl.read();
+        ctrl &= !(0xf << 1 | 1 << 9 | 1 << 12 | 1 << 16);
+        ctrl |= 1;
+        if let Some(sampling) = config.pc_sampling {
+            let (cyctap, n) = match sampling {
+                PcSampling::Every64(n) => (0, n),
+                PcSampling::Every1024(n) => (1, n),
+            };
+            assert!(n >= 1 && n <= 16);
+            // POSTPRESET reloads the sampling counter
+            ctrl |= (n as u32 - 1) << 1 | cyctap << 9 | 1 << 12;
+        }
+        if config.exception_trace {
+            ctrl |= 1 << 16;
+        }
+        dwt.ctrl.write(ctrl);
+
+        // ITM: unlock, allow unprivileged access, enable the ports
+        itm.lar.write(0xC5AC_CE55);
+        itm.tcr.write(0);
+        itm.tpr.write(0);
+        itm.ter[0].write(config.ports);
+        // TraceBusID = 1, SWOENA = 0 (timestamps from the core clock),
+        // DWTENA, SYNCENA, TSENA, ITMENA
+        let dwt_packets = config.pc_sampling.is_some() || config.exception_trace;
+        itm.tcr.write(
+            1 << 16 | (dwt_packets as u32) << 3 | 1 << 2 | (config.timestamps as u32) << 1 | 1,
+        );
+    }
+
+    Ok(())
+}
+
+/// Disables the ITM; writes to the ports are then discarded
+pub fn disable() {
+    // NOTE(unsafe) see `init`
+    unsafe {
+        let itm = &*ITM.get();
+
+        // wait until the last packets have been flushed (TCR.BUSY)
+        while itm.tcr.read() & (1 << 23) != 0 {}
+        itm.tcr.modify(|r| r & !1);
+    }
+}
+
+/// A stimulus port
+#[derive(Clone, Copy, Debug)]
+pub struct Port(u8);
+
+impl Port {
+    /// Stimulus port `n` (0 - 31)
+    pub fn new(n: u8) -> Result<Self, Error> {
+        if n < 32 {
+            Ok(Port(n))
+        } else {
+            Err(Error::Port)
+        }
+    }
+
+    /// `true` if the ITM and this port are enabled
+    ///
+    /// Writes to a disabled port are discarded.
+    pub fn is_enabled(&self) -> bool {
+        // NOTE(unsafe) atomic reads with no side effects
+        unsafe {
+            let itm = &*ITM.get();
+            itm.tcr.read() & 1 != 0 && itm.ter[0].read() & (1 << self.0) != 0
+        }
+    }
+
+    /// Writes `bytes`, packed into word sized packets where possible
+    pub fn write_all(&self, bytes: &[u8]) {
+        if self.is_enabled() {
+            // NOTE(unsafe) the stimulus FIFO is polled before each write;
+            // concurrent writers to the same port interleave their packets
+            unsafe { itm::write_all(&(*ITM.get()).stim[self.0 as usize], bytes) }
+        }
+    }
+
+    /// Writes a string
+    pub fn write_str(&self, s: &str) {
+        self.write_all(s.as_bytes())
+    }
+
+    /// Writes a 32-bit word as a single packet
+    pub fn write_u32(&self, value: u32) {
+        if self.is_enabled() {
+            unsafe {
+                let stim = &(*ITM.get()).stim[self.0 as usize];
+                while !stim.is_fifo_ready() {}
+                stim.write_u32(value);
+            }
+        }
+    }
+}
+
+impl fmt::Write for Port {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Port::write_str(self, s);
+        Ok(())
+    }
+}
@@ -19,23 +19,39 @@
 #![feature(unsize)]
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 extern crate cast;
+extern crate cortex_m;
+extern crate cortex_m_semihosting;
 extern crate embedded_hal as hal;
+extern crate log;
 extern crate m;
 extern crate nb;
-extern crate static_ref;
+extern crate volatile_register;
 pub extern crate stm32f40x;
 
 pub mod math_utils;
 pub mod dma;
+pub mod ring;
 pub mod led;
 pub mod button;
 pub mod leds;
 pub mod serial;
+pub mod buffered_serial;
+pub mod rs485;
+pub mod lin;
+pub mod half_duplex;
+pub mod autobaud;
+pub mod spsc;
 pub mod timer;
+pub mod prescaler;
 pub mod time;
 pub mod pwm;
 pub mod capture;
+pub mod encoder;
 pub mod clock;
 pub mod spi;
 pub mod lsm9ds1;
@@ -44,11 +60,23 @@ pub mod madgwick_ahrs;
 pub mod dwt;
 pub mod adc;
 pub mod i2c;
+pub mod shell;
+pub mod crc;
+pub mod frame;
+pub mod modbus;
+pub mod xmodem;
+pub mod logger;
+pub mod itm;
+pub mod swo;
+
+#[cfg(test)]
+mod test_util;
 
 use frequency::*;
 
 pub use adc::{Adc, AdcChannel};
 pub use capture::Capture;
+pub use encoder::Encoder;
 pub use hal::prelude;
 pub use i2c::I2c;
 pub use lsm9ds1::{ImuSettings, Lsm9ds1};
@@ -0,0 +1,586 @@
+//! Local Interconnect Network (LIN)
+//!
+//! A LIN frame is a header sent by the master followed by a response sent by
+//! whichever node publishes that frame (possibly the master itself):
+//!
+//! ``` text
+//! break (>= 13 bits dominant) | sync 0x55 | protected id | data (1-8) | checksum
+//! ```
+//!
+//! `Lin` puts a `Serial` interface in LIN mode (break generation and
+//! detection). The protocol logic is hardware independent:
+//!
+//! - `protected_id` / `id_from_protected` add and check the identifier
+//!   parity bits
+//! - `checksum` computes the classic (LIN 1.x) and enhanced (LIN 2.x)
+//!   checksums
+//! - `FrameReader` follows the bytes on the bus after a break and reports
+//!   headers and complete responses; both master and slaves use it, the
+//!   master sees its own header because the transceiver echoes the bus
+//! - `Scheduler` walks a schedule table and tells the master which header to
+//!   send at each time slot
+//!
+//! # Interrupts
+//!
+//! - USART LBD (`Event::LinBreak`): call `Lin::clear_break` and
+//!   `FrameReader::on_break`
+//! - USART RXNE: feed the byte returned by `Lin::read` to `FrameReader::feed`
+
+use core::any::Any;
+use core::ptr;
+
+use hal;
+use nb;
+
+use serial::{self, Serial, Usart};
+
+/// LIN error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The byte after the break was not the 0x55 sync field
+    Sync,
+    /// The parity bits of the protected identifier are wrong
+    Parity,
+    /// The response checksum doesn't match
+    Checksum,
+    #[doc(hidden)] _Extensible,
+}
+
+/// Checksum model
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChecksumModel {
+    /// LIN 1.x: data bytes only
+    Classic,
+    /// LIN 2.x: protected identifier and data bytes. Diagnostic frames
+    /// (0x3C and 0x3D) always use the classic checksum
+    Enhanced,
+}
+
+/// Length of the break field the receiver detects
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakLength {
+    /// 10-bit break detection
+    _10,
+    /// 11-bit break detection
+    _11,
+}
+
+/// Adds the parity bits P0 (bit 6) and P1 (bit 7) to a 6-bit identifier
+pub fn protected_id(id: u8) -> u8 {
+    let id = id & 0x3f;
+    let bit = |n: u8| (id >> n) & 1;
+
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+
+    id | p0 << 6 | p1 << 7
+}
+
+/// Checks the parity of a protected identifier and returns the 6-bit
+/// identifier
+pub fn id_from_protected(pid: u8) -> Result<u8, Error> {
+    let id = pid & 0x3f;
+
+    if protected_id(id) == pid {
+        Ok(id)
+    } else {
+        Err(Error::Parity)
+    }
+}
+
+/// Computes the checksum of a response
+pub fn checksum(model: ChecksumModel, pid: u8, data: &[u8]) -> u8 {
+    let id = pid & 0x3f;
+
+    // sum with carry wrap around ("sum of 255")
+    let mut sum: u16 = if model == ChecksumModel::Enhanced && id != 0x3c && id != 0x3d {
+        pid as u16
+    } else {
+        0
+    };
+
+    for byte in data {
+        sum += *byte as u16;
+        if sum > 0xff {
+            sum -= 0xff;
+        }
+    }
+
+    !(sum as u8)
+}
+
+/// Something the `FrameReader` recognized on the bus
+#[derive(Debug, Eq, PartialEq)]
+pub enum Received<'a> {
+    /// A header with this (unprotected) identifier was received. Call
+    /// `FrameReader::expect` if this node subscribes to the frame, or send
+    /// the response with `Lin::write_response` if it publishes it
+    Header(u8),
+    /// A complete response with a valid checksum
+    Response(u8, &'a [u8]),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Idle,
+    Sync,
+    Pid,
+    Header,
+    Data,
+}
+
+/// Tracks the frames on the bus
+pub struct FrameReader {
+    model: ChecksumModel,
+    state: State,
+    pid: u8,
+    data: [u8; 8],
+    len: usize,
+    n: usize,
+}
+
+impl FrameReader {
+    /// Creates a reader that checks responses with the checksum `model`
+    pub const fn new(model: ChecksumModel) -> Self {
+        FrameReader {
+            model: model,
+            state: State::Idle,
+            pid: 0,
+            data: [0; 8],
+            len: 0,
+            n: 0,
+        }
+    }
+
+    /// A break was detected: a new frame starts
+    pub fn on_break(&mut self) {
+        self.state = State::Sync;
+    }
+
+    /// Subscribes to the response of the header that was just received
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is not 1 - 8 or if no header was just received
+    pub fn expect(&mut self, len: usize) {
+        assert!(len >= 1 && len <= 8);
+        assert_eq!(self.state, State::Header);
+
+        self.len = len;
+        self.n = 0;
+        self.state = State::Data;
+    }
+
+    /// Feeds a byte received after the break
+    ///
+    /// Returns `WouldBlock` when there's nothing to report yet. Bytes that
+    /// are not part of a tracked frame (e.g. responses this node doesn't
+    /// subscribe to) are ignored.
+    pub fn feed(&mut self, byte: u8) -> nb::Result<Received, Error> {
+        match self.state {
+            State::Idle | State::Header => {
+                self.state = State::Idle;
+                Err(nb::Error::WouldBlock)
+            }
+            State::Sync => {
+                if byte == 0x00 {
+                    // the break itself is received as a 0x00 with a framing
+                    // error
+                    Err(nb::Error::WouldBlock)
+                } else if byte == 0x55 {
+                    self.state = State::Pid;
+                    Err(nb::Error::WouldBlock)
+                } else {
+                    self.state = State::Idle;
+                    Err(nb::Error::Other(Error::Sync))
+                }
+            }
+            State::Pid => match id_from_protected(byte) {
+                Ok(id) => {
+                    self.pid = byte;
+                    self.state = State::Header;
+                    Ok(Received::Header(id))
+                }
+                Err(e) => {
+                    self.state = State::Idle;
+                    Err(nb::Error::Other(e))
+                }
+            },
+            State::Data => {
+                if self.n < self.len {
+                    self.data[self.n] = byte;
+                    self.n += 1;
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                self.state = State::Idle;
+
+                let data = &self.data[..self.len];
+                if checksum(self.model, self.pid, data) == byte {
+                    Ok(Received::Response(self.pid & 0x3f, data))
+                } else {
+                    Err(nb::Error::Other(Error::Checksum))
+                }
+            }
+        }
+    }
+}
+
+/// Direction of a frame, from the master's point of view
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The master sends the response
+    Publish,
+    /// A slave sends the response
+    Subscribe,
+}
+
+/// An entry of a schedule table
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// Frame identifier, 0 - 63
+    pub id: u8,
+    /// Number of data bytes, 1 - 8
+    pub len: u8,
+    /// Who sends the response
+    pub direction: Direction,
+    /// Length of the frame slot, in scheduler ticks
+    pub slots: u8,
+}
+
+/// Schedule table master
+///
+/// Call `tick` from a periodic timer interrupt (the LIN time base, usually
+/// 5 or 10 ms); it returns the entry whose header has to be sent now.
+pub struct Scheduler<'t> {
+    table: &'t [Entry],
+    index: usize,
+    // ticks left in the current slot, 0 = start the next entry
+    remaining: u8,
+}
+
+impl<'t> Scheduler<'t> {
+    /// Creates a scheduler that cycles through `table`
+    pub const fn new(table: &'t [Entry]) -> Self {
+        Scheduler {
+            table: table,
+            index: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Switches to another schedule table, starting at its first entry
+    pub fn set_table(&mut self, table: &'t [Entry]) {
+        self.table = table;
+        self.index = 0;
+        self.remaining = 0;
+    }
+
+    /// Advances the schedule by one time base tick
+    pub fn tick(&mut self) -> Option<&'t Entry> {
+        if self.table.is_empty() {
+            return None;
+        }
+
+        if self.remaining > 1 {
+            self.remaining -= 1;
+            return None;
+        }
+
+        let table = self.table;
+        let entry = &table[self.index];
+        self.index = (self.index + 1) % table.len();
+        self.remaining = entry.slots;
+
+        Some(entry)
+    }
+}
+
+/// LIN interface on top of a `Serial` interface
+pub struct Lin<'a, U>(pub Serial<'a, U>)
+where
+    U: 'a + Any + Usart;
+
+impl<'a, U> Lin<'a, U>
+where
+    U: Any + Usart,
+{
+    /// Switches an initialized serial interface to LIN mode
+    pub fn init(&self, break_length: BreakLength) {
+        let usart = (self.0).0;
+
+        // RM0368 19.3.8 LIN mode: STOP = 0, CLKEN = 0, SCEN = HDSEL = IREN = 0
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr2.modify(|_, w| unsafe {
+            w.stop()
+                .bits(0b00)
+                .clken()
+                .clear_bit()
+                .lbdl()
+                .bit(break_length == BreakLength::_11)
+                .linen()
+                .set_bit()
+        });
+        usart
+            .cr3
+            .modify(|_, w| w.scen().clear_bit().hdsel().clear_bit().iren().clear_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    /// Clears the break detected flag, returns `true` if it was set
+    pub fn clear_break(&self) -> bool {
+        let usart = (self.0).0;
+
+        if usart.sr.read().lbd().bit_is_set() {
+            // NOTE(write) the SR flags are cleared by writing zero; write
+            // ones to the others so that we don't clear RXNE by accident
+            usart
+                .sr
+                .write(|w| unsafe { w.bits(0xffff_ffff).lbd().clear_bit() });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Requests a break to be sent after the current byte
+    pub fn send_break(&self) {
+        (self.0).0.cr1.modify(|_, w| w.sbk().set_bit());
+    }
+
+    /// Sends a frame header: break, sync field and protected `id`
+    ///
+    /// This blocks until the header bytes have been queued
+    pub fn write_header(&self, id: u8) -> Result<(), serial::Error> {
+        self.send_break();
+        // SBK is cleared by hardware once the break has been sent
+        while (self.0).0.cr1.read().sbk().bit_is_set() {}
+
+        self.write_byte(0x55)?;
+        self.write_byte(protected_id(id))
+    }
+
+    /// Sends a response: `data` followed by its checksum
+    ///
+    /// This blocks until every byte has been queued
+    pub fn write_response(
+        &self,
+        id: u8,
+        data: &[u8],
+        model: ChecksumModel,
+    ) -> Result<(), serial::Error> {
+        assert!(data.len() >= 1 && data.len() <= 8);
+
+        for byte in data {
+            self.write_byte(*byte)?;
+        }
+        self.write_byte(checksum(model, protected_id(id), data))
+    }
+
+    /// Reads a byte, ignoring the framing error that comes with a break
+    ///
+    /// Other receive errors are counted in `Serial::errors`, like in
+    /// `Serial::read`.
+    pub fn read(&self) -> nb::Result<u8, serial::Error> {
+        let usart = (self.0).0;
+        let sr = usart.sr.read();
+
+        // NOTE the break is received as a 0x00 with a framing error (RM0368
+        // 19.3.8). That FE is expected, so it's neither counted nor
+        // reported: the 0x00 is returned and `FrameReader::feed` skips it
+        if let Some(error) = self.0.recover(&sr, false) {
+            Err(nb::Error::Other(error))
+        } else if sr.rxne().bit_is_set() {
+            // NOTE(read_volatile) see NOTE in `Serial::read`; this also
+            // clears FE
+            Ok(unsafe { ptr::read_volatile(&usart.dr as *const _ as *const u8) })
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    fn write_byte(&self, byte: u8) -> Result<(), serial::Error> {
+        loop {
+            match hal::serial::Write::write(&self.0, byte) {
+                Ok(()) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(e),
+                Err(nb::Error::WouldBlock) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn protected_ids() {
+        let table = [
+            (0x00, 0x80),
+            (0x01, 0xc1),
+            (0x02, 0x42),
+            (0x03, 0x03),
+            (0x04, 0xc4),
+            (0x10, 0x50),
+            (0x20, 0x20),
+            (0x3c, 0x3c),
+            (0x3d, 0x7d),
+            (0x3e, 0xfe),
+            (0x3f, 0xbf),
+        ];
+
+        for &(id, pid) in table.iter() {
+            assert_eq!(protected_id(id), pid);
+            assert_eq!(id_from_protected(pid), Ok(id));
+        }
+
+        // the upper bits of the identifier are ignored
+        assert_eq!(protected_id(0xc1), 0xc1);
+    }
+
+    #[test]
+    fn parity_errors() {
+        for id in 0..64 {
+            let pid = protected_id(id);
+            assert_eq!(id_from_protected(pid), Ok(id));
+
+            // any single bit error is detected
+            for bit in 0..8 {
+                assert_eq!(id_from_protected(pid ^ 1 << bit), Err(Error::Parity));
+            }
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        // 0x4a + 0x55 + 0x93 + 0xe5 = 0x019 after the carries, inverted
+        let data = [0x55, 0x93, 0xe5];
+        assert_eq!(checksum(ChecksumModel::Enhanced, 0x4a, &data), 0xe6);
+        assert_eq!(checksum(ChecksumModel::Classic, 0x4a, &data), 0x31);
+
+        // diagnostic frames always use the classic checksum
+        let data = [0x01, 0x06, 0xb2, 0x00, 0xff, 0x7f, 0xff, 0xff];
+        assert_eq!(
+            checksum(ChecksumModel::Enhanced, 0x3c, &data),
+            checksum(ChecksumModel::Classic, 0x3c, &data)
+        );
+        assert_eq!(
+            checksum(ChecksumModel::Enhanced, 0x7d, &data),
+            checksum(ChecksumModel::Classic, 0x7d, &data)
+        );
+
+        // the carry wraps around
+        assert_eq!(checksum(ChecksumModel::Classic, 0, &[0xff, 0xff]), 0x00);
+        assert_eq!(checksum(ChecksumModel::Classic, 0, &[0xff, 0x01]), 0xfe);
+        assert_eq!(checksum(ChecksumModel::Classic, 0, &[]), 0xff);
+    }
+
+    // Feeds `bytes` and returns everything but `WouldBlock`; subscribes to
+    // every header with `len` data bytes
+    fn read(
+        reader: &mut FrameReader,
+        bytes: &[u8],
+        len: usize,
+    ) -> Vec<Result<(u8, Vec<u8>), Error>> {
+        let mut out = Vec::new();
+        for byte in bytes {
+            match reader.feed(*byte) {
+                Ok(Received::Header(id)) => out.push(Ok((id, Vec::new()))),
+                Ok(Received::Response(id, data)) => out.push(Ok((id, data.to_vec()))),
+                Err(nb::Error::Other(e)) => out.push(Err(e)),
+                Err(nb::Error::WouldBlock) => {}
+            }
+            if reader.state == State::Header {
+                reader.expect(len);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn frame_reader() {
+        let mut reader = FrameReader::new(ChecksumModel::Enhanced);
+
+        // identifier 0x0a, protected 0xca
+        reader.on_break();
+        assert_eq!(
+            read(&mut reader, &[0x00, 0x55, 0xca, 0x55, 0x93, 0xe5, 0x66], 3),
+            [
+                Ok((0x0a, Vec::new())),
+                Ok((0x0a, [0x55, 0x93, 0xe5].to_vec()))
+            ]
+        );
+
+        // bad checksum, then bytes without a break are ignored
+        reader.on_break();
+        assert_eq!(
+            read(
+                &mut reader,
+                &[0x55, 0xca, 0x55, 0x93, 0xe5, 0xe6, 0x55, 0xca],
+                3
+            ),
+            [Ok((0x0a, Vec::new())), Err(Error::Checksum)]
+        );
+
+        // bad sync and bad parity
+        reader.on_break();
+        assert_eq!(
+            read(&mut reader, &[0x54, 0x55, 0xca], 3),
+            [Err(Error::Sync)]
+        );
+        reader.on_break();
+        assert_eq!(
+            read(&mut reader, &[0x55, 0x4a, 0x00], 3),
+            [Err(Error::Parity)]
+        );
+
+        // a header this node doesn't subscribe to: its response is ignored
+        reader.on_break();
+        assert_eq!(reader.feed(0x55), Err(nb::Error::WouldBlock));
+        assert_eq!(reader.feed(0xca), Ok(Received::Header(0x0a)));
+        assert_eq!(read(&mut reader, &[0x55, 0x93, 0xe5, 0x66], 3), []);
+    }
+
+    #[test]
+    fn scheduler() {
+        let entry = |id, slots| Entry {
+            id: id,
+            len: 2,
+            direction: Direction::Publish,
+            slots: slots,
+        };
+        let a = [entry(1, 2), entry(2, 1), entry(3, 3)];
+        let b = [entry(4, 1)];
+
+        let mut scheduler = Scheduler::new(&a);
+        let ids: Vec<Option<u8>> = (0..12).map(|_| scheduler.tick().map(|e| e.id)).collect();
+        assert_eq!(
+            ids,
+            [
+                Some(1),
+                None,
+                Some(2),
+                Some(3),
+                None,
+                None,
+                Some(1),
+                None,
+                Some(2),
+                Some(3),
+                None,
+                None
+            ]
+        );
+
+        scheduler.tick();
+        scheduler.set_table(&b);
+        assert_eq!(scheduler.tick().map(|e| e.id), Some(4));
+        assert_eq!(scheduler.tick().map(|e| e.id), Some(4));
+
+        scheduler.set_table(&[]);
+        assert!(scheduler.tick().is_none());
+    }
+}
@@ -0,0 +1,554 @@
+//! Backend for the `log` facade
+//!
+//! A `Logger` filters records by level (globally and per module), prefixes
+//! them with the cycle counter and hands each formatted line to a `Sink`:
+//!
+//! ``` text
+//! [    123456 INFO  app::sensors] temperature = 21.5
+//! ```
+//!
+//! Available sinks:
+//!
+//! - `DmaSerial`: USART2 through DMA1 stream 6
+//! - `Itm`: an ITM stimulus port
+//! - `Semihosting`: the host's stdout, skipped when no debugger is attached
+//! - `Ram`: an in-memory ring buffer that keeps the latest lines, e.g. to be
+//!   dumped after a panic
+//!
+//! A tuple `(A, B)` of sinks writes every line to both.
+//!
+//! # Usage
+//!
+//! ``` ignore
+//! static LOGGER: Logger<(Itm, Ram<[u8; 1024]>)> = Logger::new(
+//!     (Itm::new(0), Ram::new([0; 1024])),
+//!     LevelFilter::Info,
+//!     &[("app::sensors", LevelFilter::Trace)],
+//! );
+//!
+//! logger::init(&LOGGER).unwrap();
+//! info!("started");
+//! ```
+//!
+//! Records are formatted and written with interrupts disabled, so logging
+//! works from any context and lines from different priorities don't
+//! interleave, at the cost of a critical section as long as the formatting.
+
+use core::cell::{Cell, UnsafeCell};
+use core::cmp;
+use core::fmt::Write;
+use core::marker::Unsize;
+
+use cast::u16;
+use cortex_m::interrupt;
+use cortex_m::itm;
+use cortex_m_semihosting::hio;
+use log::{self, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use stm32f40x::{DCB, DMA1, DWT, ITM, USART2};
+
+use dma::{Dma1Stream6, Stream};
+use serial::{tx_dma_config, U8Writer};
+
+/// Maximum length of a log line; longer lines are truncated
+pub const LINE_LEN: usize = 128;
+
+/// Destination of the formatted log lines
+pub trait Sink {
+    /// Writes a complete line
+    ///
+    /// Called with interrupts disabled
+    fn write(&self, line: &[u8]);
+
+    /// Pushes out buffered output, if any
+    fn flush(&self) {}
+}
+
+impl<A, B> Sink for (A, B)
+where
+    A: Sink,
+    B: Sink,
+{
+    fn write(&self, line: &[u8]) {
+        self.0.write(line);
+        self.1.write(line);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+        self.1.flush();
+    }
+}
+
+/// `log` backend
+pub struct Logger<S>
+where
+    S: Sink,
+{
+    sink: S,
+    level: LevelFilter,
+    filters: &'static [(&'static str, LevelFilter)],
+}
+
+// NOTE(unsafe) the sink is only accessed with interrupts disabled
+unsafe impl<S> Sync for Logger<S>
+where
+    S: Sink,
+{
+}
+
+unsafe impl<S> Send for Logger<S>
+where
+    S: Sink,
+{
+}
+
+impl<S> Logger<S>
+where
+    S: Sink,
+{
+    /// Creates a logger that writes to `sink`
+    ///
+    /// Records are accepted up to `level`, except for the modules listed in
+    /// `filters`: the longest module path that is a prefix of the record's
+    /// target sets the level instead.
+    pub const fn new(
+        sink: S,
+        level: LevelFilter,
+        filters: &'static [(&'static str, LevelFilter)],
+    ) -> Self {
+        Logger {
+            sink: sink,
+            level: level,
+            filters: filters,
+        }
+    }
+
+    /// The sink of this logger
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// The level filter that applies to `target`
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let mut level = self.level;
+        let mut longest = 0;
+
+        for &(module, filter) in self.filters {
+            if module.len() >= longest && is_module_prefix(module, target) {
+                level = filter;
+                longest = module.len();
+            }
+        }
+
+        level
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.filters
+            .iter()
+            .fold(self.level, |max, &(_, filter)| cmp::max(max, filter))
+    }
+}
+
+// `module` is `target` or one of its parents
+fn is_module_prefix(module: &str, target: &str) -> bool {
+    target.starts_with(module)
+        && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+}
+
+impl<S> Log for Logger<S>
+where
+    S: Sink,
+{
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        interrupt::free(|_| {
+            let mut line = [0; LINE_LEN];
+
+            let len = {
+                // leave room for the line terminator
+                let mut writer = U8Writer::new(&mut line[..LINE_LEN - 2]);
+                // NOTE(ok) can't fail, the line is truncated
+                write!(
+                    writer,
+                    "[{:>10} {:<5} {}] {}",
+                    cycles(),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ).ok();
+                writer.len()
+            };
+
+            line[len] = b'\r';
+            line[len + 1] = b'\n';
+
+            self.sink.write(&line[..len + 2]);
+        });
+    }
+
+    fn flush(&self) {
+        interrupt::free(|_| self.sink.flush());
+    }
+}
+
+/// Installs `logger` as the `log` backend and starts the cycle counter
+///
+/// Can only be called once
+pub fn init<S>(logger: &'static Logger<S>) -> Result<(), SetLoggerError>
+where
+    S: Sink,
+{
+    // NOTE(unsafe) read-modify-write of debug registers nobody else writes
+    unsafe {
+        // DEMCR.TRCENA: enable the DWT and ITM blocks
+        (*DCB.get()).demcr.modify(|r| r | (1 << 24));
+        // DWT_CTRL.CYCCNTENA
+        (*DWT.get()).ctrl.modify(|r| r | 1);
+    }
+
+    log::set_logger(logger)?;
+    log::set_max_level(logger.max_level());
+
+    Ok(())
+}
+
+fn cycles() -> u32 {
+    // NOTE(unsafe) atomic read with no side effects
+    unsafe { (*DWT.get()).cyccnt.read() }
+}
+
+/// Writes to an ITM stimulus port
+///
+/// Nothing is written if the ITM or the port is disabled, so this is safe to
+/// use without a trace probe.
+pub struct Itm {
+    port: u8,
+}
+
+impl Itm {
+    /// Writes to stimulus `port` (0 - 31)
+    pub const fn new(port: u8) -> Self {
+        Itm { port: port }
+    }
+}
+
+impl Sink for Itm {
+    fn write(&self, line: &[u8]) {
+        let port = self.port as usize;
+
+        // NOTE(unsafe) the stimulus port is only written with interrupts
+        // disabled
+        unsafe {
+            let itm = &*ITM.get();
+
+            // TCR.ITMENA and the port's TER bit
+            if itm.tcr.read() & 1 == 0 || itm.ter[port / 32].read() & (1 << (port % 32)) == 0 {
+                return;
+            }
+
+            itm::write_all(&itm.stim[port], line);
+        }
+    }
+}
+
+/// Writes to the host's stdout through semihosting
+///
+/// Semihosting calls stop the processor when no debugger is attached, so
+/// lines are dropped in that case.
+pub struct Semihosting;
+
+impl Sink for Semihosting {
+    fn write(&self, line: &[u8]) {
+        // NOTE(unsafe) atomic read with no side effects
+        // DHCSR.C_DEBUGEN
+        if unsafe { (*DCB.get()).dhcsr.read() } & 1 == 0 {
+            return;
+        }
+
+        if let Ok(mut stdout) = hio::hstdout() {
+            stdout.write_all(line).ok();
+        }
+    }
+}
+
+/// Keeps the latest log output in a RAM ring buffer
+///
+/// When the buffer is full the oldest bytes are overwritten.
+pub struct Ram<B>
+where
+    B: Unsize<[u8]>,
+{
+    buffer: UnsafeCell<B>,
+    // next byte to write
+    position: Cell<usize>,
+    wrapped: Cell<bool>,
+}
+
+impl<B> Ram<B>
+where
+    B: Unsize<[u8]>,
+{
+    /// Creates a ring buffer that uses `buffer` as storage
+    pub const fn new(buffer: B) -> Self {
+        Ram {
+            buffer: UnsafeCell::new(buffer),
+            position: Cell::new(0),
+            wrapped: Cell::new(false),
+        }
+    }
+
+    /// Passes the stored output, oldest bytes first, to `f` as two slices
+    pub fn dump<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&[u8], &[u8]) -> R,
+    {
+        interrupt::free(|_| {
+            let buffer: &[u8] = unsafe { &*self.buffer.get() };
+            let position = self.position.get();
+
+            if self.wrapped.get() {
+                f(&buffer[position..], &buffer[..position])
+            } else {
+                f(&buffer[..position], &[])
+            }
+        })
+    }
+
+    /// Discards the stored output
+    pub fn clear(&self) {
+        interrupt::free(|_| {
+            self.position.set(0);
+            self.wrapped.set(false);
+        })
+    }
+}
+
+impl<B> Sink for Ram<B>
+where
+    B: Unsize<[u8]>,
+{
+    fn write(&self, line: &[u8]) {
+        let buffer: &mut [u8] = unsafe { &mut *self.buffer.get() };
+
+        if buffer.is_empty() {
+            return;
+        }
+
+        let mut position = self.position.get();
+        for byte in line {
+            buffer[position] = *byte;
+            position += 1;
+            if position == buffer.len() {
+                position = 0;
+                self.wrapped.set(true);
+            }
+        }
+        self.position.set(position);
+    }
+}
+
+/// Sends the log through USART2 using DMA1 stream 6
+///
+/// Lines are collected in one half of the buffer `B` while the DMA sends the
+/// other half. A line that doesn't fit in the collecting half is dropped and
+/// counted. Collected lines are sent on the next `write` or `flush` after
+/// the current transfer completes; call `log::logger().flush()` from the
+/// idle loop or from the DMA1 stream 6 interrupt to keep the output going.
+///
+/// The serial port must have been initialized with `Serial::init` and DMA
+/// enabled. Nothing is sent until `init` hands stream 6 over to the sink,
+/// e.g. `LOGGER.sink().init(dma1, streams.s6)`.
+pub struct DmaSerial<B>
+where
+    B: Unsize<[u8]>,
+{
+    buffers: UnsafeCell<[B; 2]>,
+    stream: UnsafeCell<Option<Dma1Stream6>>,
+    // half being collected
+    active: Cell<usize>,
+    // bytes collected in the active half
+    len: Cell<usize>,
+    dropped: Cell<u32>,
+}
+
+impl<B> DmaSerial<B>
+where
+    B: Unsize<[u8]>,
+{
+    /// Creates a sink that uses `buffers` as storage
+    pub const fn new(buffers: [B; 2]) -> Self {
+        DmaSerial {
+            buffers: UnsafeCell::new(buffers),
+            stream: UnsafeCell::new(None),
+            active: Cell::new(0),
+            len: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Takes DMA1 stream 6 over and sends the lines collected so far
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sink already has a stream or if the stream is in use
+    pub fn init(&self, dma1: &DMA1, mut stream: Dma1Stream6) {
+        assert!(!Dma1Stream6::is_enabled(dma1), "DMA stream in use");
+        stream.configure(dma1, &tx_dma_config());
+
+        interrupt::free(|_| {
+            // NOTE(unsafe) the stream is only accessed with interrupts disabled
+            let slot = unsafe { &mut *self.stream.get() };
+            assert!(slot.is_none(), "DMA serial sink already initialized");
+            *slot = Some(stream);
+        });
+
+        interrupt::free(|_| self.start());
+    }
+
+    /// Number of lines dropped because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        interrupt::free(|_| self.dropped.get())
+    }
+
+    // Starts sending the collected lines if the DMA is idle
+    fn start(&self) {
+        // NOTE(unsafe) the stream is only accessed with interrupts disabled
+        let stream = match unsafe { &mut *self.stream.get() } {
+            &mut Some(ref mut stream) => stream,
+            &mut None => return,
+        };
+
+        // NOTE(unsafe) stream 6 is owned by this sink
+        let dma1 = unsafe { &*DMA1.get() };
+
+        if Dma1Stream6::is_enabled(dma1) {
+            return;
+        }
+
+        // the previous transfer is over; its transfer complete (or error)
+        // flag would keep the stream interrupt pending when there's nothing
+        // left to send
+        Dma1Stream6::clear_all(dma1);
+
+        let len = self.len.get();
+        if len == 0 {
+            return;
+        }
+
+        let active = self.active.get();
+        let buffer: &[u8] = unsafe { &(*self.buffers.get())[active] };
+
+        // NOTE(unsafe) only the address of the data register is taken
+        let dr = unsafe { &(*USART2.get()).dr as *const _ as u32 };
+        stream.start(dma1, dr, buffer.as_ptr() as u32, u16(len).unwrap());
+
+        self.active.set(1 - self.active.get());
+        self.len.set(0);
+    }
+}
+
+impl<B> Sink for DmaSerial<B>
+where
+    B: Unsize<[u8]>,
+{
+    fn write(&self, line: &[u8]) {
+        // push out the previous lines first to make room
+        self.start();
+
+        let active = self.active.get();
+        let buffer: &mut [u8] = unsafe { &mut (*self.buffers.get())[active] };
+        let len = self.len.get();
+
+        if len + line.len() > buffer.len() {
+            self.dropped.set(self.dropped.get().wrapping_add(1));
+            return;
+        }
+
+        buffer[len..len + line.len()].copy_from_slice(line);
+        self.len.set(len + line.len());
+
+        self.start();
+    }
+
+    fn flush(&self) {
+        self.start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Discard;
+
+    impl Sink for Discard {
+        fn write(&self, _: &[u8]) {}
+    }
+
+    static FILTERS: &[(&str, LevelFilter)] = &[
+        ("app", LevelFilter::Warn),
+        ("app::sensors", LevelFilter::Trace),
+        ("app::sensors::imu", LevelFilter::Off),
+        ("f4", LevelFilter::Error),
+    ];
+
+    #[test]
+    fn module_prefix() {
+        assert!(is_module_prefix("app", "app"));
+        assert!(is_module_prefix("app", "app::sensors"));
+        assert!(is_module_prefix("app::sensors", "app::sensors::imu"));
+
+        // not a whole path segment
+        assert!(!is_module_prefix("app", "apple"));
+        assert!(!is_module_prefix("app::sensors", "app::sensorsx"));
+        assert!(!is_module_prefix("app::sensors", "app::sensors:"));
+        // a child is not a prefix of its parent
+        assert!(!is_module_prefix("app::sensors", "app"));
+        assert!(!is_module_prefix("app", "f4::app"));
+    }
+
+    #[test]
+    fn level_for() {
+        let logger = Logger::new(Discard, LevelFilter::Info, FILTERS);
+
+        assert_eq!(logger.level_for("main"), LevelFilter::Info);
+        assert_eq!(logger.level_for("apple"), LevelFilter::Info);
+        assert_eq!(logger.level_for("app"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("app::net"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("app::sensors"), LevelFilter::Trace);
+        assert_eq!(logger.level_for("app::sensors::temp"), LevelFilter::Trace);
+        // the longest prefix wins, even when it's more restrictive
+        assert_eq!(logger.level_for("app::sensors::imu"), LevelFilter::Off);
+        assert_eq!(logger.level_for("app::sensors::imu::gyro"), LevelFilter::Off);
+        assert_eq!(logger.level_for("f4::serial"), LevelFilter::Error);
+
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn filter_order() {
+        // a parent listed after its child doesn't override it
+        static REVERSED: &[(&str, LevelFilter)] = &[
+            ("app::sensors", LevelFilter::Trace),
+            ("app", LevelFilter::Warn),
+        ];
+        let logger = Logger::new(Discard, LevelFilter::Off, REVERSED);
+
+        assert_eq!(logger.level_for("app::sensors::imu"), LevelFilter::Trace);
+        assert_eq!(logger.level_for("app::net"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("main"), LevelFilter::Off);
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+
+        let logger = Logger::new(Discard, LevelFilter::Debug, &[]);
+        assert_eq!(logger.level_for("app"), LevelFilter::Debug);
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+    }
+}
@@ -0,0 +1,679 @@
+//! Modbus RTU slave
+//!
+//! The application exposes its data through the `RegisterMap` trait and a
+//! `Slave` takes care of the framing:
+//!
+//! - every received byte is appended to the current request and restarts a
+//!   `Timer` set to the 3.5 character inter-frame delay (see
+//!   `inter_frame_delay`)
+//! - when the timer expires the request is complete: its CRC-16/MODBUS is
+//!   checked, the function is executed against the `RegisterMap` and the
+//!   response (or exception response) is written into a buffer for the
+//!   application to send, for example with `Serial::write_all`.
+//!
+//! Supported functions: 1 (read coils), 2 (read discrete inputs), 3 (read
+//! holding registers), 4 (read input registers), 5 (write single coil), 6
+//! (write single register), 15 (write multiple coils) and 16 (write multiple
+//! registers). Requests to the broadcast address 0 are executed if they're
+//! writes, and never answered.
+//!
+//! `process` works on a complete frame and doesn't touch any peripheral.
+
+use core::any::Any;
+use core::cmp;
+
+use hal;
+
+use crc;
+use serial::{Serial, Usart};
+use time::Microseconds;
+
+/// Maximum size of a Modbus RTU frame (address + PDU + CRC)
+pub const MAX_ADU: usize = 256;
+
+/// Broadcast address
+pub const BROADCAST: u8 = 0;
+
+/// Exception code returned to the master
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exception {
+    /// The function code is not supported
+    IllegalFunction = 1,
+    /// The address (or address range) is not mapped
+    IllegalDataAddress = 2,
+    /// A value in the request is not allowed
+    IllegalDataValue = 3,
+    /// An unrecoverable error occurred while performing the action
+    SlaveDeviceFailure = 4,
+}
+
+/// The data model of a Modbus slave
+///
+/// Every method has a default implementation that reports the address as
+/// not mapped, so only the tables that are used need to be implemented.
+pub trait RegisterMap {
+    /// Reads coil `address` (functions 1)
+    fn read_coil(&mut self, address: u16) -> Result<bool, Exception> {
+        let _ = address;
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Writes coil `address` (functions 5 and 15)
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+        let _ = (address, value);
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Reads discrete input `address` (function 2)
+    fn read_discrete_input(&mut self, address: u16) -> Result<bool, Exception> {
+        let _ = address;
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Reads holding register `address` (function 3)
+    fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception> {
+        let _ = address;
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Writes holding register `address` (functions 6 and 16)
+    fn write_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        let _ = (address, value);
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Reads input register `address` (function 4)
+    fn read_input_register(&mut self, address: u16) -> Result<u16, Exception> {
+        let _ = address;
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// Silent interval that delimits frames at `baud_rate`
+///
+/// 3.5 character times of 11 bits; fixed to 1750 us above 19200 baud as
+/// recommended by the Modbus over serial line specification
+pub fn inter_frame_delay(baud_rate: u32) -> Microseconds {
+    if baud_rate > 19_200 {
+        Microseconds(1_750)
+    } else {
+        // 3.5 * 11 bits = 38.5 bits, rounded up
+        Microseconds((38_500_000 + baud_rate - 1) / baud_rate)
+    }
+}
+
+fn be16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+/// Executes the request in `frame` and writes the response into `response`
+///
+/// `frame` is a complete RTU frame, including the CRC. Returns the length of
+/// the response, or `None` if the frame must be ignored: bad CRC, addressed
+/// to another slave, broadcast, or too short. Broadcast reads (functions 1 -
+/// 4) are not executed.
+///
+/// # Panics
+///
+/// Panics if `response` is smaller than `MAX_ADU`
+pub fn process<M>(address: u8, frame: &[u8], map: &mut M, response: &mut [u8]) -> Option<usize>
+where
+    M: RegisterMap,
+{
+    assert!(response.len() >= MAX_ADU);
+
+    // address + function + CRC
+    if frame.len() < 4 || frame.len() > MAX_ADU {
+        return None;
+    }
+
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc::crc16_modbus(body) != (crc[0] as u16 | (crc[1] as u16) << 8) {
+        return None;
+    }
+
+    let slave = body[0];
+    if slave != address && slave != BROADCAST {
+        return None;
+    }
+
+    let function = body[1];
+    if slave == BROADCAST && function >= 1 && function <= 4 {
+        // reads have no effect without a response
+        return None;
+    }
+
+    let data = &body[2..];
+
+    response[0] = address;
+    response[1] = function;
+
+    let len = match execute(function, data, map, &mut response[2..]) {
+        Ok(n) => 2 + n,
+        Err(exception) => {
+            response[1] = function | 0x80;
+            response[2] = exception as u8;
+            3
+        }
+    };
+
+    if slave == BROADCAST {
+        return None;
+    }
+
+    let crc = crc::crc16_modbus(&response[..len]);
+    response[len] = crc as u8;
+    response[len + 1] = (crc >> 8) as u8;
+
+    Some(len + 2)
+}
+
+// Returns the size of the response data (after the function code)
+fn execute<M>(function: u8, data: &[u8], map: &mut M, out: &mut [u8]) -> Result<usize, Exception>
+where
+    M: RegisterMap,
+{
+    match function {
+        // Read coils / discrete inputs
+        1 | 2 => {
+            let (start, count) = range(data, 2000)?;
+
+            for byte in out[1..1 + (count + 7) / 8].iter_mut() {
+                *byte = 0;
+            }
+
+            for i in 0..count {
+                let address = start + i as u16;
+                let bit = if function == 1 {
+                    map.read_coil(address)?
+                } else {
+                    map.read_discrete_input(address)?
+                };
+                if bit {
+                    out[1 + i / 8] |= 1 << (i % 8);
+                }
+            }
+
+            out[0] = ((count + 7) / 8) as u8;
+            Ok(1 + out[0] as usize)
+        }
+        // Read holding / input registers
+        3 | 4 => {
+            let (start, count) = range(data, 125)?;
+
+            for i in 0..count {
+                let address = start + i as u16;
+                let value = if function == 3 {
+                    map.read_holding_register(address)?
+                } else {
+                    map.read_input_register(address)?
+                };
+                out[1 + 2 * i] = (value >> 8) as u8;
+                out[2 + 2 * i] = value as u8;
+            }
+
+            out[0] = (2 * count) as u8;
+            Ok(1 + 2 * count)
+        }
+        // Write single coil
+        5 => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let value = match be16(&data[2..]) {
+                0xff00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            map.write_coil(be16(data), value)?;
+
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        }
+        // Write single register
+        6 => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue);
+            }
+            map.write_holding_register(be16(data), be16(&data[2..]))?;
+
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        }
+        // Write multiple coils
+        15 => {
+            let (start, count) = range(data, 1968)?;
+            let bytes = (count + 7) / 8;
+            if data.len() != 5 + bytes || data[4] as usize != bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            for i in 0..count {
+                let bit = data[5 + i / 8] & (1 << (i % 8)) != 0;
+                map.write_coil(start + i as u16, bit)?;
+            }
+
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        // Write multiple registers
+        16 => {
+            let (start, count) = range(data, 123)?;
+            if data.len() != 5 + 2 * count || data[4] as usize != 2 * count {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            for i in 0..count {
+                let value = be16(&data[5 + 2 * i..]);
+                map.write_holding_register(start + i as u16, value)?;
+            }
+
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+// Parses the starting address and quantity of a request
+fn range(data: &[u8], max: usize) -> Result<(u16, usize), Exception> {
+    if data.len() < 4 {
+        return Err(Exception::IllegalDataValue);
+    }
+
+    let start = be16(data);
+    let count = be16(&data[2..]) as usize;
+
+    if count == 0 || count > max {
+        Err(Exception::IllegalDataValue)
+    } else if start as usize + count > 0x1_0000 {
+        Err(Exception::IllegalDataAddress)
+    } else {
+        Ok((start, count))
+    }
+}
+
+/// Modbus RTU slave state
+pub struct Slave {
+    address: u8,
+    frame: [u8; MAX_ADU],
+    len: usize,
+    // the frame grew past `MAX_ADU`; drop it
+    overflow: bool,
+}
+
+impl Slave {
+    /// Creates a slave that answers to `address` (1 - 247)
+    pub const fn new(address: u8) -> Self {
+        Slave {
+            address: address,
+            frame: [0; MAX_ADU],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Address of this slave
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Appends a received byte to the current frame
+    pub fn feed(&mut self, byte: u8) {
+        if self.len < MAX_ADU {
+            self.frame[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    /// Ends the current frame and executes it; call this once the line has
+    /// been silent for the inter-frame delay
+    ///
+    /// Returns the length of the response written into `response`, if any
+    pub fn end_of_frame<M>(&mut self, map: &mut M, response: &mut [u8]) -> Option<usize>
+    where
+        M: RegisterMap,
+    {
+        let len = cmp::min(self.len, MAX_ADU);
+        let overflow = self.overflow;
+
+        self.len = 0;
+        self.overflow = false;
+
+        if overflow {
+            None
+        } else {
+            process(self.address, &self.frame[..len], map, response)
+        }
+    }
+
+    /// Handles the USART RXNE interrupt: stores the received byte and
+    /// restarts the inter-frame `timer`
+    ///
+    /// `timer` must have been initialized with `inter_frame_delay`
+    pub fn on_receive<U, T>(&mut self, serial: Serial<U>, timer: &T)
+    where
+        U: Any + Usart,
+        T: hal::Timer,
+    {
+        // NOTE errors (noise, framing) corrupt the frame; the CRC check will
+        // reject it
+        if let Ok(byte) = hal::serial::Read::read(&serial) {
+            self.feed(byte);
+        }
+
+        timer.pause();
+        timer.restart();
+        timer.resume();
+    }
+
+    /// Handles the timer update interrupt: the frame is complete
+    ///
+    /// Returns the length of the response written into `response`, which
+    /// the caller then transmits
+    pub fn on_timeout<T, M>(&mut self, timer: &T, map: &mut M, response: &mut [u8]) -> Option<usize>
+    where
+        T: hal::Timer,
+        M: RegisterMap,
+    {
+        timer.pause();
+        timer.wait().ok();
+
+        self.end_of_frame(map, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    struct Map {
+        coils: [bool; 16],
+        registers: [u16; 16],
+        reads: usize,
+    }
+
+    impl Map {
+        fn new() -> Self {
+            Map {
+                coils: [false; 16],
+                registers: [0; 16],
+                reads: 0,
+            }
+        }
+    }
+
+    impl RegisterMap for Map {
+        fn read_coil(&mut self, address: u16) -> Result<bool, Exception> {
+            self.reads += 1;
+            self.coils
+                .get(address as usize)
+                .cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+            *self
+                .coils
+                .get_mut(address as usize)
+                .ok_or(Exception::IllegalDataAddress)? = value;
+            Ok(())
+        }
+
+        fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception> {
+            self.reads += 1;
+            self.registers
+                .get(address as usize)
+                .cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn write_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            *self
+                .registers
+                .get_mut(address as usize)
+                .ok_or(Exception::IllegalDataAddress)? = value;
+            Ok(())
+        }
+
+        fn read_input_register(&mut self, address: u16) -> Result<u16, Exception> {
+            // the example of the Modbus application protocol specification
+            if address >= 0x6b && address < 0x6e {
+                Ok([0x022b, 0x0000, 0x0064][address as usize - 0x6b])
+            } else {
+                Err(Exception::IllegalDataAddress)
+            }
+        }
+    }
+
+    // Appends the CRC to `body`
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let crc = crc::crc16_modbus(body);
+        let mut frame = body.to_vec();
+        frame.push(crc as u8);
+        frame.push((crc >> 8) as u8);
+        frame
+    }
+
+    fn run(address: u8, frame: &[u8], map: &mut Map) -> Option<Vec<u8>> {
+        let mut response = [0; MAX_ADU];
+        process(address, frame, map, &mut response).map(|n| response[..n].to_vec())
+    }
+
+    #[test]
+    fn vectors() {
+        let mut map = Map::new();
+        map.registers[..3].copy_from_slice(&[0xae41, 0x5652, 0x4340]);
+
+        // read holding registers 0 - 2 of slave 0x11
+        assert_eq!(
+            run(
+                0x11,
+                &[0x11, 0x03, 0x00, 0x00, 0x00, 0x03, 0x07, 0x5b],
+                &mut map
+            ),
+            Some(frame(&[
+                0x11, 0x03, 0x06, 0xae, 0x41, 0x56, 0x52, 0x43, 0x40
+            ]))
+        );
+
+        // read input registers 0x6b - 0x6d of slave 0x11
+        assert_eq!(
+            run(
+                0x11,
+                &[0x11, 0x04, 0x00, 0x6b, 0x00, 0x03, 0xc3, 0x47],
+                &mut map
+            ),
+            Some(frame(&[
+                0x11, 0x04, 0x06, 0x02, 0x2b, 0x00, 0x00, 0x00, 0x64
+            ]))
+        );
+
+        // write single register 1 of slave 0x01; the response echoes the request
+        let request = [0x01, 0x06, 0x00, 0x01, 0x00, 0x03, 0x98, 0x0b];
+        assert_eq!(run(0x01, &request, &mut map), Some(request.to_vec()));
+        assert_eq!(map.registers[1], 3);
+    }
+
+    #[test]
+    fn coils() {
+        let mut map = Map::new();
+
+        // write single coil 2
+        let request = frame(&[0x01, 0x05, 0x00, 0x02, 0xff, 0x00]);
+        assert_eq!(run(0x01, &request, &mut map), Some(request));
+
+        // write coils 8 - 17 (10 coils)
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x0f, 0x00, 0x03, 0x00, 0x0a, 0x02, 0xcd, 0x01]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x0f, 0x00, 0x03, 0x00, 0x0a]))
+        );
+
+        // read coils 0 - 12
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x01, 0x00, 0x00, 0x00, 0x0d]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x01, 0x02, 0x6c, 0x0e]))
+        );
+    }
+
+    #[test]
+    fn ignored() {
+        let mut map = Map::new();
+        let request = frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]);
+
+        // another slave
+        assert_eq!(run(0x02, &request, &mut map), None);
+
+        // bad CRC
+        let mut corrupted = request.clone();
+        corrupted[3] ^= 0x01;
+        assert_eq!(run(0x01, &corrupted, &mut map), None);
+
+        // too short
+        assert_eq!(run(0x01, &request[..3], &mut map), None);
+        assert_eq!(run(0x01, &[], &mut map), None);
+        assert_eq!(run(0x01, &frame(&[0x01]), &mut map), None);
+
+        // too long
+        let mut long = [0; MAX_ADU - 1].to_vec();
+        long[0] = 0x01;
+        long[1] = 0x03;
+        assert!(run(0x01, &frame(&long[..MAX_ADU - 2]), &mut map).is_some());
+        assert_eq!(run(0x01, &frame(&long), &mut map), None);
+    }
+
+    #[test]
+    fn broadcast() {
+        let mut map = Map::new();
+
+        // writes are executed but not answered
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x00, 0x06, 0x00, 0x04, 0x12, 0x34]),
+                &mut map
+            ),
+            None
+        );
+        assert_eq!(map.registers[4], 0x1234);
+
+        // reads are not executed
+        for function in 1..5 {
+            let request = frame(&[0x00, function, 0x00, 0x00, 0x00, 0x01]);
+            assert_eq!(run(0x01, &request, &mut map), None);
+        }
+        assert_eq!(map.reads, 0);
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut map = Map::new();
+
+        // unsupported function
+        assert_eq!(
+            run(0x01, &frame(&[0x01, 0x07]), &mut map),
+            Some(frame(&[0x01, 0x87, 0x01]))
+        );
+        // not mapped
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x03, 0x00, 0x0f, 0x00, 0x02]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x83, 0x02]))
+        );
+        // past the end of the address space
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x03, 0xff, 0xff, 0x00, 0x02]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x83, 0x02]))
+        );
+        // zero and too many registers
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x00]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x83, 0x03]))
+        );
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x04, 0x00, 0x00, 0x00, 0x7e]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x84, 0x03]))
+        );
+        // invalid coil value
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x05, 0x00, 0x00, 0x12, 0x34]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x85, 0x03]))
+        );
+        // byte count doesn't match the quantity
+        assert_eq!(
+            run(
+                0x01,
+                &frame(&[0x01, 0x10, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x01]),
+                &mut map
+            ),
+            Some(frame(&[0x01, 0x90, 0x03]))
+        );
+        // truncated request
+        assert_eq!(
+            run(0x01, &frame(&[0x01, 0x03, 0x00]), &mut map),
+            Some(frame(&[0x01, 0x83, 0x03]))
+        );
+    }
+
+    #[test]
+    fn slave() {
+        let mut map = Map::new();
+        let mut slave = Slave::new(0x01);
+        let mut response = [0; MAX_ADU];
+
+        for byte in frame(&[0x01, 0x06, 0x00, 0x00, 0xbe, 0xef]) {
+            slave.feed(byte);
+        }
+        assert_eq!(slave.end_of_frame(&mut map, &mut response), Some(8));
+        assert_eq!(map.registers[0], 0xbeef);
+
+        // an overlong frame is dropped, and the next one is processed
+        for _ in 0..MAX_ADU + 1 {
+            slave.feed(0x01);
+        }
+        assert_eq!(slave.end_of_frame(&mut map, &mut response), None);
+        for byte in frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]) {
+            slave.feed(byte);
+        }
+        assert_eq!(slave.end_of_frame(&mut map, &mut response), Some(7));
+        assert_eq!(response[3..5], [0xbe, 0xef]);
+    }
+
+    #[test]
+    fn delay() {
+        assert_eq!(inter_frame_delay(9_600).0, 4_011);
+        assert_eq!(inter_frame_delay(19_200).0, 2_006);
+        assert_eq!(inter_frame_delay(115_200).0, 1_750);
+    }
+}
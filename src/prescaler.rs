@@ -0,0 +1,227 @@
+//! Prescaler and auto-reload solver for the timers
+//!
+//! A timer's update period is `(PSC + 1) * (ARR + 1)` cycles of its clock.
+//! PSC is 16 bits wide on every timer; ARR is 32 bits wide on TIM2 and TIM5
+//! and 16 bits wide on the others (RM0368 13.4.11, 13.4.12). `solve` picks
+//! the register values for a period, either as close to it as possible or
+//! with the largest ARR, i.e. the finest PWM duty cycle resolution.
+//!
+//! `solve` is pure and doesn't touch any peripheral.
+
+use core::cmp;
+
+/// Solver error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The period is shorter than 2 ticks (the counter doesn't run with
+    /// ARR = 0)
+    TooShort,
+    #[doc(hidden)] _Extensible,
+}
+
+/// Width of the counter and of the auto-reload register
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Counter {
+    /// TIM1, TIM3, TIM4 and TIM9 - TIM11
+    Bits16,
+    /// TIM2 and TIM5
+    Bits32,
+}
+
+impl Counter {
+    /// Largest ARR value
+    pub fn max(&self) -> u32 {
+        match *self {
+            Counter::Bits16 => 0xffff,
+            Counter::Bits32 => 0xffff_ffff,
+        }
+    }
+}
+
+/// What `solve` optimizes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Goal {
+    /// Smallest period error; among equally good solutions the one with the
+    /// largest ARR
+    Accuracy,
+    /// Largest ARR, with the smallest period error for that prescaler
+    Resolution,
+}
+
+/// Register values for a period
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Solution {
+    /// PSC value
+    pub psc: u16,
+    /// ARR value
+    pub arr: u32,
+    /// Achieved period, in ticks of the timer clock
+    pub period: u64,
+    /// Achieved minus requested period, in ticks of the timer clock
+    pub error: i64,
+}
+
+/// Period, in ticks of the timer clock, of the register values `psc` and
+/// `arr`
+pub fn period(psc: u16, arr: u32) -> u64 {
+    (u64::from(psc) + 1) * (u64::from(arr) + 1)
+}
+
+/// Finds PSC and ARR for a period of `ticks` cycles of the timer clock
+///
+/// With `Goal::Accuracy` every prescaler is tried until an exact solution is
+/// found, so when there's none this takes up to 65536 iterations on a 16-bit
+/// timer. Periods of up to 2^16 ticks (any period on a 32-bit timer) are
+/// always exact.
+pub fn solve(ticks: u32, counter: Counter, goal: Goal) -> Result<Solution, Error> {
+    if ticks < 2 {
+        return Err(Error::TooShort);
+    }
+
+    let ticks = u64::from(ticks);
+    let max = u64::from(counter.max()) + 1;
+
+    // smallest prescaler that brings the count in range; at most 2^16
+    // because `ticks` < 2^32
+    let first = (ticks + max - 1) / max;
+    let mut best = candidate(ticks, first, max);
+
+    if goal == Goal::Accuracy {
+        // beyond `ticks / 2` the count would be less than 2
+        let last = cmp::min(1 << 16, ticks / 2);
+        let mut div = first + 1;
+        while best.error != 0 && div <= last {
+            let next = candidate(ticks, div, max);
+            if next.error.abs() < best.error.abs() {
+                best = next;
+            }
+            div += 1;
+        }
+    }
+
+    Ok(best)
+}
+
+// Best solution with a prescaler of `div` (PSC + 1) and at most `max` counts
+// (ARR + 1)
+fn candidate(ticks: u64, div: u64, max: u64) -> Solution {
+    let counts = cmp::min(cmp::max((ticks + div / 2) / div, 2), max);
+    let period = div * counts;
+
+    Solution {
+        psc: (div - 1) as u16,
+        arr: (counts - 1) as u32,
+        period: period,
+        error: period as i64 - ticks as i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::Xorshift32;
+
+    const COUNTERS: [Counter; 2] = [Counter::Bits16, Counter::Bits32];
+    const GOALS: [Goal; 2] = [Goal::Accuracy, Goal::Resolution];
+
+    // properties of every solution
+    fn check(ticks: u32, counter: Counter, goal: Goal) -> Solution {
+        let solution = solve(ticks, counter, goal).unwrap();
+
+        assert_eq!(solution.period, period(solution.psc, solution.arr));
+        assert!(solution.arr >= 1 && solution.arr <= counter.max());
+        assert_eq!(solution.error, solution.period as i64 - i64::from(ticks));
+
+        if goal == Goal::Resolution {
+            // a smaller prescaler can't count up to `ticks`
+            let max = u64::from(counter.max()) + 1;
+            assert!(u64::from(solution.psc) * max < u64::from(ticks));
+            // ARR is the closest one for this prescaler
+            assert!(solution.error.abs() as u64 * 2 <= u64::from(solution.psc) + 1);
+        }
+
+        solution
+    }
+
+    // smallest error of all the PSC / ARR pairs, by brute force
+    fn best_error(ticks: u32, counter: Counter) -> u64 {
+        let ticks = u64::from(ticks);
+        let max = u64::from(counter.max()) + 1;
+
+        let mut best = u64::max_value();
+        for div in 1..(1 << 16) + 1 {
+            // the closest count is one of the two around `ticks / div`
+            for &counts in &[ticks / div, ticks / div + 1] {
+                let counts = cmp::min(cmp::max(counts, 2), max);
+                let error = (div * counts) as i64 - ticks as i64;
+                best = cmp::min(best, error.abs() as u64);
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn too_short() {
+        for &counter in &COUNTERS {
+            for &goal in &GOALS {
+                assert_eq!(solve(0, counter, goal), Err(Error::TooShort));
+                assert_eq!(solve(1, counter, goal), Err(Error::TooShort));
+            }
+        }
+    }
+
+    #[test]
+    fn sweep() {
+        for ticks in 2..(1 << 17) + 1 {
+            for &counter in &COUNTERS {
+                for &goal in &GOALS {
+                    let solution = check(ticks, counter, goal);
+
+                    if goal == Goal::Accuracy && (ticks <= 1 << 16 || counter == Counter::Bits32)
+                    {
+                        assert_eq!(solution.error, 0, "{} ticks", ticks);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sampled() {
+        let mut rng = Xorshift32::new(0x1234_5678);
+
+        // the end of the 16-bit range, then values with no exact solution
+        // on a 16-bit timer: primes, 193 * 22253377 and 65535 * 65537
+        let edges = [
+            1 << 16,
+            (1 << 16) + 1,
+            (1 << 17) - 1,
+            0xffff_0001,
+            0xffff_fffb,
+            0xffff_ffff,
+        ];
+
+        for i in 0..500 {
+            let ticks = if i < edges.len() {
+                edges[i]
+            } else {
+                // spread the samples over every order of magnitude
+                cmp::max(rng.next_u32() >> (rng.next_u32() % 32), 2)
+            };
+
+            for &counter in &COUNTERS {
+                check(ticks, counter, Goal::Resolution);
+
+                let accuracy = check(ticks, counter, Goal::Accuracy);
+                assert_eq!(
+                    accuracy.error.abs() as u64,
+                    best_error(ticks, counter),
+                    "{} ticks",
+                    ticks
+                );
+            }
+
+            assert_eq!(check(ticks, Counter::Bits32, Goal::Accuracy).error, 0);
+        }
+    }
+}
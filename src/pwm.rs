@@ -29,17 +29,29 @@
 //! - CH2 = PB7
 //! - CH3 = PB8
 //! - CH4 = PB9
+//!
+//! # One-pulse mode
+//!
+//! `pulse` outputs a single pulse with a programmable delay and width instead
+//! of a periodic signal; `pulse_on` outputs one after every edge on the
+//! channel 1 or 2 pin. `init` goes back to continuous PWM.
+//!
+//! # Synchronized timers
+//!
+//! `Timer(pwm.0)` links PWM timers together, e.g. to start them in lockstep;
+//! see `timer::Timer::set_master` and `timer::Timer::set_slave`.
 
 use core::any::{Any, TypeId};
+use core::cmp;
 use core::marker::Unsize;
 
 use cast::{u16, u32};
 use hal;
-use static_ref::Static;
 use stm32f40x::{DMA1, TIM1, TIM2, TIM3, TIM4, GPIOA, GPIOB, GPIOC, RCC};
 
-use dma::{self, Buffer, Dma1Stream2};
-use timer::Channel;
+use dma::{self, Dma1Stream2, Stream, Transfer};
+use prescaler::{self, Counter, Goal, Solution};
+use timer::{Channel, Timer, Trigger, CR1_CEN, CR1_OPM, CR1_URS, EGR_UG};
 
 /// PWM driver
 pub struct Pwm<'a, T>(pub &'a T)
@@ -93,6 +105,70 @@ macro_rules! impl_Pwm {
                     rcc.apb1enr.modify(|_, w| w.tim4en().set_bit());
                 }
 
+                self._pin(channel, gpioa, gpiob, gpioc, rcc);
+
+                // PWM mode 1
+                match channel {
+                    Channel::_1 => {
+                        tim.ccmr1_output.modify(|_, w| unsafe {w.oc1pe().set_bit().oc1m().bits(0b110)});
+                        tim.ccer.modify(|_, w| {w.cc1p().clear_bit()});
+                    }
+                    Channel::_2 => {
+                        tim.ccmr1_output.modify(|_, w| unsafe {w.oc2pe().set_bit().oc2m().bits(0b110)});
+                        tim.ccer.modify(|_, w| {w.cc2p().clear_bit()});
+                    }
+                    Channel::_3 => {
+                        tim.ccmr2_output.modify(|_, w| unsafe {w.oc3pe().set_bit().oc3m().bits(0b110)});
+                        tim.ccer.modify(|_, w| {w.cc3p().clear_bit()});
+                    }
+                    Channel::_4 => {
+                        if tim.get_type_id() == TypeId::of::<TIM2>() {
+                            unimplemented!()
+                        }
+                        tim.ccmr2_output.modify(|_, w| unsafe {w.oc4pe().set_bit().oc4m().bits(0b110)});
+                        tim.ccer.modify(|_, w| {w.cc4p().clear_bit()});
+                    }
+                }
+
+                self._set_period(period);
+
+                if dma1.is_some() {
+                    //  Update DMA request enable
+                    tim.dier.modify(|_, w| w.ude().set_bit());
+
+                    // the stream is configured by `set_duties`
+                    if tim.get_type_id() != TypeId::of::<TIM3>() {
+                        unimplemented!()
+                    }
+                }
+
+                // Not triggered by a slave mode input (see `pulse_on`)
+                tim.smcr.write(|w| unsafe { w.bits(0) });
+
+                tim.cr1.write(|w| unsafe {
+                    w.cms()
+                        .bits(0b00)
+                        .dir()
+                        .bit(false)
+                        .opm()
+                        .bit(false)
+                        .cen()
+                        .set_bit()
+                });
+            }
+
+            // Enables the GPIO port of `channel`'s pin and puts the pin in
+            // its alternate function mode
+            fn _pin(
+                &self,
+                channel: Channel,
+                gpioa: &GPIOA,
+                gpiob: &GPIOB,
+                gpioc: &GPIOC,
+                rcc: &RCC,
+            ) {
+                let tim = self.0;
+
                 rcc.ahb1enr.modify(|_, w| {
                     if tim.get_type_id() == TypeId::of::<TIM1>() {
                         w.gpioaen().set_bit()
@@ -213,130 +289,213 @@ macro_rules! impl_Pwm {
                         }
                     }
                 }
+            }
+
+            /// Sets the period with the largest auto-reload value, i.e. the
+            /// finest duty cycle resolution, that the prescaler allows
+            ///
+            /// Returns the register values with the achieved period and its
+            /// error (see `prescaler::solve`); the maximum duty is `arr`.
+            /// `init` and `hal::Pwm::set_period` use the shortest period, 2
+            /// ticks, instead of failing on shorter ones.
+            pub fn try_set_period<P>(&self, period: P) -> Result<Solution, prescaler::Error>
+            where
+                P: Into<::$APB::Ticks>,
+            {
+                let solution = prescaler::solve(period.into().0, self._counter(), Goal::Resolution)?;
+
+                self.0.psc.write(|w| unsafe{w.psc().bits(solution.psc)});
+                self.0.arr.write(|w| unsafe{w.bits(solution.arr)});
+
+                Ok(solution)
+            }
+
+            fn _set_period(&self, period: ::$APB::Ticks) {
+                // the solver only rejects periods shorter than 2 ticks
+                let period = period.map(|ticks| cmp::max(ticks, 2));
+
+                self.try_set_period(period).expect("invalid period");
+            }
+
+            fn _counter(&self) -> Counter {
+                if self.0.get_type_id() == TypeId::of::<TIM2>() {
+                    Counter::Bits32
+                } else {
+                    Counter::Bits16
+                }
+            }
+
+            /// Outputs a single pulse on `channel`, `delay` from now and
+            /// `width` long
+            ///
+            /// `channel` must have been initialized with `init`. The timer
+            /// switches to one-pulse mode (RM0368 13.3.15): the output goes
+            /// high after `delay` and low again at the update event, where the
+            /// counter stops. `Timer::is_running` tells when the pulse is over.
+            ///
+            /// `delay` and `width` are rounded to whole counter ticks, which
+            /// last `PSC + 1` timer clock cycles (see `prescaler::solve`; PSC
+            /// grows with `delay + width`), and each lasts at least one
+            /// counter tick: a `delay` of 0 becomes one tick, taken from
+            /// `width`.
+            ///
+            /// # Panics
+            ///
+            /// Panics if `width` is 0, or if `delay + width` overflows 32 bits
+            /// or is shorter than 2 timer clock cycles
+            pub fn pulse<D, W>(&self, channel: Channel, delay: D, width: W)
+            where
+                D: Into<::$APB::Ticks>,
+                W: Into<::$APB::Ticks>,
+            {
+                self._pulse(channel, delay.into(), width.into());
+
+                self.0.cr1.modify(|_, w| w.cen().set_bit());
+            }
+
+            /// Outputs a pulse on `channel`, `delay` after every `trigger`
+            /// edge and `width` long
+            ///
+            /// Like `pulse` but started by the slave mode controller (see
+            /// `Timer::start_on`), whose input pin is configured here. Edges
+            /// that arrive while a pulse is being output are ignored.
+            ///
+            /// `delay` and `width` are rounded as in `pulse`; a `delay` of 0
+            /// becomes one counter tick after the edge, taken from `width`.
+            ///
+            /// # Panics
+            ///
+            /// Panics if `trigger` uses the pin of `channel`, if `width` is 0,
+            /// or if `delay + width` overflows 32 bits or is shorter than 2
+            /// timer clock cycles
+            pub fn pulse_on<D, W>(
+                &self,
+                channel: Channel,
+                delay: D,
+                width: W,
+                trigger: Trigger,
+                gpioa: &GPIOA,
+                gpiob: &GPIOB,
+                gpioc: &GPIOC,
+                rcc: &RCC,
+            ) where
+                D: Into<::$APB::Ticks>,
+                W: Into<::$APB::Ticks>,
+            {
+                match (channel, trigger.channel()) {
+                    (Channel::_1, Channel::_1) | (Channel::_2, Channel::_2) => {
+                        panic!("trigger input on the output channel")
+                    }
+                    _ => {}
+                }
+
+                self._pin(trigger.channel(), gpioa, gpiob, gpioc, rcc);
+                self._pulse(channel, delay.into(), width.into());
+
+                Timer(self.0).start_on(trigger);
+            }
+
+            // Stops the counter and loads the pulse in one-pulse mode
+            fn _pulse(&self, channel: Channel, delay: ::$APB::Ticks, width: ::$APB::Ticks) {
+                let tim = self.0;
+
+                assert!(width.0 > 0, "empty pulse");
+
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_CEN) });
+
+                // PWM mode 2 from CNT = 0: low until CNT = CCR, high until the
+                // overflow that follows CNT = ARR. With CCR = 0 the output
+                // would stay high once the counter stops.
+                let period = delay.0.checked_add(width.0).expect("pulse too long");
+                let solution = prescaler::solve(period, self._counter(), Goal::Accuracy)
+                    .expect("invalid pulse");
+                let div = u32(solution.psc) + 1;
+                let ccr = cmp::min(cmp::max(delay.0 / div, 1), solution.arr);
+
+                tim.psc.write(|w| unsafe { w.psc().bits(solution.psc) });
+                tim.arr.write(|w| unsafe { w.bits(solution.arr) });
 
-                // PWM mode 1
                 match channel {
                     Channel::_1 => {
-                        tim.ccmr1_output.modify(|_, w| unsafe {w.oc1pe().set_bit().oc1m().bits(0b110)});
-                        tim.ccer.modify(|_, w| {w.cc1p().clear_bit()});
+                        tim.ccr1.write(|w| unsafe { w.bits(ccr) });
+                        tim.ccmr1_output.modify(|_, w| unsafe { w.oc1m().bits(0b111) });
                     }
                     Channel::_2 => {
-                        tim.ccmr1_output.modify(|_, w| unsafe {w.oc2pe().set_bit().oc2m().bits(0b110)});
-                        tim.ccer.modify(|_, w| {w.cc2p().clear_bit()});
+                        tim.ccr2.write(|w| unsafe { w.bits(ccr) });
+                        tim.ccmr1_output.modify(|_, w| unsafe { w.oc2m().bits(0b111) });
                     }
                     Channel::_3 => {
-                        tim.ccmr2_output.modify(|_, w| unsafe {w.oc3pe().set_bit().oc3m().bits(0b110)});
-                        tim.ccer.modify(|_, w| {w.cc3p().clear_bit()});
+                        tim.ccr3.write(|w| unsafe { w.bits(ccr) });
+                        tim.ccmr2_output.modify(|_, w| unsafe { w.oc3m().bits(0b111) });
                     }
                     Channel::_4 => {
-                        if tim.get_type_id() == TypeId::of::<TIM2>() {
-                            unimplemented!()
-                        }
-                        tim.ccmr2_output.modify(|_, w| unsafe {w.oc4pe().set_bit().oc4m().bits(0b110)});
-                        tim.ccer.modify(|_, w| {w.cc4p().clear_bit()});
-                    }
-                }
-
-                self._set_period(period);
-
-                if let Some(dma1) = dma1 {
-                    //  Update DMA request enable
-                    tim.dier.modify(|_, w| w.ude().set_bit());
-
-                    if tim.get_type_id() == TypeId::of::<TIM3>() {
-                        // TIM3_CH4/UP
-                        // chsel: Channel 5 (RM0368 9.3.3 Table 27)
-                        // pl: Medium priority
-                        // msize: Memory size = 8 bits
-                        // psize: Peripheral size = 16 bits
-                        // minc: Memory increment mode enabled
-                        // pinc: Peripheral increment mode disabled
-                        // circ: Circular mode disabled
-                        // dir: Transfer from memory to peripheral
-                        // tcie: Transfer complete interrupt enabled
-                        // en: Disabled
-                        dma1.s2cr.write(|w| unsafe {
-                            w.chsel()
-                                .bits(5)
-                                .pl()
-                                .bits(0b01)
-                                .msize()
-                                .bits(0b00)
-                                .psize()
-                                .bits(0b01)
-                                .minc()
-                                .set_bit()
-                                .circ()
-                                .set_bit()
-                                .pinc()
-                                .clear_bit()
-                                .dir()
-                                .bits(1)
-                                .tcie()
-                                .set_bit()
-                                .en()
-                                .clear_bit()
-                        });
-                    } else {
-                        unimplemented!()
+                        tim.ccr4.write(|w| unsafe { w.bits(ccr) });
+                        tim.ccmr2_output.modify(|_, w| unsafe { w.oc4m().bits(0b111) });
                     }
                 }
 
-                tim.cr1.write(|w| unsafe {
-                    w.cms()
-                        .bits(0b00)
-                        .dir()
-                        .bit(false)
-                        .opm()
-                        .bit(false)
-                        .cen()
-                        .set_bit()
-                });
-            }
-
-            fn _set_period(&self, period: ::$APB::Ticks) {
-                let period = period.0;
-
-                let psc = u16((period - 1) / (1 << 16)).unwrap();
-                self.0.psc.write(|w| unsafe{w.psc().bits(psc)});
+                hal::Pwm::enable(self, channel);
 
-                let arr = u32(period / u32(psc + 1));
-                self.0.arr.write(|w| unsafe{w.bits(arr)});
+                // URS: the UG below loads the preloaded registers and clears
+                // the counter without setting UIF
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_OPM | CR1_URS) });
+                tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+                tim.sr.modify(|_, w| w.uif().clear_bit());
             }
 
             /// Uses `buffer` to continuously change the duty cycle on every period
+            ///
+            /// The transfer is circular and never completes; `release` it to
+            /// stop it and get the `buffer` and the `stream` back. The DMA1
+            /// stream 2 interrupt fires at the end of every sequence of
+            /// duties; a `dma::Handler` can turn it into a callback.
+            ///
+            /// # Panics
+            ///
+            /// Panics if DMA1 stream 2 is in use
             pub fn set_duties<B>(
                 &self,
                 dma1: &DMA1,
+                mut stream: Dma1Stream2,
                 channel: Channel,
-                buffer: &Static<Buffer<B, Dma1Stream2>>,
-            ) -> ::core::result::Result<(), dma::Error>
+                buffer: &'static mut B,
+            ) -> Transfer<&'static mut B, Dma1Stream2>
             where
                 B: Unsize<[u8]>,
             {
                 let tim3 = self.0;
 
                 if tim3.get_type_id() == TypeId::of::<TIM3>() {
-                    if dma1.s2cr.read().en().bit_is_set() {
-                        return Err(dma::Error::InUse);
-                    }
-
-                    let buffer: &[u8] = buffer.lock();
-
-                    dma1.s2ndtr.write(|w| unsafe { w.ndt().bits(u16(buffer.len()).unwrap()) });
-                    dma1.s2par.write(|w| unsafe {
-                        match channel {
-                            Channel::_1 => w.bits(&tim3.ccr1 as *const _ as u32),
-                            Channel::_2 => w.bits(&tim3.ccr2 as *const _ as u32),
-                            Channel::_3 => w.bits(&tim3.ccr3 as *const _ as u32),
-                            Channel::_4 => w.bits(&tim3.ccr4 as *const _ as u32),
-                        }
-                    });
-                    dma1.s2m0ar.write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
-                    dma1.s2cr.modify(|_, w| w.en().set_bit());
-
-                    Ok(())
-
+                    assert!(!Dma1Stream2::is_enabled(dma1), "DMA stream in use");
+
+                    let (address, len) = {
+                        let slice: &[u8] = &*buffer;
+                        (slice.as_ptr() as u32, u16(slice.len()).unwrap())
+                    };
+
+                    let ccr = match channel {
+                        Channel::_1 => &tim3.ccr1 as *const _ as u32,
+                        Channel::_2 => &tim3.ccr2 as *const _ as u32,
+                        Channel::_3 => &tim3.ccr3 as *const _ as u32,
+                        Channel::_4 => &tim3.ccr4 as *const _ as u32,
+                    };
+
+                    // TIM3_CH4/UP: DMA1 stream 2, channel 5 (RM0368 9.3.3
+                    // Table 27); 8-bit memory items into the 16-bit CCR,
+                    // circular
+                    let mut config = dma::Config::new(
+                        dma::Channel::_5,
+                        dma::Direction::MemoryToPeripheral,
+                    );
+                    config.peripheral_size = dma::Size::HalfWord;
+                    config.circular = true;
+                    config.transfer_complete_interrupt = true;
+                    config.transfer_error_interrupt = true;
+                    stream.configure(dma1, &config);
+
+                    // NOTE(unsafe) the transfer covers the whole `'static`
+                    // buffer
+                    unsafe { Transfer::start(dma1, stream, buffer, ccr, address, len) }
                 } else {
                     unimplemented!()
                 }
@@ -388,7 +547,11 @@ macro_rules! impl_halPwm {
             }
 
             fn get_period(&self) -> ::$APB::Ticks {
-                ::$APB::Ticks(u32(self.0.psc.read().bits() * self.0.arr.read().bits()))
+                let period = prescaler::period(
+                    self.0.psc.read().psc().bits(),
+                    self.0.arr.read().bits(),
+                );
+                ::$APB::Ticks(u32(period).unwrap_or(u32::max_value()))
             }
 
             fn set_duty(&self, channel: Channel, duty: u32) {
@@ -463,7 +626,11 @@ impl<'a> hal::Pwm for Pwm<'a, TIM1> {
     }
 
     fn get_period(&self) -> ::apb2::Ticks {
-        ::apb2::Ticks(u32(self.0.psc.read().bits() * self.0.arr.read().bits()))
+        let period = prescaler::period(
+            self.0.psc.read().psc().bits(),
+            u32(self.0.arr.read().arr().bits()),
+        );
+        ::apb2::Ticks(u32(period).unwrap_or(u32::max_value()))
     }
 
     fn set_duty(&self, channel: Channel, duty: u16) {
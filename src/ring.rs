@@ -0,0 +1,290 @@
+//! Bookkeeping for circular DMA reception
+//!
+//! A DMA stream in circular mode keeps writing into a ring of `len` bytes and
+//! only tells us how far it got through its NDTR register plus the half
+//! transfer (HT) and transfer complete (TC) flags. `RingIndex` turns those
+//! observations into the range of new bytes since the last read, and detects
+//! when the DMA lapped the reader.
+//!
+//! This module does not touch any register so it can be exercised on the
+//! host.
+
+use core::ops::Range;
+
+/// The DMA wrote over data that had not been read yet
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Overrun;
+
+/// New data in the ring, as at most two contiguous ranges
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    /// Bytes between the read position and the end of the ring (or the write
+    /// position if the data doesn't wrap around)
+    pub first: Range<usize>,
+    /// Bytes from the start of the ring, non-empty only if the data wrapped
+    /// around
+    pub second: Range<usize>,
+}
+
+impl Chunk {
+    /// Total number of new bytes
+    pub fn len(&self) -> usize {
+        (self.first.end - self.first.start) + (self.second.end - self.second.start)
+    }
+
+    /// `true` if there is no new data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Read position of a ring that's being filled by a circular DMA transfer
+#[derive(Clone, Copy, Debug)]
+pub struct RingIndex {
+    len: usize,
+    read: usize,
+    // boundaries we know the DMA crossed but whose flag we haven't seen yet
+    owed_half: bool,
+    owed_end: bool,
+}
+
+impl RingIndex {
+    /// Creates the bookkeeping for a ring of `len` bytes
+    pub const fn new(len: usize) -> Self {
+        RingIndex {
+            len: len,
+            read: 0,
+            owed_half: false,
+            owed_end: false,
+        }
+    }
+
+    /// Size of the ring
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+
+    /// Current read position
+    pub fn position(&self) -> usize {
+        self.read
+    }
+
+    /// Forgets any unread data; the next read starts at `position`
+    pub fn reset(&mut self, position: usize) {
+        self.read = position % self.len;
+        self.owed_half = false;
+        self.owed_end = false;
+    }
+
+    /// Consumes the data written since the last call
+    ///
+    /// `ht` and `tc` are the half transfer and transfer complete flags and
+    /// `ndtr` is the value of the stream's NDTR register. The flags must be
+    /// sampled (and the ones that were set cleared) *before* sampling NDTR;
+    /// a boundary that shows up in `ndtr` but not yet in the flags is
+    /// remembered and expected on the next call.
+    ///
+    /// On `Overrun` the read position is moved to the current write position
+    /// so the next call returns fresh data only.
+    ///
+    /// NOTE a lap that crosses both flags twice in a row between two calls
+    /// can't be told apart from a regular transfer; call this at least once
+    /// per half ring (for example from the HT/TC interrupts)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ring is smaller than 2 bytes or `ndtr` is larger than
+    /// the ring
+    pub fn advance(&mut self, ndtr: usize, ht: bool, tc: bool) -> Result<Chunk, Overrun> {
+        assert!(self.len >= 2 && ndtr <= self.len);
+
+        let half = self.len / 2;
+        // NDTR counts down and reloads to `len` on wrap around
+        let write = (self.len - ndtr) % self.len;
+        let read = self.read;
+
+        // Which flags is the DMA allowed to have raised while going from
+        // `read` to `write`?
+        let (crosses_half, crosses_end) = if write > read {
+            (read < half && write >= half, false)
+        } else if write < read {
+            (read < half || write >= half, true)
+        } else {
+            (false, false)
+        };
+
+        let due_half = self.owed_half as u8 + crosses_half as u8;
+        let due_end = self.owed_end as u8 + crosses_end as u8;
+
+        self.read = write;
+
+        if (ht && due_half == 0) || (tc && due_end == 0) {
+            self.owed_half = false;
+            self.owed_end = false;
+            return Err(Overrun);
+        }
+
+        self.owed_half = due_half > ht as u8;
+        self.owed_end = due_end > tc as u8;
+
+        if write >= read {
+            Ok(Chunk {
+                first: read..write,
+                second: 0..0,
+            })
+        } else {
+            Ok(Chunk {
+                first: read..self.len,
+                second: 0..write,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use test_util::Xorshift32;
+
+    #[test]
+    fn empty() {
+        let mut ring = RingIndex::new(8);
+
+        assert!(ring.advance(8, false, false).unwrap().is_empty());
+        assert_eq!(ring.position(), 0);
+    }
+
+    #[test]
+    fn contiguous_then_wrapped() {
+        let mut ring = RingIndex::new(8);
+
+        assert_eq!(
+            ring.advance(5, false, false),
+            Ok(Chunk {
+                first: 0..3,
+                second: 0..0,
+            })
+        );
+        // crosses the half way mark
+        assert_eq!(
+            ring.advance(2, true, false),
+            Ok(Chunk {
+                first: 3..6,
+                second: 0..0,
+            })
+        );
+        // wraps around
+        let chunk = ring.advance(6, false, true).unwrap();
+        assert_eq!(
+            chunk,
+            Chunk {
+                first: 6..8,
+                second: 0..2,
+            }
+        );
+        assert_eq!(chunk.len(), 4);
+        assert_eq!(ring.position(), 2);
+    }
+
+    #[test]
+    fn write_position_at_the_end() {
+        let mut ring = RingIndex::new(8);
+
+        ring.advance(4, true, false).unwrap();
+        // NDTR reloaded: the DMA is back at the start of the ring
+        assert_eq!(
+            ring.advance(8, false, true),
+            Ok(Chunk {
+                first: 4..8,
+                second: 0..0,
+            })
+        );
+        assert_eq!(ring.position(), 0);
+    }
+
+    #[test]
+    fn flag_seen_after_ndtr() {
+        let mut ring = RingIndex::new(8);
+
+        // NDTR already past the half but HT not set yet
+        assert_eq!(ring.advance(3, false, false).unwrap().len(), 5);
+        // HT shows up later; it was owed, not an overrun
+        assert!(ring.advance(3, true, false).unwrap().is_empty());
+        // but only once
+        assert_eq!(ring.advance(3, true, false), Err(Overrun));
+    }
+
+    #[test]
+    fn overrun() {
+        let mut ring = RingIndex::new(8);
+
+        ring.advance(6, false, false).unwrap();
+        // the DMA went around the whole ring: both flags, NDTR barely moved
+        assert_eq!(ring.advance(5, true, true), Err(Overrun));
+        // resynchronized on the write position
+        assert_eq!(ring.position(), 3);
+        assert_eq!(ring.advance(4, true, false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reset() {
+        let mut ring = RingIndex::new(8);
+
+        ring.advance(5, false, false).unwrap();
+        ring.reset(10);
+        assert_eq!(ring.position(), 2);
+        assert_eq!(
+            ring.advance(5, false, false),
+            Ok(Chunk {
+                first: 2..3,
+                second: 0..0,
+            })
+        );
+    }
+
+    // A simulated DMA writes a counting sequence in steps of random sizes;
+    // the chunks must give back the sequence, byte by byte
+    #[test]
+    fn simulated_transfer() {
+        const LEN: usize = 16;
+
+        let mut rng = Xorshift32::new(0x2545_f491);
+
+        let mut ring = RingIndex::new(LEN);
+        let mut buffer = [0u8; LEN];
+        let mut written = 0usize;
+        let (mut ht, mut tc) = (false, false);
+        let mut received = Vec::new();
+
+        for _ in 0..10_000 {
+            // less than half a ring between two reads
+            let step = rng.next_u32() as usize % (LEN / 2);
+            for _ in 0..step {
+                buffer[written % LEN] = written as u8;
+                written += 1;
+                match written % LEN {
+                    0 => tc = true,
+                    n if n == LEN / 2 => ht = true,
+                    _ => {}
+                }
+            }
+
+            // flags first, then NDTR
+            let (sampled_ht, sampled_tc) = (ht, tc);
+            ht = false;
+            tc = false;
+            let ndtr = LEN - written % LEN;
+
+            let chunk = ring.advance(ndtr, sampled_ht, sampled_tc).unwrap();
+            received.extend_from_slice(&buffer[chunk.first.clone()]);
+            received.extend_from_slice(&buffer[chunk.second.clone()]);
+        }
+
+        assert_eq!(received.len(), written);
+        for (i, byte) in received.iter().enumerate() {
+            assert_eq!(*byte, i as u8);
+        }
+    }
+}
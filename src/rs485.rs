@@ -0,0 +1,256 @@
+//! RS-485 half duplex serial interface
+//!
+//! An RS-485 transceiver only drives the bus while its driver enable (DE)
+//! input is asserted. `Rs485` asserts a GPIO wired to DE (and usually to the
+//! inverted receiver enable, RE) before every transmission and releases it on
+//! the USART transmission complete event, i.e. right after the stop bit of
+//! the last byte, so the bus is free for the next node to answer.
+//!
+//! For multi-drop buses the USART mute mode is supported: with
+//! `Wakeup::AddressMark` the frames are 9 bits long and a frame with the 9th
+//! bit set carries a 4-bit node address. A muted receiver ignores everything
+//! until it sees its own address.
+//!
+//! # Interrupts
+//!
+//! - USART TC: call `Rs485::on_interrupt` from the USART interrupt
+
+use core::any::Any;
+use core::marker::Unsize;
+
+use hal;
+use nb;
+use stm32f40x::{DMA1, GPIOA, GPIOB, GPIOC, RCC, USART2};
+
+use dma::{Dma1Stream6, Stream, Transfer};
+use serial::{self, Event, Serial, Usart};
+
+/// GPIO port of the driver enable pin
+#[derive(Clone, Copy, Debug)]
+pub enum Port {
+    /// GPIOA
+    A,
+    /// GPIOB
+    B,
+    /// GPIOC
+    C,
+}
+
+/// The GPIO pin that drives the transceiver's DE (and /RE) input
+#[derive(Clone, Copy, Debug)]
+pub struct DriverEnable {
+    /// GPIO port
+    pub port: Port,
+    /// Pin number, 0 - 15
+    pub pin: u8,
+    /// `true` if the transceiver drives the bus while the pin is high
+    pub active_high: bool,
+}
+
+impl DriverEnable {
+    /// Configures the pin as a push-pull output and releases the bus
+    pub fn init(&self, rcc: &RCC) {
+        assert!(self.pin < 16);
+
+        match self.port {
+            Port::A => rcc.ahb1enr.modify(|_, w| w.gpioaen().set_bit()),
+            Port::B => rcc.ahb1enr.modify(|_, w| w.gpioben().set_bit()),
+            Port::C => rcc.ahb1enr.modify(|_, w| w.gpiocen().set_bit()),
+        }
+
+        self.set(false);
+
+        let shift = 2 * self.pin;
+        // NOTE(unsafe) only this pin's mode bits are touched
+        unsafe {
+            match self.port {
+                Port::A => (*GPIOA.get()).moder.modify(|r, w| {
+                    w.bits((r.bits() & !(0b11 << shift)) | (0b01 << shift))
+                }),
+                Port::B => (*GPIOB.get()).moder.modify(|r, w| {
+                    w.bits((r.bits() & !(0b11 << shift)) | (0b01 << shift))
+                }),
+                Port::C => (*GPIOC.get()).moder.modify(|r, w| {
+                    w.bits((r.bits() & !(0b11 << shift)) | (0b01 << shift))
+                }),
+            }
+        }
+    }
+
+    /// Asserts (`true`) or releases (`false`) the driver enable line
+    pub fn set(&self, drive: bool) {
+        let bits = if drive == self.active_high {
+            1 << self.pin
+        } else {
+            1 << (self.pin + 16)
+        };
+
+        // NOTE(safe) atomic write
+        unsafe {
+            match self.port {
+                Port::A => (*GPIOA.get()).bsrr.write(|w| w.bits(bits)),
+                Port::B => (*GPIOB.get()).bsrr.write(|w| w.bits(bits)),
+                Port::C => (*GPIOC.get()).bsrr.write(|w| w.bits(bits)),
+            }
+        }
+    }
+}
+
+/// Receiver wakeup method in mute mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Wakeup {
+    /// Wake up when the line goes idle
+    IdleLine,
+    /// Wake up on a 9-bit frame with the MSB set and a matching address
+    AddressMark,
+}
+
+/// RS-485 interface on top of a `Serial` interface
+pub struct Rs485<'a, U>
+where
+    U: 'a + Any + Usart,
+{
+    serial: Serial<'a, U>,
+    de: DriverEnable,
+}
+
+impl<'a, U> Rs485<'a, U>
+where
+    U: Any + Usart,
+{
+    /// Wraps an initialized `serial` interface
+    ///
+    /// `de` must have been `init`ialized
+    pub fn new(serial: Serial<'a, U>, de: DriverEnable) -> Self {
+        de.set(false);
+
+        Rs485 {
+            serial: serial,
+            de: de,
+        }
+    }
+
+    /// The underlying serial interface
+    pub fn serial(&self) -> Serial<'a, U> {
+        self.serial
+    }
+
+    /// Configures the 9-bit address mark mode and sets the address of this
+    /// node (4 bits)
+    ///
+    /// The receiver stays awake until `mute` is called
+    pub fn set_address(&self, address: u8) {
+        let usart = self.serial.0;
+
+        assert!(address < 16);
+
+        // RM0368 19.3.6 Multiprocessor communication
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr2.modify(|_, w| unsafe { w.add().bits(address) });
+        usart.cr1.modify(|_, w| w.m().set_bit().wake().set_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    /// Puts the receiver in mute mode until the `wakeup` condition happens
+    pub fn mute(&self, wakeup: Wakeup) {
+        let usart = self.serial.0;
+
+        usart.cr1.modify(|_, w| {
+            w.wake()
+                .bit(wakeup == Wakeup::AddressMark)
+                .rwu()
+                .set_bit()
+        });
+    }
+
+    /// `true` if the receiver is in mute mode
+    pub fn is_muted(&self) -> bool {
+        self.serial.0.cr1.read().rwu().bit_is_set()
+    }
+
+    /// Sends an address frame (9th bit set) to wake up node `address`
+    pub fn write_address(&self, address: u8) -> serial::Result<()> {
+        self.write_frame(0x100 | address as u16)
+    }
+
+    fn write_frame(&self, frame: u16) -> serial::Result<()> {
+        let usart = self.serial.0;
+
+        if usart.sr.read().txe().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.start_transmission();
+        // NOTE(write) full width write, in 9-bit mode the 9th bit of the DR
+        // write is the address mark
+        usart.dr.write(|w| unsafe { w.dr().bits(frame) });
+
+        Ok(())
+    }
+
+    // Drives the bus and arms the transmission complete interrupt
+    fn start_transmission(&self) {
+        let usart = self.serial.0;
+
+        self.de.set(true);
+        // NOTE(write) the SR flags are cleared by writing zero; write ones to
+        // the others so that we don't clear RXNE by accident
+        usart
+            .sr
+            .write(|w| unsafe { w.bits(0xffff_ffff).tc().clear_bit() });
+        self.serial.listen(Event::Tc);
+    }
+
+    /// Releases the bus once the last byte has left the shift register
+    ///
+    /// Call this from the USART interrupt. Returns `true` if the bus was
+    /// released.
+    pub fn on_interrupt(&self) -> bool {
+        let usart = self.serial.0;
+
+        if usart.cr1.read().tcie().bit_is_set() && usart.sr.read().tc().bit_is_set() {
+            self.serial.unlisten(Event::Tc);
+            self.de.set(false);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<'a, U> hal::serial::Write<u8> for Rs485<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = serial::Error;
+
+    fn write(&self, byte: u8) -> serial::Result<()> {
+        // 9th bit clear: a data frame in address mark mode
+        self.write_frame(byte as u16)
+    }
+}
+
+impl<'a> Rs485<'a, USART2> {
+    /// Starts a DMA transfer to send `buffer` over the bus
+    ///
+    /// The bus is driven from now until `on_interrupt` sees the transmission
+    /// complete event after the last byte. See `Serial::write_all`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA1 stream 6 is in use
+    pub fn write_all<B>(
+        &self,
+        dma1: &DMA1,
+        stream: Dma1Stream6,
+        buffer: &'static mut B,
+    ) -> Transfer<&'static mut B, Dma1Stream6>
+    where
+        B: Unsize<[u8]>,
+    {
+        assert!(!Dma1Stream6::is_enabled(dma1), "DMA stream in use");
+
+        self.start_transmission();
+        self.serial.write_all(dma1, stream, buffer)
+    }
+}
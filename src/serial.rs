@@ -6,20 +6,30 @@
 //!
 //! - TX = PA2
 //! - RX = PA3
+//! - CTS = PA0 (optional, see `FlowControl`)
+//! - RTS = PA1 (optional, see `FlowControl`)
 //! - Interrupt = USART2
+//!
+//! NOTE On the Nucleo boards PA2 and PA3 are wired to the ST-LINK virtual COM
+//! port, which has no flow control lines. CTS and RTS are available on the
+//! Arduino A0 and A1 headers and conflict with TIM2 CH1/CH2 and ADC1 IN0/IN1.
+//! USART1 (CTS = PA11, RTS = PA12) and USART6 (no CTS/RTS pins on the 64 pin
+//! package) are not supported by `Serial` yet.
+//!
+//! See the `half_duplex`, `lin` and `rs485` modules for the other USART modes.
 
 use core::any::{Any, TypeId};
 use core::marker::Unsize;
 use core::ops::Deref;
-use core::ptr;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use cast::u16;
 use hal;
 use nb;
-use static_ref::Static;
 use stm32f40x::{gpioa, DMA1, USART2, usart6, GPIOA, RCC};
 
-use dma::{self, Buffer, Dma1Stream5, Dma1Stream6};
+use dma::{self, Dma1Stream5, Dma1Stream6, DoubleBuffer, RingBuffer, Stream, Transfer};
 
 use core::fmt;
 
@@ -32,11 +42,18 @@ pub unsafe trait Usart: Deref<Target = usart6::RegisterBlock> {
     type GPIO: Deref<Target = gpioa::RegisterBlock>;
     /// IMPLEMENTATION DETAIL
     type Ticks: Into<u32>;
+    /// IMPLEMENTATION DETAIL
+    fn error_counters() -> &'static ErrorCounters;
 }
 
 unsafe impl Usart for USART2 {
     type GPIO = GPIOA;
     type Ticks = ::apb2::Ticks;
+
+    fn error_counters() -> &'static ErrorCounters {
+        static ERRORS: ErrorCounters = ErrorCounters::new();
+        &ERRORS
+    }
 }
 
 /// An error
@@ -48,11 +65,68 @@ pub enum Error {
     Noise,
     /// RX buffer overrun
     Overrun,
+    /// Parity check failed (only when parity is enabled, e.g. in smartcard
+    /// mode)
+    Parity,
     #[doc(hidden)] _Extensible,
 }
 
+/// Number of receive errors of each class seen by a USART
+///
+/// See `Serial::errors`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ErrorCounts {
+    /// Framing errors (including breaks)
+    pub framing: usize,
+    /// Noise errors
+    pub noise: usize,
+    /// Overrun errors
+    pub overrun: usize,
+    /// Parity errors
+    pub parity: usize,
+}
+
+/// IMPLEMENTATION DETAIL
+#[doc(hidden)]
+pub struct ErrorCounters {
+    framing: AtomicUsize,
+    noise: AtomicUsize,
+    overrun: AtomicUsize,
+    parity: AtomicUsize,
+}
+
+impl ErrorCounters {
+    const fn new() -> Self {
+        ErrorCounters {
+            framing: AtomicUsize::new(0),
+            noise: AtomicUsize::new(0),
+            overrun: AtomicUsize::new(0),
+            parity: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Hardware flow control
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlowControl {
+    /// No flow control (default after `init`)
+    None,
+    /// RTS only: the USART deasserts RTS while its receive buffer is full
+    Rts,
+    /// CTS only: the USART only transmits while CTS is asserted (low)
+    Cts,
+    /// Both RTS and CTS
+    RtsCts,
+}
+
 /// Interrupt event
 pub enum Event {
+    /// CTS input toggled (requires `FlowControl::Cts` or `FlowControl::RtsCts`)
+    Cts,
+    /// IDLE line detected (the RX line went quiet after a frame)
+    Idle,
+    /// LIN break detected (requires `lin::Lin::init`)
+    LinBreak,
     /// RX buffer Not Empty (new data available)
     Rxne,
     /// Transmission Complete
@@ -133,91 +207,14 @@ where
                 .modify(|_, w| w.moder2().bits(2).moder3().bits(2));
         }
 
-        if let Some(dma1) = dma1 {
-            if usart.get_type_id() == TypeId::of::<USART2>() {
-                // TX DMA transfer
-                // chsel: Channel 4 (RM0368 9.3.3 Table 27)
-                // pl: Medium priority
-                // msize: Memory size = 8 bits
-                // psize: Peripheral size = 8 bits
-                // minc: Memory increment mode enabled
-                // pinc: Peripheral increment mode disabled
-                // circ: Circular mode disabled
-                // dir: Transfer from memory to peripheral
-                // tcie: Transfer complete interrupt enabled
-                // en: Disabled
-                dma1.s6cr.write(|w| unsafe {
-                    w.chsel()
-                        .bits(4)
-                        .pl()
-                        .bits(0b01)
-                        .msize()
-                        .bits(0b00)
-                        .psize()
-                        .bits(0b00)
-                        .minc()
-                        .set_bit()
-                        .circ()
-                        .clear_bit()
-                        .pinc()
-                        .clear_bit()
-                        .dir()
-                        .bits(1)
-                        .tcie()
-                        .set_bit()
-                        .en()
-                        .clear_bit()
-                });
-
-                // RX DMA transfer
-                // chsel: Channel 4 (RM0368 9.3.3 Table 27)
-                // pl: Medium priority
-                // msize: Memory size = 8 bits
-                // psize: Peripheral size = 8 bits
-                // minc: Memory increment mode enabled
-                // pinc: Peripheral increment mode disabled
-                // circ: Circular mode disabled
-                // dir: Transfer from peripheral to memory
-                // tcie: Transfer complete interrupt enabled
-                // en: Disabled
-                dma1.s5cr.write(|w| unsafe {
-                    w.chsel()
-                        .bits(4)
-                        .pl()
-                        .bits(0b01)
-                        .msize()
-                        .bits(0b00)
-                        .psize()
-                        .bits(0b00)
-                        .minc()
-                        .set_bit()
-                        .circ()
-                        .clear_bit()
-                        .pinc()
-                        .clear_bit()
-                        .dir()
-                        .bits(0)
-                        .tcie()
-                        .set_bit()
-                        .en()
-                        .clear_bit()
-                });
-            }
-        }
+        self.reset_errors();
 
         // 8N1, stop bit
         usart.cr2.write(|w| unsafe { w.stop().bits(0b00) });
 
-        // Baud rate
-        // Check if peripheral does not use default clock
-        let ahb1_clk: u32 = ::ahb1::Ticks::from(::time::Seconds(1)).into();
-        let apb1_clk: u32 = ::apb1::Ticks::from(::time::Seconds(1)).into();
-        let apb1psc: u32 = ahb1_clk / apb1_clk;
-        let brr = baud_rate.into() / apb1psc;
-        assert!(brr >= 16, "impossible baud rate");
-        usart.brr.write(|w| unsafe { w.bits(brr) });
+        self._set_baud_rate(baud_rate);
 
-        // disable hardware flow control
+        // disable hardware flow control, see `flow_control`
         // enable DMA TX and RX transfers
         usart.cr3.write(|w| {
             w.rtse()
@@ -249,11 +246,41 @@ where
         });
     }
 
+    /// Changes the baud rate of an initialized serial interface
+    ///
+    /// Waits for the current transmission to complete first.
+    pub fn set_baud_rate<B>(&self, baud_rate: B)
+    where
+        B: Into<U::Ticks>,
+    {
+        let usart = self.0;
+
+        while usart.sr.read().tc().bit_is_clear() {}
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        self._set_baud_rate(baud_rate.into());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    fn _set_baud_rate(&self, baud_rate: U::Ticks) {
+        // Baud rate
+        // Check if peripheral does not use default clock
+        let ahb1_clk: u32 = ::ahb1::Ticks::from(::time::Seconds(1)).into();
+        let apb1_clk: u32 = ::apb1::Ticks::from(::time::Seconds(1)).into();
+        let apb1psc: u32 = ahb1_clk / apb1_clk;
+        let brr = baud_rate.into() / apb1psc;
+        assert!(brr >= 16, "impossible baud rate");
+        self.0.brr.write(|w| unsafe { w.bits(brr) });
+    }
+
     /// Starts listening for an interrupt `event`
     pub fn listen(&self, event: Event) {
         let usart = self.0;
 
         match event {
+            Event::Cts => usart.cr3.modify(|_, w| w.ctsie().set_bit()),
+            Event::Idle => usart.cr1.modify(|_, w| w.idleie().set_bit()),
+            Event::LinBreak => usart.cr2.modify(|_, w| w.lbdie().set_bit()),
             Event::Rxne => usart.cr1.modify(|_, w| w.rxneie().set_bit()),
             Event::Tc => usart.cr1.modify(|_, w| w.tcie().set_bit()),
             Event::Txe => usart.cr1.modify(|_, w| w.txeie().set_bit()),
@@ -265,11 +292,156 @@ where
         let usart = self.0;
 
         match event {
+            Event::Cts => usart.cr3.modify(|_, w| w.ctsie().clear_bit()),
+            Event::Idle => usart.cr1.modify(|_, w| w.idleie().clear_bit()),
+            Event::LinBreak => usart.cr2.modify(|_, w| w.lbdie().clear_bit()),
             Event::Rxne => usart.cr1.modify(|_, w| w.rxneie().clear_bit()),
             Event::Tc => usart.cr1.modify(|_, w| w.tcie().clear_bit()),
             Event::Txe => usart.cr1.modify(|_, w| w.txeie().clear_bit()),
         }
     }
+
+    /// Enables or disables RTS/CTS hardware flow control
+    ///
+    /// Must be called after `init`. The CTS and RTS pins are switched to
+    /// their alternate function only if they're used; see the module
+    /// documentation for the pin mapping.
+    pub fn flow_control(&self, flow: FlowControl, gpio: &U::GPIO) {
+        let usart = self.0;
+
+        let (rts, cts) = match flow {
+            FlowControl::None => (false, false),
+            FlowControl::Rts => (true, false),
+            FlowControl::Cts => (false, true),
+            FlowControl::RtsCts => (true, true),
+        };
+
+        if usart.get_type_id() == TypeId::of::<USART2>() {
+            // DM00102166 - Alternate function AF7, Table 9
+            // PA0 = CTS (alternate function input, pulled up so that an
+            // unconnected CTS doesn't block the transmitter forever)
+            if cts {
+                gpio.afrl.modify(|_, w| w.afrl0().bits(7));
+                gpio.pupdr.modify(|_, w| unsafe { w.pupdr0().bits(0b01) });
+                gpio.moder.modify(|_, w| w.moder0().bits(2));
+            }
+            // PA1 = RTS (alternate function push-pull)
+            if rts {
+                gpio.afrl.modify(|_, w| w.afrl1().bits(7));
+                gpio.ospeedr.modify(|_, w| w.ospeedr1().bits(0b11));
+                gpio.moder.modify(|_, w| w.moder1().bits(2));
+            }
+        }
+
+        // RM0368 19.3.14 RTS and CTS flow control
+        usart.cr3.modify(|_, w| w.rtse().bit(rts).ctse().bit(cts));
+    }
+
+    /// Clears the CTS flag, returns `true` if the CTS line toggled since the
+    /// last call
+    pub fn clear_cts(&self) -> bool {
+        let usart = self.0;
+
+        if usart.sr.read().cts().bit_is_set() {
+            // NOTE(write) the SR flags are cleared by writing zero; write ones
+            // to the others so that we don't clear RXNE or TC by accident
+            usart
+                .sr
+                .write(|w| unsafe { w.bits(0xffff_ffff).cts().clear_bit() });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Clears the IDLE flag, returns `true` if it was set
+    pub fn clear_idle(&self) -> bool {
+        let usart = self.0;
+
+        // RM0368 19.6.1 IDLE is cleared by a read to SR followed by a read
+        // to DR
+        if usart.sr.read().idle().bit_is_set() {
+            usart.dr.read();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of receive errors of each class since `init` (or the last
+    /// `reset_errors`)
+    pub fn errors(&self) -> ErrorCounts {
+        let counters = self.counters();
+
+        ErrorCounts {
+            framing: counters.framing.load(Ordering::Relaxed),
+            noise: counters.noise.load(Ordering::Relaxed),
+            overrun: counters.overrun.load(Ordering::Relaxed),
+            parity: counters.parity.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the error counters to zero
+    pub fn reset_errors(&self) {
+        let counters = self.counters();
+
+        counters.framing.store(0, Ordering::Relaxed);
+        counters.noise.store(0, Ordering::Relaxed);
+        counters.overrun.store(0, Ordering::Relaxed);
+        counters.parity.store(0, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> &'static ErrorCounters {
+        U::error_counters()
+    }
+
+    /// Counts the error flags set in `sr`, a value just read from SR, and
+    /// returns the most severe one
+    ///
+    /// The caller must read DR next to clear the flags.
+    pub(crate) fn count_errors(&self, sr: &usart6::sr::R) -> Option<Error> {
+        self._count_errors(sr, true)
+    }
+
+    fn _count_errors(&self, sr: &usart6::sr::R, framing: bool) -> Option<Error> {
+        let counters = self.counters();
+        let mut error = None;
+
+        // in increasing order of severity
+        if sr.pe().bit_is_set() {
+            counters.parity.fetch_add(1, Ordering::Relaxed);
+            error = Some(Error::Parity);
+        }
+        if framing && sr.fe().bit_is_set() {
+            counters.framing.fetch_add(1, Ordering::Relaxed);
+            error = Some(Error::Framing);
+        }
+        if sr.nf().bit_is_set() {
+            counters.noise.fetch_add(1, Ordering::Relaxed);
+            error = Some(Error::Noise);
+        }
+        if sr.ore().bit_is_set() {
+            counters.overrun.fetch_add(1, Ordering::Relaxed);
+            error = Some(Error::Overrun);
+        }
+
+        error
+    }
+
+    // Counts and clears the receive error flags; the byte in DR is
+    // discarded. FE is ignored, and left for the read of DR to clear, unless
+    // `framing` is set
+    pub(crate) fn recover(&self, sr: &usart6::sr::R, framing: bool) -> Option<Error> {
+        let error = self._count_errors(sr, framing);
+
+        if error.is_some() {
+            // RM0368 19.6.1 ORE, NF, FE and PE are cleared by a read to SR
+            // (done by the caller) followed by a read to DR
+            self.0.dr.read();
+        }
+
+        error
+    }
 }
 
 impl<'a, U> hal::serial::Read<u8> for Serial<'a, U>
@@ -282,12 +454,10 @@ where
         let usart2 = self.0;
         let sr = usart2.sr.read();
 
-        if sr.ore().bit_is_set() {
-            Err(nb::Error::Other(Error::Overrun))
-        } else if sr.nf().bit_is_set() {
-            Err(nb::Error::Other(Error::Noise))
-        } else if sr.fe().bit_is_set() {
-            Err(nb::Error::Other(Error::Framing))
+        // NOTE an error is reported once, then the flags are cleared and the
+        // corrupted byte is dropped
+        if let Some(error) = self.recover(&sr, true) {
+            Err(nb::Error::Other(error))
         } else if sr.rxne().bit_is_set() {
             // NOTE(read_volatile) the register is 9 bits big but we'll only
             // work with the first 8 bits
@@ -308,13 +478,11 @@ where
         let usart2 = self.0;
         let sr = usart2.sr.read();
 
-        if sr.ore().bit_is_set() {
-            Err(nb::Error::Other(Error::Overrun))
-        } else if sr.nf().bit_is_set() {
-            Err(nb::Error::Other(Error::Noise))
-        } else if sr.fe().bit_is_set() {
-            Err(nb::Error::Other(Error::Framing))
-        } else if sr.txe().bit_is_set() {
+        // NOTE receive errors are left for `read` to report and clear:
+        // clearing them takes a read of DR, which would drop received data,
+        // and reporting them here would block writes in the half duplex
+        // modes, where the transmitter's own echo can overrun the receiver
+        if sr.txe().bit_is_set() {
             // NOTE(write_volatile) see NOTE in the `read` method
             unsafe { ptr::write_volatile(&usart2.dr as *const _ as *mut u8, byte) }
             Ok(())
@@ -324,99 +492,383 @@ where
     }
 }
 
+// USART2_TX: DMA1 stream 6, channel 4 (RM0368 9.3.3 Table 27)
+pub(crate) fn tx_dma_config() -> dma::Config {
+    let mut config = dma::Config::new(dma::Channel::_4, dma::Direction::MemoryToPeripheral);
+    config.transfer_complete_interrupt = true;
+    config.transfer_error_interrupt = true;
+    config
+}
+
+// USART2_RX: DMA1 stream 5, channel 4; the ring buffer reception also needs
+// the half transfer interrupt
+fn rx_dma_config(circular: bool) -> dma::Config {
+    let mut config = dma::Config::new(dma::Channel::_4, dma::Direction::PeripheralToMemory);
+    config.circular = circular;
+    config.half_transfer_interrupt = circular;
+    config.transfer_complete_interrupt = true;
+    config.transfer_error_interrupt = true;
+    config
+}
+
 impl<'a> Serial<'a, USART2> {
     /// Starts a DMA transfer to receive serial data into a `buffer`
     ///
-    /// This will mutably lock the `buffer` preventing borrowing its contents
-    /// The `buffer` can be `release`d after the DMA transfer finishes
-    // TODO support circular mode + half transfer interrupt as a double
-    // buffering mode
+    /// The `buffer` and the `stream` are handed back by the returned
+    /// `Transfer` once the `buffer` is full. To be called back instead, hand
+    /// the `Transfer` to a `dma::Completion` serviced by the DMA1 stream 5
+    /// interrupt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA1 stream 5 is in use
     pub fn read_exact<B>(
         &self,
         dma1: &DMA1,
-        buffer: &Static<Buffer<B, Dma1Stream5>>,
-    ) -> ::core::result::Result<(), dma::Error>
+        mut stream: Dma1Stream5,
+        buffer: &'static mut B,
+    ) -> Transfer<&'static mut B, Dma1Stream5>
     where
         B: Unsize<[u8]>,
     {
         let usart2 = self.0;
 
-        if dma1.s5cr.read().en().bit_is_set() {
-            return Err(dma::Error::InUse);
-        }
+        assert!(!Dma1Stream5::is_enabled(dma1), "DMA stream in use");
 
-        let buffer: &mut [u8] = buffer.lock_mut();
+        let (address, len) = {
+            let slice: &[u8] = &*buffer;
+            (slice.as_ptr() as u32, u16(slice.len()).unwrap())
+        };
 
-        dma1.s5ndtr
-            .write(|w| unsafe { w.ndt().bits(u16(buffer.len()).unwrap()) });
-        dma1.s5par
-            .write(|w| unsafe { w.bits(&usart2.dr as *const _ as u32) });
-        dma1.s5m0ar
-            .write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
-        dma1.s5cr.modify(|_, w| w.en().set_bit());
+        stream.configure(dma1, &rx_dma_config(false));
 
-        Ok(())
+        let dr = &usart2.dr as *const _ as u32;
+
+        // NOTE(unsafe) the transfer covers the whole `'static` buffer
+        unsafe { Transfer::start(dma1, stream, buffer, dr, address, len) }
+    }
+
+    /// Starts continuously receiving serial data into the ring `buffer`
+    ///
+    /// The DMA runs in circular mode with the half transfer and transfer
+    /// complete interrupts enabled, and the USART IDLE interrupt is enabled
+    /// so that a frame shorter than half the ring is noticed as soon as the
+    /// line goes quiet. Call `RingBuffer::read` from those interrupts (after
+    /// `clear_idle` in the USART one) to get the received bytes, and
+    /// `RingBuffer::stop` to take the `buffer` and the `stream` back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA1 stream 5 is in use or if `buffer` is shorter than 2 or
+    /// longer than 65535 bytes
+    pub fn read_circ<B>(
+        &self,
+        dma1: &DMA1,
+        stream: Dma1Stream5,
+        buffer: &'static mut B,
+    ) -> RingBuffer<B, Dma1Stream5>
+    where
+        B: Unsize<[u8]>,
+    {
+        let usart2 = self.0;
+
+        self.clear_idle();
+        self.listen(Event::Idle);
+
+        RingBuffer::start(
+            dma1,
+            stream,
+            &rx_dma_config(true),
+            &usart2.dr as *const _ as u32,
+            buffer,
+        )
+    }
+
+    /// Starts continuously receiving serial data into two buffers that the
+    /// DMA fills alternately
+    ///
+    /// Each buffer is handed out by `DoubleBuffer::swap` as soon as it's
+    /// full, in exchange for a fresh one. Call `swap` from the DMA1 stream 5
+    /// (transfer complete) interrupt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if DMA1 stream 5 is in use
+    pub fn read_double_buffered<B>(
+        &self,
+        dma1: &DMA1,
+        stream: Dma1Stream5,
+        buffers: [&'static mut B; 2],
+    ) -> DoubleBuffer<B, Dma1Stream5>
+    where
+        B: Unsize<[u8]>,
+    {
+        let usart2 = self.0;
+
+        DoubleBuffer::start(
+            dma1,
+            stream,
+            &rx_dma_config(false),
+            &usart2.dr as *const _ as u32,
+            buffers,
+        )
     }
 
     /// Starts a DMA transfer to send `buffer` through this serial port
     ///
-    /// This will immutably lock the `buffer` preventing mutably borrowing its
-    /// contents. The `buffer` can be `release`d after the DMA transfer finishes
+    /// The `buffer` and the `stream` are handed back by the returned
+    /// `Transfer` once everything has been sent, or by a `dma::Completion`
+    /// serviced by the DMA1 stream 6 interrupt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is empty or if DMA1 stream 6 is in use
     pub fn write_all<B>(
         &self,
         dma1: &DMA1,
-        buffer: &Static<Buffer<B, Dma1Stream6>>,
-    ) -> ::core::result::Result<(), dma::Error>
+        stream: Dma1Stream6,
+        buffer: &'static mut B,
+    ) -> Transfer<&'static mut B, Dma1Stream6>
+    where
+        B: Unsize<[u8]>,
+    {
+        self._write(dma1, stream, buffer, None)
+    }
+
+    /// Like `write_all` but only sends the first `len` bytes of `buffer`
+    ///
+    /// Use it with the length reported by `U8Writer::len`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is 0 or larger than the `buffer`, or if DMA1 stream 6
+    /// is in use. A transfer of zero bytes would never complete.
+    pub fn write_prefix<B>(
+        &self,
+        dma1: &DMA1,
+        stream: Dma1Stream6,
+        buffer: &'static mut B,
+        len: usize,
+    ) -> Transfer<&'static mut B, Dma1Stream6>
+    where
+        B: Unsize<[u8]>,
+    {
+        self._write(dma1, stream, buffer, Some(len))
+    }
+
+    fn _write<B>(
+        &self,
+        dma1: &DMA1,
+        mut stream: Dma1Stream6,
+        buffer: &'static mut B,
+        len: Option<usize>,
+    ) -> Transfer<&'static mut B, Dma1Stream6>
     where
         B: Unsize<[u8]>,
     {
-        // write!(dma1, "hi {}", 1);
         let usart2 = self.0;
 
-        if dma1.s6cr.read().en().bit_is_set() {
-            return Err(dma::Error::InUse);
-        }
+        assert!(!Dma1Stream6::is_enabled(dma1), "DMA stream in use");
 
-        let buffer: &[u8] = buffer.lock();
+        let (address, len) = {
+            let slice: &[u8] = &*buffer;
+            let slice = match len {
+                Some(len) => &slice[..len],
+                None => slice,
+            };
+            (slice.as_ptr() as u32, u16(slice.len()).unwrap())
+        };
+        // NDTR = 0 doesn't start the stream
+        assert!(len != 0, "nothing to send");
 
-        dma1.s6ndtr
-            .write(|w| unsafe { w.ndt().bits(u16(buffer.len()).unwrap()) });
-        dma1.s6par
-            .write(|w| unsafe { w.bits(&usart2.dr as *const _ as u32) });
-        dma1.s6m0ar
-            .write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
-        dma1.s6cr.modify(|_, w| w.en().set_bit());
+        stream.configure(dma1, &tx_dma_config());
 
-        Ok(())
+        let dr = &usart2.dr as *const _ as u32;
+
+        // NOTE(unsafe) the transfer covers a prefix of the `'static` buffer
+        unsafe { Transfer::start(dma1, stream, buffer, dr, address, len) }
     }
 }
+
+/// Transmit buffer used by `uprint!`
 ///
+/// Holds the buffer and DMA1 stream 6 between messages and the `Transfer`
+/// while a message is being sent. Call `release` from the DMA1 stream 6
+/// interrupt to take them back.
+pub enum TxBuffer<B>
+where
+    B: 'static,
+{
+    /// Ready to be written
+    Idle(&'static mut B, Dma1Stream6),
+    /// Being sent
+    Busy(Transfer<&'static mut B, Dma1Stream6>),
+    /// Holds nothing; only seen if `send` or `release` panicked
+    Empty,
+}
+
+impl<B> TxBuffer<B>
+where
+    B: Unsize<[u8]>,
+{
+    /// Lets `f` write the message into the buffer and starts sending the
+    /// number of bytes `f` returns
+    ///
+    /// An empty message sends nothing and leaves the buffer idle. Returns
+    /// `InUse` if the previous message is still being sent.
+    pub fn send<F>(
+        &mut self,
+        serial: Serial<USART2>,
+        dma1: &DMA1,
+        f: F,
+    ) -> ::core::result::Result<(), dma::Error>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        match mem::replace(self, TxBuffer::Empty) {
+            TxBuffer::Idle(buffer, stream) => {
+                let len = f(&mut *buffer);
+                *self = if len == 0 {
+                    // `write_prefix` can't start a transfer of zero bytes
+                    TxBuffer::Idle(buffer, stream)
+                } else {
+                    TxBuffer::Busy(serial.write_prefix(dma1, stream, buffer, len))
+                };
+                Ok(())
+            }
+            state => {
+                *self = state;
+                Err(dma::Error::InUse)
+            }
+        }
+    }
+
+    /// Takes the buffer back once the message has been sent
+    pub fn release(&mut self, dma1: &DMA1) -> nb::Result<(), dma::Error> {
+        match mem::replace(self, TxBuffer::Empty) {
+            TxBuffer::Busy(transfer) => {
+                let result = match transfer.is_done(dma1) {
+                    Ok(false) => {
+                        *self = TxBuffer::Busy(transfer);
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Ok(true) => Ok(()),
+                    Err(e) => Err(nb::Error::Other(e)),
+                };
+
+                let (buffer, stream) = transfer.wait(dma1);
+                *self = TxBuffer::Idle(buffer, stream);
+
+                result
+            }
+            state => {
+                *self = state;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What `U8Writer` does with text that doesn't fit in its buffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    /// Keep what fits (cut at a character boundary) and drop the rest
+    Truncate,
+    /// Drop the whole piece of text that doesn't fit and return `fmt::Error`
+    Error,
+}
+
+/// Formats text into a byte buffer
+///
+/// The writer never writes past the end of the buffer. `len` reports how
+/// many bytes were written and `is_truncated` whether some text was dropped.
+/// Once text has been dropped nothing else is written until `reset`, so the
+/// buffer always holds a prefix of the formatted output.
 pub struct U8Writer<'a> {
     buf: &'a mut [u8],
     offset: usize,
+    overflow: Overflow,
+    truncated: bool,
 }
 
 impl<'a> U8Writer<'a> {
-    ///
+    /// Creates a writer that truncates the text that doesn't fit in `buf`
     pub fn new(buf: &'a mut [u8]) -> Self {
+        U8Writer::with_overflow(buf, Overflow::Truncate)
+    }
+
+    /// Creates a writer with the given `overflow` behavior
+    pub fn with_overflow(buf: &'a mut [u8], overflow: Overflow) -> Self {
         U8Writer {
             buf: buf,
             offset: 0,
+            overflow: overflow,
+            truncated: false,
         }
     }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.offset
+    }
+
+    /// `true` if nothing has been written
+    pub fn is_empty(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Size of the buffer
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// `true` if some text didn't fit in the buffer
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The bytes written so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.offset]
+    }
+
+    /// Empties the writer so that the buffer can be reused
+    pub fn reset(&mut self) {
+        self.offset = 0;
+        self.truncated = false;
+    }
 }
 
 impl<'a> fmt::Write for U8Writer<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let bytes = s.as_bytes();
-        // Skip over already-copied data
-        let remainder = &mut self.buf[self.offset..];
-        // Make the two slices the same length
-        let remainder = &mut remainder[..bytes.len()];
-        // Copy
-        remainder.copy_from_slice(bytes);
-        // Increment offset by number of copied bytes
-        self.offset += bytes.len();
+        if self.truncated {
+            return match self.overflow {
+                Overflow::Truncate => Ok(()),
+                Overflow::Error => Err(fmt::Error),
+            };
+        }
+
+        let free = self.buf.len() - self.offset;
+        let n = if s.len() <= free {
+            s.len()
+        } else {
+            self.truncated = true;
+
+            if self.overflow == Overflow::Error {
+                return Err(fmt::Error);
+            }
+
+            // don't split a multi-byte character
+            let mut n = free;
+            while !s.is_char_boundary(n) {
+                n -= 1;
+            }
+            n
+        };
+
+        self.buf[self.offset..self.offset + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.offset += n;
+
         Ok(())
     }
 }
@@ -424,41 +876,50 @@ impl<'a> fmt::Write for U8Writer<'a> {
 /// Macro for printing formatted strings over serial through DMA.
 /// Uses the cortex-m-rtfm resource model and can thus not be used
 /// outside rtfm tasks.
+///
+/// The transmit buffer is a `TxBuffer` resource. Text that doesn't fit in it
+/// is truncated; only the written part of the buffer is sent.
 #[macro_export]
 macro_rules! uprint {
-    ($T:ident, $USART:expr, $DMA:expr, $TX_BUFFER:expr) => {
-        // Transmit the contents of the buffer using DMA
-        $TX_BUFFER.claim($T, |tx, t| {
-            $DMA.claim(t, |dma, t| {
-                $USART.claim(t, |usart, _| {
+    ($T:ident, $USART:expr, $DMA:expr, $TX_BUFFER:expr) => {{
+        use rtfm::{Resource};
+        // The resources are borrowed separately because the transmit buffer
+        // is claimed mutably while the others are claimed
+        let tx_buffer = &mut $TX_BUFFER;
+        let dma = &$DMA;
+        let usart = &$USART;
+        // Transmit the whole buffer using DMA
+        tx_buffer.claim_mut($T, |tx, t| {
+            dma.claim(t, |dma, t| {
+                usart.claim(t, |usart, _| {
                     let serial = Serial(&**usart);
-                    serial.write_all(dma, tx).unwrap();
+                    tx.send(serial, dma, |buf| buf.len()).unwrap();
                 });
             });
         });
-    };
-    ($T:ident, $USART:expr, $DMA:expr, $TX_BUFFER:expr, $s:expr) => {
+    }};
+    ($T:ident, $USART:expr, $DMA:expr, $TX_BUFFER:expr, $($arg:tt)* ) => {{
         use rtfm::{Resource};
         use core::fmt::Write;
         use f4::U8Writer;
-        // Claim the transmit buffer and write a string literal into it
-        $TX_BUFFER.claim_mut($T, |tx, _| {
-            let len = (*tx).deref().borrow().len();
-            let buf = &mut (*tx).deref().borrow_mut();
-            write!(U8Writer::new(&mut buf[..len]), $s).unwrap();
-        });
-        uprint!($T, $USART, $DMA, $TX_BUFFER);
-    };
-    ($T:ident, $USART:expr, $DMA:expr, $TX_BUFFER:expr, $($arg:tt)* ) => {
-        use rtfm::{Resource};
-        use core::fmt::Write;
-        use f4::U8Writer;
-        // Claim the transmit buffer and write a formatted string into it
-        $TX_BUFFER.claim_mut($T, |tx, _| {
-            let len = (*tx).deref().borrow().len();
-            let buf = &mut (*tx).deref().borrow_mut();
-            write!(U8Writer::new(&mut buf[..len]), $($arg)*).unwrap();
+        // Borrowed separately, see above
+        let tx_buffer = &mut $TX_BUFFER;
+        let dma = &$DMA;
+        let usart = &$USART;
+        // Write a formatted string into the transmit buffer and transmit the
+        // written part using DMA
+        tx_buffer.claim_mut($T, |tx, t| {
+            dma.claim(t, |dma, t| {
+                usart.claim(t, |usart, _| {
+                    let serial = Serial(&**usart);
+                    tx.send(serial, dma, |buf| {
+                        let mut writer = U8Writer::new(buf);
+                        // NOTE(ok) can't fail, the text is truncated
+                        write!(writer, $($arg)*).ok();
+                        writer.len()
+                    }).unwrap();
+                });
+            });
         });
-        uprint!($T, $USART, $DMA, $TX_BUFFER);
-    };
+    }};
 }
@@ -0,0 +1,703 @@
+//! Command line shell
+//!
+//! A small `no_std` shell to drive the board from a terminal emulator:
+//!
+//! - `LineEditor` turns the received bytes into lines, handling backspace
+//!   and the up/down arrow keys to browse the last `HISTORY` lines.
+//! - `tokenize` splits a line into whitespace separated arguments. Single and
+//!   double quotes group words and `\` escapes the next character.
+//! - `Args` parses the arguments as integers, floats or hex numbers.
+//! - `Shell` looks the first argument up in a static table of `Command`s and
+//!   runs its handler. The built-in `help` command lists the table.
+//!
+//! All output goes to a `core::fmt::Write` sink, and nothing here touches a
+//! peripheral, so the shell can be fed bytes on the host as well.
+//!
+//! ``` ignore
+//! fn led(_: &mut (), args: &Args, out: &mut fmt::Write) -> Result<(), Error> {
+//!     match args.str(1)? {
+//!         "on" => LED.on(),
+//!         "off" => LED.off(),
+//!         _ => return Err(Error::InvalidArgument(1)),
+//!     }
+//!     Ok(())
+//! }
+//!
+//! static COMMANDS: &[Command<()>] = &[Command {
+//!     name: "led",
+//!     help: "led on|off - switch the user LED",
+//!     handler: led,
+//! }];
+//!
+//! static mut SHELL: Shell<()> = Shell::new("> ", COMMANDS);
+//!
+//! // for every received byte
+//! SHELL.feed(byte, &mut (), &mut serial_writer);
+//! ```
+
+use core::fmt;
+use core::str;
+
+/// Maximum length of a line, in bytes
+pub const MAX_LINE: usize = 64;
+/// Maximum number of arguments in a line, including the command name
+pub const MAX_ARGS: usize = 8;
+/// Number of lines kept in the history
+pub const HISTORY: usize = 4;
+
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7f;
+const ESC: u8 = 0x1b;
+
+/// Shell error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The line has more than `MAX_ARGS` arguments
+    TooManyArgs,
+    /// A quote was opened but never closed
+    UnterminatedQuote,
+    /// No command with that name in the table
+    UnknownCommand,
+    /// The argument at this index is missing
+    MissingArgument(usize),
+    /// The argument at this index couldn't be parsed
+    InvalidArgument(usize),
+    /// The sink returned an error
+    Fmt,
+    #[doc(hidden)] _Extensible,
+}
+
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Error {
+        Error::Fmt
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooManyArgs => f.write_str("too many arguments"),
+            Error::UnterminatedQuote => f.write_str("unterminated quote"),
+            Error::UnknownCommand => f.write_str("unknown command, try `help`"),
+            Error::MissingArgument(i) => write!(f, "missing argument {}", i),
+            Error::InvalidArgument(i) => write!(f, "invalid argument {}", i),
+            Error::Fmt => f.write_str("output error"),
+            Error::_Extensible => Ok(()),
+        }
+    }
+}
+
+/// Arguments of a command line; argument 0 is the command name
+pub struct Args<'a> {
+    line: &'a [u8],
+    spans: [(usize, usize); MAX_ARGS],
+    len: usize,
+}
+
+impl<'a> Args<'a> {
+    /// Number of arguments, including the command name
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// `true` if the line was empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Argument `i`, if present
+    pub fn get(&self, i: usize) -> Option<&'a str> {
+        if i < self.len {
+            let (start, end) = self.spans[i];
+            // NOTE(unsafe) `tokenize` only splits at ASCII characters so the
+            // span is as valid UTF-8 as the line, which was checked
+            Some(unsafe { str::from_utf8_unchecked(&self.line[start..end]) })
+        } else {
+            None
+        }
+    }
+
+    /// Argument `i` as a string
+    pub fn str(&self, i: usize) -> Result<&'a str, Error> {
+        self.get(i).ok_or(Error::MissingArgument(i))
+    }
+
+    /// Argument `i` as a signed decimal integer; a `0x` prefix selects hex
+    pub fn int(&self, i: usize) -> Result<i32, Error> {
+        let s = self.str(i)?;
+        let (negative, digits) = if s.starts_with('-') {
+            (true, &s[1..])
+        } else {
+            (false, s)
+        };
+
+        if digits.starts_with("0x") || digits.starts_with("0X") {
+            let value =
+                i32::from_str_radix(&digits[2..], 16).map_err(|_| Error::InvalidArgument(i))?;
+            Ok(if negative { -value } else { value })
+        } else {
+            i32::from_str_radix(s, 10).map_err(|_| Error::InvalidArgument(i))
+        }
+    }
+
+    /// Argument `i` as an unsigned hexadecimal number, with or without a
+    /// `0x` prefix
+    pub fn hex(&self, i: usize) -> Result<u32, Error> {
+        let s = self.str(i)?;
+        let digits = if s.starts_with("0x") || s.starts_with("0X") {
+            &s[2..]
+        } else {
+            s
+        };
+
+        u32::from_str_radix(digits, 16).map_err(|_| Error::InvalidArgument(i))
+    }
+
+    /// Argument `i` as a floating point number
+    pub fn float(&self, i: usize) -> Result<f32, Error> {
+        self.str(i)?
+            .parse::<f32>()
+            .map_err(|_| Error::InvalidArgument(i))
+    }
+}
+
+/// Splits `line` into arguments
+///
+/// Quotes and escapes are removed in place, which is why `line` must be
+/// mutable.
+pub fn tokenize(line: &mut [u8]) -> Result<Args, Error> {
+    let mut spans = [(0, 0); MAX_ARGS];
+    let mut len = 0;
+
+    // `read` scans the input, `write` is where unquoted bytes are copied to
+    let mut read = 0;
+    let mut write = 0;
+
+    loop {
+        while read < line.len() && (line[read] == b' ' || line[read] == b'\t') {
+            read += 1;
+        }
+
+        if read == line.len() {
+            break;
+        }
+
+        if len == MAX_ARGS {
+            return Err(Error::TooManyArgs);
+        }
+
+        let start = write;
+        let mut quote = None;
+
+        while read < line.len() {
+            let byte = line[read];
+            read += 1;
+
+            match (quote, byte) {
+                (None, b' ') | (None, b'\t') => break,
+                (None, b'"') | (None, b'\'') => quote = Some(byte),
+                (Some(q), _) if q == byte => quote = None,
+                (Some(b'\''), _) => {
+                    line[write] = byte;
+                    write += 1;
+                }
+                (_, b'\\') if read < line.len() => {
+                    line[write] = line[read];
+                    write += 1;
+                    read += 1;
+                }
+                _ => {
+                    line[write] = byte;
+                    write += 1;
+                }
+            }
+        }
+
+        if quote.is_some() {
+            return Err(Error::UnterminatedQuote);
+        }
+
+        spans[len] = (start, write);
+        len += 1;
+    }
+
+    Ok(Args {
+        line: line,
+        spans: spans,
+        len: len,
+    })
+}
+
+/// An entry of the command table
+pub struct Command<C> {
+    /// Name the command is invoked with
+    pub name: &'static str,
+    /// One line description shown by `help`
+    pub help: &'static str,
+    /// Runs the command; `C` is application state shared by all commands
+    pub handler: fn(&mut C, &Args, &mut fmt::Write) -> Result<(), Error>,
+}
+
+/// Runs the command in `line`
+///
+/// `help` is handled here and lists every command in `commands`
+pub fn dispatch<C>(
+    commands: &[Command<C>],
+    context: &mut C,
+    line: &mut [u8],
+    out: &mut fmt::Write,
+) -> Result<(), Error> {
+    let args = tokenize(line)?;
+
+    let name = match args.get(0) {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+
+    if name == "help" {
+        for command in commands {
+            writeln!(out, "{:10} {}\r", command.name, command.help)?;
+        }
+        return writeln!(out, "{:10} {}\r", "help", "list the commands").map_err(Error::from);
+    }
+
+    match commands.iter().find(|c| c.name == name) {
+        Some(command) => (command.handler)(context, &args, out),
+        None => Err(Error::UnknownCommand),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Escape {
+    None,
+    // got ESC
+    Esc,
+    // got ESC [ and maybe parameter or intermediate bytes
+    Csi,
+    // got ESC O
+    Ss3,
+}
+
+/// Line editor with backspace and history
+pub struct LineEditor {
+    prompt: &'static str,
+    line: [u8; MAX_LINE],
+    len: usize,
+    history: [[u8; MAX_LINE]; HISTORY],
+    history_len: [usize; HISTORY],
+    // number of valid history entries
+    history_count: usize,
+    // next history slot to write
+    history_next: usize,
+    // how far back we are browsing, 0 = not browsing
+    browse: usize,
+    escape: Escape,
+    // the previous byte was `\r`
+    cr: bool,
+}
+
+impl LineEditor {
+    /// Creates an editor that shows `prompt` in front of every line
+    pub const fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt: prompt,
+            line: [0; MAX_LINE],
+            len: 0,
+            history: [[0; MAX_LINE]; HISTORY],
+            history_len: [0; HISTORY],
+            history_count: 0,
+            history_next: 0,
+            browse: 0,
+            escape: Escape::None,
+            cr: false,
+        }
+    }
+
+    /// Prints the prompt
+    pub fn prompt(&self, out: &mut fmt::Write) -> fmt::Result {
+        out.write_str(self.prompt)
+    }
+
+    /// Feeds a received `byte` to the editor, echoing it to `out`
+    ///
+    /// Returns the line once `\r`, `\n` or `\r\n` is received. The returned
+    /// line can be modified in place (see `tokenize`) and is discarded on the
+    /// next call.
+    pub fn feed(&mut self, byte: u8, out: &mut fmt::Write) -> Result<Option<&mut [u8]>, Error> {
+        let cr = self.cr;
+        self.cr = byte == b'\r';
+
+        match (self.escape, byte) {
+            (Escape::None, ESC) => {
+                self.escape = Escape::Esc;
+                return Ok(None);
+            }
+            (Escape::Esc, b'[') => {
+                self.escape = Escape::Csi;
+                return Ok(None);
+            }
+            (Escape::Esc, b'O') => {
+                self.escape = Escape::Ss3;
+                return Ok(None);
+            }
+            (Escape::Csi, b'A') | (Escape::Ss3, b'A') => {
+                self.escape = Escape::None;
+                self.browse_history(true, out)?;
+                return Ok(None);
+            }
+            (Escape::Csi, b'B') | (Escape::Ss3, b'B') => {
+                self.escape = Escape::None;
+                self.browse_history(false, out)?;
+                return Ok(None);
+            }
+            // CSI parameter and intermediate bytes, e.g. `ESC [ 3 ~`
+            (Escape::Csi, 0x20...0x3f) => return Ok(None),
+            (Escape::None, _) => {}
+            // the final byte of an unsupported escape sequence; drop it
+            _ => {
+                self.escape = Escape::None;
+                return Ok(None);
+            }
+        }
+
+        match byte {
+            // second half of a CRLF line ending
+            b'\n' if cr => Ok(None),
+            b'\r' | b'\n' => {
+                out.write_str("\r\n")?;
+
+                let len = self.len;
+                self.len = 0;
+                self.browse = 0;
+
+                if len == 0 {
+                    // keep the prompt coming for empty lines
+                    self.prompt(out)?;
+                    return Ok(None);
+                }
+
+                if str::from_utf8(&self.line[..len]).is_err() {
+                    self.prompt(out)?;
+                    return Ok(None);
+                }
+
+                self.push_history(len);
+
+                Ok(Some(&mut self.line[..len]))
+            }
+            BS | DEL => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    out.write_str("\x08 \x08")?;
+                }
+                Ok(None)
+            }
+            0x20...0x7e => {
+                if self.len < MAX_LINE {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    // NOTE(unsafe) printable ASCII
+                    out.write_str(unsafe { str::from_utf8_unchecked(&[byte]) })?;
+                }
+                Ok(None)
+            }
+            // other control characters and non ASCII input are ignored
+            _ => Ok(None),
+        }
+    }
+
+    fn push_history(&mut self, len: usize) {
+        // don't store the same line twice in a row
+        if self.history_count > 0 {
+            let last = (self.history_next + HISTORY - 1) % HISTORY;
+            if self.history[last][..self.history_len[last]] == self.line[..len] {
+                return;
+            }
+        }
+
+        let slot = self.history_next;
+        self.history[slot][..len].copy_from_slice(&self.line[..len]);
+        self.history_len[slot] = len;
+        self.history_next = (slot + 1) % HISTORY;
+        if self.history_count < HISTORY {
+            self.history_count += 1;
+        }
+    }
+
+    fn browse_history(&mut self, older: bool, out: &mut fmt::Write) -> fmt::Result {
+        if older {
+            if self.browse == self.history_count {
+                return Ok(());
+            }
+            self.browse += 1;
+        } else {
+            if self.browse == 0 {
+                return Ok(());
+            }
+            self.browse -= 1;
+        }
+
+        if self.browse == 0 {
+            self.len = 0;
+        } else {
+            let slot = (self.history_next + HISTORY - self.browse) % HISTORY;
+            let len = self.history_len[slot];
+            self.line[..len].copy_from_slice(&self.history[slot][..len]);
+            self.len = len;
+        }
+
+        // erase the current line and redraw it
+        out.write_str("\r\x1b[K")?;
+        self.prompt(out)?;
+        // NOTE(unsafe) the history only holds lines that were valid UTF-8
+        out.write_str(unsafe { str::from_utf8_unchecked(&self.line[..self.len]) })
+    }
+}
+
+/// A line editor attached to a command table
+pub struct Shell<C>
+where
+    C: 'static,
+{
+    editor: LineEditor,
+    commands: &'static [Command<C>],
+}
+
+impl<C> Shell<C>
+where
+    C: 'static,
+{
+    /// Creates a shell that runs the `commands`
+    pub const fn new(prompt: &'static str, commands: &'static [Command<C>]) -> Self {
+        Shell {
+            editor: LineEditor::new(prompt),
+            commands: commands,
+        }
+    }
+
+    /// Prints the prompt
+    pub fn prompt(&self, out: &mut fmt::Write) -> fmt::Result {
+        self.editor.prompt(out)
+    }
+
+    /// Feeds a received `byte` to the shell
+    ///
+    /// When a line is complete its command is run, errors are reported on
+    /// `out`, and a new prompt is printed. Only errors of the sink itself are
+    /// returned.
+    pub fn feed(&mut self, byte: u8, context: &mut C, out: &mut fmt::Write) -> fmt::Result {
+        let result = match self.editor.feed(byte, out) {
+            Ok(None) => return Ok(()),
+            Ok(Some(line)) => dispatch(self.commands, context, line, out),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(Error::Fmt) => return Err(fmt::Error),
+            Err(e) => writeln!(out, "error: {}\r", e)?,
+        }
+
+        self.editor.prompt(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    // Feeds `input` and returns the lines that were completed
+    fn lines(editor: &mut LineEditor, input: &[u8], out: &mut String) -> Vec<String> {
+        let mut lines = Vec::new();
+        for byte in input {
+            if let Some(line) = editor.feed(*byte, out).unwrap() {
+                lines.push(String::from(str::from_utf8(line).unwrap()));
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn line_endings() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        assert_eq!(
+            lines(&mut editor, b"a\rb\nc\r\n", &mut out),
+            ["a", "b", "c"]
+        );
+        // CRLF is a single line ending: no empty line and no extra prompt
+        assert_eq!(out, "a\r\nb\r\nc\r\n");
+
+        // two CRs are an empty line
+        let mut out = String::new();
+        assert!(lines(&mut editor, b"\r\r", &mut out).is_empty());
+        assert_eq!(out, "\r\n> \r\n> ");
+    }
+
+    #[test]
+    fn backspace() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        assert_eq!(
+            lines(&mut editor, b"lx\x08ed ofx\x7f\x7ff\r", &mut out),
+            ["led of"]
+        );
+        // backspace on an empty line does nothing
+        assert_eq!(lines(&mut editor, b"\x08\x08ok\r", &mut out), ["ok"]);
+    }
+
+    #[test]
+    fn history() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        lines(&mut editor, b"one\rtwo\rtwo\r", &mut out);
+
+        // up twice: duplicates are stored once
+        assert_eq!(lines(&mut editor, b"\x1b[A\x1b[A\r", &mut out), ["one"]);
+        // a recalled line becomes the newest entry: one, two, one
+        assert_eq!(
+            lines(&mut editor, b"\x1b[A\x1b[A\x1b[A\x1b[B\r", &mut out),
+            ["two"]
+        );
+        // past the oldest entry of one, two, one, two
+        assert_eq!(
+            lines(&mut editor, b"\x1b[A\x1b[A\x1b[A\x1b[A\x1b[A\r", &mut out),
+            ["one"]
+        );
+        // back down to an empty line
+        assert!(lines(&mut editor, b"\x1b[A\x1b[B\r", &mut out).is_empty());
+        // application cursor mode
+        assert_eq!(lines(&mut editor, b"\x1bOA\r", &mut out), ["one"]);
+
+        // the redrawn line is erased first
+        let mut out = String::new();
+        lines(&mut editor, b"\x1b[A", &mut out);
+        assert_eq!(out, "\r\x1b[K> one");
+    }
+
+    #[test]
+    fn history_limit() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        for i in 0..HISTORY + 2 {
+            lines(&mut editor, format!("{}\r", i).as_bytes(), &mut out);
+        }
+
+        let mut up = Vec::new();
+        for _ in 0..HISTORY + 2 {
+            up.extend_from_slice(b"\x1b[A");
+        }
+        up.push(b'\r');
+        assert_eq!(lines(&mut editor, &up, &mut out), ["2"]);
+    }
+
+    #[test]
+    fn unknown_escapes() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        // Delete, Home with parameters, right arrow, ctrl-up, ESC x
+        let input = b"a\x1b[3~b\x1b[1;5Hc\x1b[Cd\x1b[1;5Ae\x1bxf\r";
+        assert_eq!(lines(&mut editor, input, &mut out), ["abcdef"]);
+    }
+
+    #[test]
+    fn invalid_utf8_and_long_lines() {
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        // non ASCII bytes are ignored
+        assert_eq!(lines(&mut editor, b"a\xc3\xa9b\r", &mut out), ["ab"]);
+
+        let mut long = [b'x'; MAX_LINE + 10].to_vec();
+        long.push(b'\r');
+        assert_eq!(lines(&mut editor, &long, &mut out)[0].len(), MAX_LINE);
+    }
+
+    #[test]
+    fn tokens() {
+        let mut line = *b"  set 'a b' \"c\\\"d\" e\\ f  ";
+        let args = tokenize(&mut line).unwrap();
+
+        assert_eq!(args.len(), 4);
+        assert_eq!(args.get(0), Some("set"));
+        assert_eq!(args.get(1), Some("a b"));
+        assert_eq!(args.get(2), Some("c\"d"));
+        assert_eq!(args.get(3), Some("e f"));
+        assert_eq!(args.get(4), None);
+
+        assert!(tokenize(&mut []).unwrap().is_empty());
+        assert_eq!(
+            tokenize(&mut b"a 'b".to_vec()).err(),
+            Some(Error::UnterminatedQuote)
+        );
+        assert_eq!(
+            tokenize(&mut b"1 2 3 4 5 6 7 8 9".to_vec()).err(),
+            Some(Error::TooManyArgs)
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        let mut line = *b"cmd -12 0x1f -0x10 ff 1.5 x";
+        let args = tokenize(&mut line).unwrap();
+
+        assert_eq!(args.int(1), Ok(-12));
+        assert_eq!(args.int(2), Ok(31));
+        assert_eq!(args.int(3), Ok(-16));
+        assert_eq!(args.hex(4), Ok(0xff));
+        assert_eq!(args.hex(2), Ok(0x1f));
+        assert_eq!(args.float(5), Ok(1.5));
+        assert_eq!(args.int(6), Err(Error::InvalidArgument(6)));
+        assert_eq!(args.int(7), Err(Error::MissingArgument(7)));
+    }
+
+    fn add(total: &mut i32, args: &Args, out: &mut fmt::Write) -> Result<(), Error> {
+        *total += args.int(1)?;
+        write!(out, "{}", total)?;
+        Ok(())
+    }
+
+    static COMMANDS: &[Command<i32>] = &[Command {
+        name: "add",
+        help: "add <n>",
+        handler: add,
+    }];
+
+    #[test]
+    fn shell() {
+        let mut shell = Shell::new("> ", COMMANDS);
+        let mut total = 0;
+
+        let mut out = String::new();
+        for byte in b"add 2\r\nadd 0x10\r\n" {
+            shell.feed(*byte, &mut total, &mut out).unwrap();
+        }
+        assert_eq!(total, 18);
+        assert_eq!(out, "add 2\r\n2> add 0x10\r\n18> ");
+
+        let mut out = String::new();
+        for byte in b"sub 1\radd\r" {
+            shell.feed(*byte, &mut total, &mut out).unwrap();
+        }
+        assert_eq!(
+            out,
+            "sub 1\r\nerror: unknown command, try `help`\r\n> \
+             add\r\nerror: missing argument 1\r\n> "
+        );
+
+        let mut out = String::new();
+        for byte in b"help\r" {
+            shell.feed(*byte, &mut total, &mut out).unwrap();
+        }
+        assert!(out.contains("add        add <n>\r\n"));
+        assert!(out.contains("help       list the commands\r\n"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Lock-free single producer single consumer queue
+//!
+//! The queue is meant to pass data between an interrupt handler and the rest
+//! of the application without disabling interrupts. It's split into a
+//! `Producer` and a `Consumer` end which can live in different execution
+//! contexts; each end can only be used from one context at a time.
+//!
+//! The storage `B` is statically allocated by the caller (usually an array)
+//! and one of its slots is always kept free, so a `[T; N]` buffer holds at
+//! most `N - 1` elements (none if `N` is 0 or 1).
+
+use core::cell::UnsafeCell;
+use core::marker::{PhantomData, Unsize};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A single producer single consumer queue backed by the array `B`
+pub struct Queue<T, B>
+where
+    B: Unsize<[T]>,
+{
+    // index of the next element to dequeue; only written by the consumer
+    head: AtomicUsize,
+    // index of the next free slot; only written by the producer
+    tail: AtomicUsize,
+    buffer: UnsafeCell<B>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T, B> Sync for Queue<T, B>
+where
+    T: Send,
+    B: Unsize<[T]>,
+{
+}
+
+impl<T, B> Queue<T, B>
+where
+    T: Copy,
+    B: Unsize<[T]>,
+{
+    /// Creates an empty queue that uses `buffer` as storage
+    pub const fn new(buffer: B) -> Self {
+        Queue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: UnsafeCell::new(buffer),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Maximum number of elements the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.slots().saturating_sub(1)
+    }
+
+    /// Number of elements in the queue
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if tail >= head {
+            tail - head
+        } else {
+            self.slots() - head + tail
+        }
+    }
+
+    /// `true` if the queue contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    /// Splits the queue into its producer and consumer ends
+    pub fn split(&mut self) -> (Producer<T, B>, Consumer<T, B>) {
+        (
+            Producer {
+                queue: self,
+                _not_sync: PhantomData,
+            },
+            Consumer {
+                queue: self,
+                _not_sync: PhantomData,
+            },
+        )
+    }
+
+    fn slots(&self) -> usize {
+        let buffer: &[T] = unsafe { &*self.buffer.get() };
+        buffer.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        let buffer: *mut [T] = self.buffer.get();
+        unsafe { (buffer as *mut T).offset(index as isize) }
+    }
+
+    // NOTE(unsafe) must only be called from the producer end
+    unsafe fn enqueue(&self, item: T) -> Result<(), T> {
+        if self.slots() == 0 {
+            return Err(item);
+        }
+
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % self.slots();
+
+        if next == self.head.load(Ordering::Acquire) {
+            return Err(item);
+        }
+
+        ptr::write(self.slot(tail), item);
+        self.tail.store(next, Ordering::Release);
+
+        Ok(())
+    }
+
+    // NOTE(unsafe) must only be called from the consumer end
+    unsafe fn dequeue(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let item = ptr::read(self.slot(head));
+        self.head.store((head + 1) % self.slots(), Ordering::Release);
+
+        Some(item)
+    }
+}
+
+/// The producer end of a `Queue`
+pub struct Producer<'a, T, B>
+where
+    T: 'a,
+    B: 'a + Unsize<[T]>,
+{
+    queue: &'a Queue<T, B>,
+    // can be moved to another context but not shared between contexts
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, T, B> Send for Producer<'a, T, B>
+where
+    T: Send,
+    B: Unsize<[T]>,
+{
+}
+
+impl<'a, T, B> Producer<'a, T, B>
+where
+    T: Copy,
+    B: Unsize<[T]>,
+{
+    /// Adds an `item` to the end of the queue
+    ///
+    /// Returns the `item` back if the queue is full
+    pub fn enqueue(&self, item: T) -> Result<(), T> {
+        unsafe { self.queue.enqueue(item) }
+    }
+
+    /// Number of elements that can be enqueued right now
+    pub fn free(&self) -> usize {
+        self.queue.capacity() - self.queue.len()
+    }
+
+    /// Maximum number of elements the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity()
+    }
+}
+
+/// The consumer end of a `Queue`
+pub struct Consumer<'a, T, B>
+where
+    T: 'a,
+    B: 'a + Unsize<[T]>,
+{
+    queue: &'a Queue<T, B>,
+    // can be moved to another context but not shared between contexts
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, T, B> Send for Consumer<'a, T, B>
+where
+    T: Send,
+    B: Unsize<[T]>,
+{
+}
+
+impl<'a, T, B> Consumer<'a, T, B>
+where
+    T: Copy,
+    B: Unsize<[T]>,
+{
+    /// Removes the item at the front of the queue, if any
+    pub fn dequeue(&self) -> Option<T> {
+        unsafe { self.queue.dequeue() }
+    }
+
+    /// Number of elements waiting in the queue
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// `true` if the queue contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let mut queue: Queue<u8, [u8; 4]> = Queue::new([0; 4]);
+        let (producer, consumer) = queue.split();
+
+        assert_eq!(producer.capacity(), 3);
+        assert!(consumer.is_empty());
+
+        producer.enqueue(1).unwrap();
+        producer.enqueue(2).unwrap();
+        producer.enqueue(3).unwrap();
+        assert_eq!(producer.enqueue(4), Err(4));
+        assert_eq!(producer.free(), 0);
+        assert_eq!(consumer.len(), 3);
+
+        assert_eq!(consumer.dequeue(), Some(1));
+        producer.enqueue(4).unwrap();
+        assert_eq!(consumer.dequeue(), Some(2));
+        assert_eq!(consumer.dequeue(), Some(3));
+        assert_eq!(consumer.dequeue(), Some(4));
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let mut queue: Queue<u8, [u8; 0]> = Queue::new([]);
+        let (producer, consumer) = queue.split();
+
+        assert_eq!(producer.capacity(), 0);
+        assert_eq!(producer.free(), 0);
+        assert_eq!(producer.enqueue(1), Err(1));
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    #[test]
+    fn single_slot() {
+        let mut queue: Queue<u8, [u8; 1]> = Queue::new([0]);
+        let (producer, consumer) = queue.split();
+
+        assert_eq!(producer.capacity(), 0);
+        assert_eq!(producer.enqueue(1), Err(1));
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    // Producer and consumer on two threads spinning on a small queue: every
+    // item must come out once, in order
+    #[test]
+    fn concurrent() {
+        const N: u32 = 2_000_000;
+
+        let queue: &'static mut Queue<u32, [u32; 8]> =
+            Box::leak(Box::new(Queue::new([0; 8])));
+        let (producer, consumer) = queue.split();
+
+        let sender = thread::spawn(move || {
+            for i in 0..N {
+                let mut item = i;
+                while let Err(back) = producer.enqueue(item) {
+                    item = back;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < N {
+            match consumer.dequeue() {
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+
+        sender.join().unwrap();
+        assert_eq!(consumer.dequeue(), None);
+    }
+}
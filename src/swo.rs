@@ -0,0 +1,458 @@
+//! Decoder for the ITM packet stream captured from the SWO pin
+//!
+//! The decoder doesn't depend on any peripheral and is meant to run on the
+//! host, e.g. on a capture saved with OpenOCD's `tpiu config internal`. It
+//! expects the TPIU formatter to be bypassed, as configured by `itm::init`.
+//!
+//! `Decoder` turns bytes into `Packet`s; `Lines` reassembles the text sent to
+//! one stimulus port into lines.
+//!
+//! Packet formats: ARMv7-M Architecture Reference Manual, Appendix D4
+//! (Debug ITM and DWT Packet Protocol).
+
+use core::marker::Unsize;
+
+/// What an exception trace packet reports
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExceptionAction {
+    /// The exception was entered
+    Entered,
+    /// The exception handler returned
+    Exited,
+    /// Execution returned to a preempted exception
+    Returned,
+}
+
+/// A decoded packet
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Packet {
+    /// Synchronization packet
+    Sync,
+    /// The ITM FIFO overflowed, packets were lost
+    Overflow,
+    /// Data written to a stimulus port; only the first `len` bytes of
+    /// `payload` are valid (little endian, as written)
+    Instrumentation {
+        /// Stimulus port
+        port: u8,
+        /// Data
+        payload: [u8; 4],
+        /// 1, 2 or 4
+        len: u8,
+    },
+    /// Local timestamp: core cycles since the previous timestamp
+    Timestamp {
+        /// Elapsed time
+        delta: u32,
+        /// Timestamp control: 0 = in sync with the data, other values mean
+        /// the timestamp or the data were delayed
+        control: u8,
+    },
+    /// DWT exception trace
+    Exception {
+        /// Exception number (16 + IRQ number for interrupts)
+        number: u16,
+        /// What happened
+        action: ExceptionAction,
+    },
+    /// DWT periodic PC sample; `None` when the core was sleeping
+    PcSample(Option<u32>),
+    /// DWT event counter wrap; bit mask of the counters that wrapped
+    EventCounter(u8),
+    /// Other hardware source (e.g. data trace) packet
+    Hardware {
+        /// Discriminator ID
+        id: u8,
+        /// Payload, little endian
+        value: u32,
+    },
+    /// Header byte that doesn't start a valid packet
+    Invalid(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Header,
+    // source packet: header, payload bytes received so far
+    Payload { header: u8, received: u8 },
+    // timestamp with continuation bytes
+    Timestamp { control: u8, value: u32, shift: u8 },
+    // extension or global timestamp packet: drop continuation bytes
+    Skip,
+}
+
+/// Incremental ITM packet decoder
+pub struct Decoder {
+    state: State,
+    payload: [u8; 4],
+    // consecutive zero bytes, for the synchronization packet
+    zeros: u8,
+}
+
+impl Decoder {
+    /// Creates a decoder, waiting for a packet header
+    pub const fn new() -> Self {
+        Decoder {
+            state: State::Header,
+            payload: [0; 4],
+            zeros: 0,
+        }
+    }
+
+    /// Feeds the next byte of the stream
+    ///
+    /// Returns a packet once it's complete
+    pub fn feed(&mut self, byte: u8) -> Option<Packet> {
+        // a synchronization packet (at least 47 zero bits followed by a one)
+        // resynchronizes the decoder whatever its state
+        if byte == 0 {
+            self.zeros = self.zeros.saturating_add(1);
+        } else {
+            let zeros = self.zeros;
+            self.zeros = 0;
+
+            if zeros >= 5 && byte == 0x80 {
+                self.state = State::Header;
+                return Some(Packet::Sync);
+            }
+        }
+
+        match self.state {
+            State::Header => self.header(byte),
+            State::Payload { header, received } => {
+                self.payload[received as usize] = byte;
+                let received = received + 1;
+
+                if received == payload_len(header) {
+                    self.state = State::Header;
+                    Some(self.source(header, received))
+                } else {
+                    self.state = State::Payload {
+                        header: header,
+                        received: received,
+                    };
+                    None
+                }
+            }
+            State::Timestamp {
+                control,
+                value,
+                shift,
+            } => {
+                let value = value | ((byte & 0x7f) as u32) << shift;
+
+                if byte & 0x80 != 0 && shift < 21 {
+                    self.state = State::Timestamp {
+                        control: control,
+                        value: value,
+                        shift: shift + 7,
+                    };
+                    None
+                } else {
+                    self.state = State::Header;
+                    Some(Packet::Timestamp {
+                        delta: value,
+                        control: control,
+                    })
+                }
+            }
+            State::Skip => {
+                if byte & 0x80 == 0 {
+                    self.state = State::Header;
+                }
+                None
+            }
+        }
+    }
+
+    fn header(&mut self, byte: u8) -> Option<Packet> {
+        if byte & 0b11 != 0 {
+            // source packet
+            self.payload = [0; 4];
+            self.state = State::Payload {
+                header: byte,
+                received: 0,
+            };
+            return None;
+        }
+
+        match byte {
+            // part of a synchronization packet
+            0x00 => None,
+            0x70 => Some(Packet::Overflow),
+            // local timestamp, format 2: 1-6 cycles, no payload
+            0x10...0x60 if byte & 0x0f == 0 => Some(Packet::Timestamp {
+                delta: (byte >> 4) as u32,
+                control: 0,
+            }),
+            // local timestamp, format 1
+            _ if byte & 0xcf == 0xc0 => {
+                self.state = State::Timestamp {
+                    control: (byte >> 4) & 0b11,
+                    value: 0,
+                    shift: 0,
+                };
+                None
+            }
+            // global timestamps and extension packets
+            0x94 | 0xb4 => {
+                self.state = State::Skip;
+                None
+            }
+            _ if byte & 0x0b == 0x08 => {
+                if byte & 0x80 != 0 {
+                    self.state = State::Skip;
+                }
+                None
+            }
+            _ => Some(Packet::Invalid(byte)),
+        }
+    }
+
+    fn source(&self, header: u8, len: u8) -> Packet {
+        let payload = self.payload;
+        let value = payload[..len as usize]
+            .iter()
+            .rev()
+            .fold(0, |acc, byte| acc << 8 | *byte as u32);
+        let id = header >> 3;
+
+        if header & 0b100 == 0 {
+            return Packet::Instrumentation {
+                port: id,
+                payload: payload,
+                len: len,
+            };
+        }
+
+        match id {
+            0 => Packet::EventCounter(payload[0]),
+            1 if len == 2 => {
+                let action = match (value >> 12) & 0b11 {
+                    1 => ExceptionAction::Entered,
+                    2 => ExceptionAction::Exited,
+                    3 => ExceptionAction::Returned,
+                    _ => return Packet::Hardware { id: id, value: value },
+                };
+
+                Packet::Exception {
+                    number: (value & 0x1ff) as u16,
+                    action: action,
+                }
+            }
+            2 if len == 4 => Packet::PcSample(Some(value)),
+            2 if len == 1 => Packet::PcSample(None),
+            _ => Packet::Hardware { id: id, value: value },
+        }
+    }
+}
+
+// payload size encoded in the header of a source packet
+fn payload_len(header: u8) -> u8 {
+    match header & 0b11 {
+        0b01 => 1,
+        0b10 => 2,
+        _ => 4,
+    }
+}
+
+/// Reassembles the text written to one stimulus port into lines
+pub struct Lines<B>
+where
+    B: Unsize<[u8]>,
+{
+    port: u8,
+    buffer: B,
+    len: usize,
+    truncated: bool,
+}
+
+impl<B> Lines<B>
+where
+    B: Unsize<[u8]>,
+{
+    /// Collects the text of stimulus `port` into `buffer`
+    pub const fn new(port: u8, buffer: B) -> Self {
+        Lines {
+            port: port,
+            buffer: buffer,
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Passes every instrumentation packet of this port to `f`, one line at
+    /// a time (without the trailing `\n`)
+    ///
+    /// The second argument of `f` is `true` if the line was longer than the
+    /// buffer and got truncated.
+    pub fn push<F>(&mut self, packet: &Packet, mut f: F)
+    where
+        F: FnMut(&[u8], bool),
+    {
+        let (payload, len) = match *packet {
+            Packet::Instrumentation {
+                port,
+                ref payload,
+                len,
+            } if port == self.port => (payload, len as usize),
+            _ => return,
+        };
+
+        for byte in &payload[..len] {
+            let buffer: &mut [u8] = &mut self.buffer;
+
+            if *byte == b'\n' {
+                f(&buffer[..self.len], self.truncated);
+                self.len = 0;
+                self.truncated = false;
+            } else if self.len < buffer.len() {
+                buffer[self.len] = *byte;
+                self.len += 1;
+            } else {
+                self.truncated = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    // A capture in the format `itm::init` configures: synchronization,
+    // text on ports 0 and 1, timestamps and DWT packets
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const CAPTURE: &[u8] = &[
+        // synchronization: 47 zeros and a one
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+        // port 0, 4 bytes: "Hell"
+        0x03, b'H', b'e', b'l', b'l',
+        // local timestamp, format 1: 0x05 | 0x01 << 7 cycles
+        0xc0, 0x85, 0x01,
+        // port 0, 2 bytes: "o\n"
+        0x02, b'o', b'\n',
+        // port 1, 1 byte: "x"
+        0x09, b'x',
+        // local timestamp, format 2: 3 cycles
+        0x30,
+        // exception 43 (IRQ 27) entered, then exited
+        0x0e, 0x2b, 0x10,
+        0x0e, 0x2b, 0x20,
+        // PC sample, then PC sample while sleeping
+        0x17, 0x56, 0x04, 0x00, 0x08,
+        0x15, 0x00,
+        // event counter wrap: CYCCNT
+        0x05, 0x20,
+        // global timestamp 1 with two continuation bytes: skipped
+        0x94, 0x81, 0x82, 0x03,
+        // overflow
+        0x70,
+        // local timestamp, format 1, delayed: 0x7f | 0x7f << 7 | 0x01 << 14
+        0xe0, 0xff, 0xff, 0x01,
+        // reserved header
+        0x04,
+        // port 31, 1 byte
+        0xf9, 0xaa,
+    ];
+
+    fn decode(bytes: &[u8]) -> Vec<Packet> {
+        let mut decoder = Decoder::new();
+        bytes.iter().filter_map(|b| decoder.feed(*b)).collect()
+    }
+
+    fn text(port: u8, payload: &[u8]) -> Packet {
+        let mut p = [0; 4];
+        p[..payload.len()].copy_from_slice(payload);
+        Packet::Instrumentation {
+            port: port,
+            payload: p,
+            len: payload.len() as u8,
+        }
+    }
+
+    #[test]
+    fn capture() {
+        assert_eq!(
+            decode(CAPTURE),
+            [
+                Packet::Sync,
+                text(0, b"Hell"),
+                Packet::Timestamp {
+                    delta: 133,
+                    control: 0,
+                },
+                text(0, b"o\n"),
+                text(1, b"x"),
+                Packet::Timestamp {
+                    delta: 3,
+                    control: 0,
+                },
+                Packet::Exception {
+                    number: 43,
+                    action: ExceptionAction::Entered,
+                },
+                Packet::Exception {
+                    number: 43,
+                    action: ExceptionAction::Exited,
+                },
+                Packet::PcSample(Some(0x0800_0456)),
+                Packet::PcSample(None),
+                Packet::EventCounter(0x20),
+                Packet::Overflow,
+                Packet::Timestamp {
+                    delta: 0x7f | 0x7f << 7 | 0x01 << 14,
+                    control: 0b10,
+                },
+                Packet::Invalid(0x04),
+                text(31, &[0xaa]),
+            ]
+        );
+    }
+
+    #[test]
+    fn resynchronization() {
+        // a capture that starts in the middle of a packet
+        let mut bytes = [0x6c, 0x6f, 0x03, b'a'].to_vec();
+        // a truncated packet followed by a synchronization packet
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]);
+        bytes.extend_from_slice(&[0x01, b'b']);
+
+        let packets = decode(&bytes);
+        let sync = packets.iter().position(|p| *p == Packet::Sync).unwrap();
+        assert_eq!(packets[sync..], [Packet::Sync, text(0, b"b")]);
+
+        // zeros in a payload are data
+        assert_eq!(
+            decode(&[0x03, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]),
+            [text(0, &[0, 0, 0, 0]), text(0, &[0])]
+        );
+    }
+
+    #[test]
+    fn lines() {
+        let mut lines = Lines::new(0, [0; 8]);
+        let mut out = Vec::new();
+
+        for packet in decode(CAPTURE).iter().chain(&[
+            text(0, b"0123"),
+            text(0, b"4567"),
+            text(0, b"89\na"),
+            text(0, b"b"),
+        ]) {
+            lines.push(packet, |line, truncated| {
+                out.push((line.to_vec(), truncated))
+            });
+        }
+
+        assert_eq!(
+            out,
+            [(b"Hello".to_vec(), false), (b"01234567".to_vec(), true)]
+        );
+        lines.push(&text(0, b"\n"), |line, truncated| {
+            assert_eq!((line, truncated), (&b"ab"[..], false))
+        });
+    }
+}
@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests
+
+/// xorshift32 pseudo random number generator
+pub struct Xorshift32 {
+    state: u32,
+}
+
+impl Xorshift32 {
+    /// Starts from `seed`, which must not be 0
+    pub fn new(seed: u32) -> Self {
+        assert!(seed != 0);
+
+        Xorshift32 { state: seed }
+    }
+
+    /// Next number of the sequence
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+}
@@ -1,12 +1,79 @@
 //! Timer
+//!
+//! Timeouts are converted to the closest prescaler / auto-reload pair that
+//! the timer's counter width allows (see `prescaler`); `try_set_timeout`
+//! reports how close that is. Timeouts shorter than 2 ticks, which the
+//! counter can't produce, are lengthened to 2 ticks; `try_set_timeout`
+//! rejects them instead.
+//!
+//! Besides the periodic timeout of `hal::Timer`, a `Timer` can fire a single
+//! timeout with `one_shot`: the counter runs in one-pulse mode (RM0368
+//! 13.3.15) and stops by itself at the update event.
+//!
+//! TIM1 - TIM5 and TIM9 can also start counting on an edge of their TI1 or
+//! TI2 input through the slave mode controller (trigger mode, RM0368
+//! 13.3.19); see `start_on` and `one_shot_on`. `Pwm::pulse_on` builds on
+//! this to output a pulse a fixed delay after an input edge.
+//!
+//! # Master / slave links
+//!
+//! A master timer (TIM1 - TIM5) sends a trigger output (TRGO) to other
+//! timers through the internal trigger matrix (ITR), where a slave timer
+//! (TIM1 - TIM5, TIM9) resets, gates, starts or clocks its counter with it
+//! (RM0368 13.3.19, 13.3.20). The `Itr` trait encodes which links exist.
+//!
+//! Timers that start in lockstep (e.g. PWM timers initialized with `Pwm::init`,
+//! which starts them):
+//!
+//! ``` ignore
+//! let (tim2, tim3, tim4) = (Timer(p.TIM2), Timer(p.TIM3), Timer(p.TIM4));
+//!
+//! // stop the counters and clear them
+//! tim2.pause();
+//! tim3.pause();
+//! tim4.pause();
+//! tim2.restart();
+//! tim3.restart();
+//! tim4.restart();
+//!
+//! tim2.set_master(TriggerOutput::Enable, false);
+//! tim3.set_slave(p.TIM2, SlaveMode::Trigger);
+//! tim4.set_slave(p.TIM2, SlaveMode::Trigger);
+//!
+//! // starts the three counters; the slaves lag by the resynchronization
+//! // delay of the trigger input, a few timer clock cycles
+//! tim2.resume();
+//! ```
+//!
+//! A 32-bit time base made of two 16-bit timers:
+//!
+//! ``` ignore
+//! let (low, high) = (Timer(p.TIM3), Timer(p.TIM4));
+//! low.init(Microseconds(1), p.RCC);
+//! high.init(Microseconds(1), p.RCC);
+//!
+//! low.set_resolution(Microseconds(1));
+//! high.set_resolution(apb1::Ticks(1));
+//! low.set_master(TriggerOutput::Update, false);
+//! // one count per overflow of the low timer
+//! high.set_slave(p.TIM3, SlaveMode::ExternalClock);
+//!
+//! high.resume();
+//! low.resume();
+//!
+//! let now: u32 = timer::read_chained(|| low.count(), || high.count());
+//! ```
 
 use core::any::{Any, TypeId};
+use core::cmp;
 
 use cast::{u16, u32};
 use hal;
 use nb::{self, Error};
 use stm32f40x::{TIM1, TIM10, TIM11, TIM2, TIM3, TIM4, TIM5, TIM9, RCC};
 
+use prescaler::{self, Counter, Goal, Solution};
+
 /// Channel associated to a timer
 #[derive(Clone, Copy, Debug)]
 pub enum Channel {
@@ -20,6 +87,172 @@ pub enum Channel {
     _4,
 }
 
+/// Active edge of a timer input
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Edge {
+    /// Low to high transition
+    Rising,
+    /// High to low transition
+    Falling,
+}
+
+/// Timer input that starts the counter in trigger mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    /// Edge on the channel 1 pin (TI1FP1)
+    Ti1(Edge),
+    /// Edge on the channel 2 pin (TI2FP2)
+    Ti2(Edge),
+}
+
+impl Trigger {
+    /// The channel whose pin is the trigger input
+    pub fn channel(&self) -> Channel {
+        match *self {
+            Trigger::Ti1(_) => Channel::_1,
+            Trigger::Ti2(_) => Channel::_2,
+        }
+    }
+}
+
+/// Trigger output of a master timer (CR2.MMS)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TriggerOutput {
+    /// The UG bit, or the reset of the master by its own slave mode
+    /// controller
+    Reset,
+    /// The counter enable, CR1.CEN
+    Enable,
+    /// The update event
+    Update,
+    /// A capture or compare match on channel 1
+    ComparePulse,
+    /// OC1REF
+    Oc1Ref,
+    /// OC2REF
+    Oc2Ref,
+    /// OC3REF
+    Oc3Ref,
+    /// OC4REF
+    Oc4Ref,
+}
+
+impl TriggerOutput {
+    fn mms(&self) -> u32 {
+        match *self {
+            TriggerOutput::Reset => 0b000,
+            TriggerOutput::Enable => 0b001,
+            TriggerOutput::Update => 0b010,
+            TriggerOutput::ComparePulse => 0b011,
+            TriggerOutput::Oc1Ref => 0b100,
+            TriggerOutput::Oc2Ref => 0b101,
+            TriggerOutput::Oc3Ref => 0b110,
+            TriggerOutput::Oc4Ref => 0b111,
+        }
+    }
+}
+
+/// What a slave timer does on its trigger input (SMCR.SMS)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SlaveMode {
+    /// A rising edge reinitializes the counter
+    Reset,
+    /// The counter runs while the trigger input is high
+    Gated,
+    /// A rising edge starts the counter
+    Trigger,
+    /// Rising edges clock the counter (external clock mode 1)
+    ExternalClock,
+}
+
+impl SlaveMode {
+    fn sms(&self) -> u32 {
+        match *self {
+            SlaveMode::Reset => 0b100,
+            SlaveMode::Gated => 0b101,
+            SlaveMode::Trigger => SMCR_SMS_TRIGGER,
+            SlaveMode::ExternalClock => 0b111,
+        }
+    }
+}
+
+/// Internal trigger connection from the `MASTER` timer to this one
+///
+/// Implemented according to the ITR tables of the TIMx_SMCR register
+/// descriptions in RM0368; the TIM8 and USB OTG inputs don't exist on this
+/// device.
+pub trait Itr<MASTER> {
+    #[doc(hidden)]
+    const TS: u32;
+}
+
+macro_rules! itr {
+    ($SLAVE:ident: $($MASTER:ident => $ts:expr),+) => {
+        $(
+            impl Itr<$MASTER> for $SLAVE {
+                const TS: u32 = $ts << 4;
+            }
+        )+
+    }
+}
+
+itr!(TIM1: TIM5 => 0b000, TIM2 => 0b001, TIM3 => 0b010, TIM4 => 0b011);
+itr!(TIM2: TIM1 => 0b000, TIM3 => 0b010, TIM4 => 0b011);
+itr!(TIM3: TIM1 => 0b000, TIM2 => 0b001, TIM5 => 0b010, TIM4 => 0b011);
+itr!(TIM4: TIM1 => 0b000, TIM2 => 0b001, TIM3 => 0b010);
+itr!(TIM5: TIM2 => 0b000, TIM3 => 0b001, TIM4 => 0b010);
+// TIM10 and TIM11 drive TIM9 with their OC1REF
+itr!(TIM9: TIM2 => 0b000, TIM3 => 0b001, TIM10 => 0b010, TIM11 => 0b011);
+
+/// Combines the counts of two chained 16-bit timers into a 32-bit count
+///
+/// `high` counts the overflows of `low` (`SlaveMode::ExternalClock` on
+/// `TriggerOutput::Update`). It is read before and after `low`; if it
+/// changed, `low` wrapped around in between and the counts are read again.
+///
+/// `high` counts an overflow a few timer clock cycles after `low` wraps
+/// around (the resynchronization of its trigger input), so when `low` is
+/// within 8 counts of 0, `high` is read 8 more times before it's trusted.
+/// Each read is a bus access to the timer and lasts at least one timer clock
+/// cycle.
+pub fn read_chained<L, H>(low: L, high: H) -> u32
+where
+    L: Fn() -> u32,
+    H: Fn() -> u32,
+{
+    loop {
+        let before = high();
+        let count = low() & 0xffff;
+        let mut after = high();
+
+        if count < SETTLE {
+            for _ in 0..SETTLE {
+                after = high();
+            }
+        }
+
+        if after == before {
+            return before << 16 | count;
+        }
+    }
+}
+
+// reads of `high` that `read_chained` waits for after an overflow of `low`
+const SETTLE: u32 = 8;
+
+// RM0368 13.4 TIMx register bits, shared by all the timers of this crate
+pub(crate) const CR1_CEN: u32 = 1 << 0;
+pub(crate) const CR1_URS: u32 = 1 << 2;
+pub(crate) const CR1_OPM: u32 = 1 << 3;
+pub(crate) const EGR_UG: u32 = 1 << 0;
+const CR2_MMS: u32 = 0b111 << 4;
+const SMCR_SMS: u32 = 0b111;
+const SMCR_SMS_TRIGGER: u32 = 0b110;
+const SMCR_TS: u32 = 0b111 << 4;
+const SMCR_TS_TI1FP1: u32 = 0b101 << 4;
+const SMCR_TS_TI2FP2: u32 = 0b110 << 4;
+const SMCR_MSM: u32 = 1 << 7;
+
 /// `hal::Timer` implementation
 pub struct Timer<'a, T>(pub &'a T)
 where
@@ -73,20 +306,107 @@ macro_rules! impl_Timer {
                 self._set_timeout(timeout);
 
                 // Continuous mode
-                // tim2.cr1.write(|w| w.opm().continuous());
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_OPM) });
 
                 // Enable the update event interrupt
                 tim.dier.modify(|_, w| w.uie().set_bit());
             }
 
+            /// Sets the timeout to the closest period the prescaler and the
+            /// auto-reload register can produce
+            ///
+            /// Returns the register values with the achieved period and its
+            /// error (see `prescaler::solve`)
+            pub fn try_set_timeout<T>(&self, timeout: T) -> Result<Solution, prescaler::Error>
+            where
+                T: Into<::$APB::Ticks>,
+            {
+                let solution = prescaler::solve(timeout.into().0, self._counter(), Goal::Accuracy)?;
+
+                self.0.psc.write(|w| unsafe{w.psc().bits(solution.psc)});
+                self.0.arr.write(|w| unsafe{w.bits(solution.arr)});
+
+                Ok(solution)
+            }
+
             fn _set_timeout(&self, timeout: ::$APB::Ticks) {
-                let period = timeout.0;
+                // the solver only rejects periods shorter than 2 ticks
+                let timeout = timeout.map(|ticks| cmp::max(ticks, 2));
 
-                let psc = u16((period - 1) / (1 << 16)).unwrap();
-                self.0.psc.write(|w| unsafe{w.psc().bits(psc)});
+                self.try_set_timeout(timeout).expect("invalid timeout");
+            }
 
-                let arr = u32(period / u32(psc + 1));
-                self.0.arr.write(|w| unsafe{w.bits(arr)});
+            fn _counter(&self) -> Counter {
+                let tim = self.0;
+
+                if tim.get_type_id() == TypeId::of::<TIM2>()
+                    || tim.get_type_id() == TypeId::of::<TIM5>()
+                {
+                    Counter::Bits32
+                } else {
+                    Counter::Bits16
+                }
+            }
+
+            /// Fires the update event once, `timeout` from now
+            ///
+            /// The counter stops by itself at the update event, which `wait`
+            /// and the update interrupt report. The timer stays in one-pulse
+            /// mode: `resume` fires another single timeout with the same
+            /// duration, and `init` goes back to periodic timeouts.
+            pub fn one_shot<T>(&self, timeout: T)
+            where
+                T: Into<::$APB::Ticks>,
+            {
+                self._one_shot(timeout.into());
+
+                self.0.cr1.modify(|_, w| w.cen().set_bit());
+            }
+
+            /// `true` while the counter is enabled, i.e. until a one-shot
+            /// timeout has fired
+            pub fn is_running(&self) -> bool {
+                self.0.cr1.read().bits() & CR1_CEN != 0
+            }
+
+            /// Current count
+            pub fn count(&self) -> u32 {
+                self.0.cnt.read().bits()
+            }
+
+            /// Counts over the whole counter range, one count every
+            /// `resolution`, instead of timing out
+            ///
+            /// Clears the counter. Use a resolution of one tick for the high
+            /// half of a chained time base.
+            pub fn set_resolution<R>(&self, resolution: R)
+            where
+                R: Into<::$APB::Ticks>,
+            {
+                let tim = self.0;
+
+                let psc = resolution.into().0.checked_sub(1).expect("impossible resolution");
+                tim.psc.write(|w| unsafe { w.psc().bits(u16(psc).unwrap()) });
+                tim.arr.write(|w| unsafe { w.bits(self._counter().max()) });
+
+                // URS: the UG below loads the prescaler without setting UIF
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_URS) });
+                tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+            }
+
+            // Stops the counter and loads `timeout` in one-pulse mode
+            fn _one_shot(&self, timeout: ::$APB::Ticks) {
+                let tim = self.0;
+
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_CEN) });
+
+                self._set_timeout(timeout);
+
+                // URS: the UG below reloads the prescaler and clears the
+                // counter without setting UIF; only the overflow does
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_OPM | CR1_URS) });
+                tim.egr.write(|w| unsafe { w.bits(EGR_UG) });
+                tim.sr.modify(|_, w| w.uif().clear_bit());
             }
         }
 
@@ -95,10 +415,11 @@ macro_rules! impl_Timer {
             type Time = ::$APB::Ticks;
 
             fn get_timeout(&self) -> ::$APB::Ticks {
-                    ::$APB::Ticks(
-                        u32(self.0.psc.read().psc().bits() + 1) *
-                            u32(self.0.arr.read().bits()),
-                    )
+                let period = prescaler::period(
+                    self.0.psc.read().psc().bits(),
+                    self.0.arr.read().bits(),
+                );
+                ::$APB::Ticks(u32(period).unwrap_or(u32::max_value()))
             }
 
                     fn pause(&self) {
@@ -132,6 +453,135 @@ macro_rules! impl_Timer {
     }
 }
 
+// Timers with a slave mode controller (TIM10 and TIM11 have none)
+macro_rules! impl_TimerTrigger {
+    ($TIM:ident, $APB:ident) => {
+        impl<'a> Timer<'a, $TIM>
+        {
+            /// Stops the counter and starts it again on the next `trigger`
+            /// edge (trigger mode)
+            ///
+            /// The pin of the trigger channel must be in its alternate
+            /// function mode; the channel is configured as an input, so it
+            /// can't be used as an output at the same time. In one-pulse mode
+            /// every edge after the counter stopped starts it again.
+            pub fn start_on(&self, trigger: Trigger) {
+                let tim = self.0;
+
+                tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_CEN) });
+
+                let ts = match trigger {
+                    Trigger::Ti1(edge) => {
+                        // CC1S = 01: CC1 is an input mapped on TI1, no filter
+                        tim.ccmr1_output.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !0xff) | 0b01)
+                        });
+                        // CC1P / CC1NP
+                        tim.ccer.modify(|r, w| unsafe {
+                            let ccer = r.bits() & !0b1010;
+                            w.bits(if edge == Edge::Falling { ccer | 1 << 1 } else { ccer })
+                        });
+                        SMCR_TS_TI1FP1
+                    }
+                    Trigger::Ti2(edge) => {
+                        // CC2S = 01: CC2 is an input mapped on TI2, no filter
+                        tim.ccmr1_output.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0xff << 8)) | 0b01 << 8)
+                        });
+                        // CC2P / CC2NP
+                        tim.ccer.modify(|r, w| unsafe {
+                            let ccer = r.bits() & !(0b1010 << 4);
+                            w.bits(if edge == Edge::Falling { ccer | 1 << 5 } else { ccer })
+                        });
+                        SMCR_TS_TI2FP2
+                    }
+                };
+
+                self._slave(ts, SMCR_SMS_TRIGGER);
+            }
+
+            /// Makes this timer a slave of `master`, through the internal
+            /// trigger matrix
+            ///
+            /// `master` selects the trigger input; its trigger output is
+            /// configured with `set_master`. In `SlaveMode::Trigger` the
+            /// counter must be stopped beforehand.
+            pub fn set_slave<M>(&self, _master: &M, mode: SlaveMode)
+            where
+                $TIM: Itr<M>,
+            {
+                self._slave(<$TIM as Itr<M>>::TS, mode.sms());
+            }
+
+            // Selects the trigger input `ts` and the slave mode `sms`
+            fn _slave(&self, ts: u32, sms: u32) {
+                let tim = self.0;
+
+                // RM0368 13.4.3 TS must only be changed while the slave mode
+                // controller is disabled
+                tim.smcr.modify(|r, w| unsafe { w.bits(r.bits() & !SMCR_SMS) });
+                tim.smcr.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !SMCR_TS) | ts)
+                });
+                tim.smcr.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !SMCR_SMS) | sms)
+                });
+            }
+
+            /// Disables the slave mode controller (trigger mode or a link to
+            /// a master); the counter is then only started by software and
+            /// clocked by the timer clock
+            pub fn disable_trigger(&self) {
+                self.0.smcr.modify(|r, w| unsafe { w.bits(r.bits() & !SMCR_SMS) });
+            }
+
+            /// Fires the update event once, `timeout` after every `trigger`
+            /// edge
+            ///
+            /// See `one_shot` and `start_on`
+            pub fn one_shot_on<T>(&self, timeout: T, trigger: Trigger)
+            where
+                T: Into<::$APB::Ticks>,
+            {
+                self._one_shot(timeout.into());
+
+                self.start_on(trigger);
+            }
+        }
+    }
+}
+
+// Timers with a trigger output (TIM9 - TIM11 have none)
+macro_rules! impl_TimerMaster {
+    ($TIM:ident) => {
+        impl<'a> Timer<'a, $TIM>
+        {
+            /// Sends `output` to the slave timers as TRGO
+            ///
+            /// `master_slave` sets SMCR.MSM: the effect of this timer's own
+            /// trigger input is delayed so that it stays in step with its
+            /// slaves, which see TRGO through their resynchronized trigger
+            /// input (RM0368 13.4.3). Only useful if this timer is itself
+            /// triggered, e.g. by `start_on` or `set_slave`; MSM is cleared
+            /// otherwise.
+            pub fn set_master(&self, output: TriggerOutput, master_slave: bool) {
+                let tim = self.0;
+
+                tim.cr2.modify(|r, w| unsafe {
+                    w.bits((r.bits() & !CR2_MMS) | output.mms() << 4)
+                });
+                tim.smcr.modify(|r, w| unsafe {
+                    w.bits(if master_slave {
+                        r.bits() | SMCR_MSM
+                    } else {
+                        r.bits() & !SMCR_MSM
+                    })
+                });
+            }
+        }
+    }
+}
+
 impl_Timer!(TIM1, apb2);
 impl_Timer!(TIM2, apb1);
 impl_Timer!(TIM3, apb1);
@@ -140,3 +590,95 @@ impl_Timer!(TIM5, apb1);
 impl_Timer!(TIM9, apb2);
 impl_Timer!(TIM10, apb2);
 impl_Timer!(TIM11, apb2);
+
+impl_TimerTrigger!(TIM1, apb2);
+impl_TimerTrigger!(TIM2, apb1);
+impl_TimerTrigger!(TIM3, apb1);
+impl_TimerTrigger!(TIM4, apb1);
+impl_TimerTrigger!(TIM5, apb1);
+impl_TimerTrigger!(TIM9, apb2);
+
+impl_TimerMaster!(TIM1);
+impl_TimerMaster!(TIM2);
+impl_TimerMaster!(TIM3);
+impl_TimerMaster!(TIM4);
+impl_TimerMaster!(TIM5);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    // Two chained timers where every register read lasts one timer clock
+    // cycle. `low` counts every `div` cycles and `high` sees its overflows
+    // `delay` cycles late.
+    struct Chain {
+        now: Cell<u32>,
+        div: u32,
+        delay: u32,
+    }
+
+    impl Chain {
+        fn tick(&self) -> u32 {
+            let now = self.now.get();
+            self.now.set(now + 1);
+            now
+        }
+
+        fn low(&self) -> u32 {
+            (self.tick() / self.div) & 0xffff
+        }
+
+        fn high(&self) -> u32 {
+            self.tick().saturating_sub(self.delay) / self.div >> 16
+        }
+    }
+
+    #[test]
+    fn chained_overflow() {
+        for &div in &[1, 2, 5] {
+            for delay in 0..SETTLE + 1 {
+                let overflow = 3 << 16;
+                for start in (overflow - 40) * div..(overflow + 40) * div {
+                    let chain = Chain {
+                        now: Cell::new(start),
+                        div: div,
+                        delay: delay,
+                    };
+
+                    let count = read_chained(|| chain.low(), || chain.high());
+
+                    assert!(
+                        count >= start / div && count <= chain.now.get() / div,
+                        "div {}, delay {}, start {}: {}",
+                        div,
+                        delay,
+                        start,
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chained_stopped() {
+        // a stopped `low` doesn't make it wait
+        let (low, high) = (Cell::new(0), Cell::new(0));
+        let count = read_chained(
+            || {
+                low.set(low.get() + 1);
+                0
+            },
+            || {
+                high.set(high.get() + 1);
+                7
+            },
+        );
+
+        assert_eq!(count, 7 << 16);
+        assert_eq!(low.get(), 1);
+        assert_eq!(high.get(), 2 + SETTLE);
+    }
+}
@@ -0,0 +1,1116 @@
+//! XMODEM-CRC, XMODEM-1K and YMODEM file transfers
+//!
+//! `Receiver` and `Sender` are protocol state machines that don't touch any
+//! peripheral: feed them the received bytes (and a `timeout` call whenever
+//! the line has been silent for `TIMEOUT`), and send whatever they return.
+//! `receive` and `send` run them on top of a `Serial` interface, using the
+//! DWT cycle counter for the timeouts.
+//!
+//! Both ends use 16-bit CRCs (CRC-16/XMODEM) and accept 128 and 1024 byte
+//! blocks. The original 8-bit checksum mode is not supported. YMODEM is
+//! limited to one file per batch.
+//!
+//! The received data is streamed to a `Sink`, block by block; the data to
+//! send is pulled from a `Source`.
+
+use core::any::Any;
+use core::cmp;
+use core::str;
+
+use hal;
+use nb;
+use stm32f40x::DWT;
+
+use crc;
+use serial::{Serial, Usart};
+use time::Seconds;
+
+/// Start of a 128 byte block
+pub const SOH: u8 = 0x01;
+/// Start of a 1024 byte block
+pub const STX: u8 = 0x02;
+/// End of transmission
+pub const EOT: u8 = 0x04;
+/// Acknowledge
+pub const ACK: u8 = 0x06;
+/// Negative acknowledge
+pub const NAK: u8 = 0x15;
+/// Cancel
+pub const CAN: u8 = 0x18;
+/// Request for a transfer in CRC mode
+pub const CRC: u8 = b'C';
+/// Padding of the last block
+pub const SUB: u8 = 0x1a;
+
+/// Silence, in seconds, after which `timeout` should be called
+pub const TIMEOUT: u32 = 1;
+
+/// Number of consecutive timeouts or bad blocks after which the transfer is
+/// cancelled
+pub const MAX_RETRIES: u8 = 10;
+
+const MAX_PACKET: usize = 3 + 1024 + 2;
+
+/// Transfer error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The peer cancelled the transfer
+    Cancelled,
+    /// Too many timeouts or corrupted blocks
+    Retries,
+    /// Unexpected block number, or a request for checksum mode
+    Protocol,
+    /// The sink or the source failed
+    Storage,
+    #[doc(hidden)] _Extensible,
+}
+
+/// Protocol variant
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// XMODEM-CRC / XMODEM-1K: data blocks only
+    Xmodem,
+    /// YMODEM: block 0 carries the file name and size
+    Ymodem,
+}
+
+/// State of a transfer after a step
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The transfer is in progress
+    Busy,
+    /// The transfer completed successfully
+    Done,
+    /// The transfer was aborted
+    Failed(Error),
+}
+
+/// Result of a step of the state machine
+#[derive(Debug)]
+pub struct Step<'a> {
+    /// Bytes to send to the peer, possibly empty
+    pub send: &'a [u8],
+    /// State of the transfer
+    pub status: Status,
+}
+
+impl<'a> Step<'a> {
+    fn busy(send: &'a [u8]) -> Self {
+        Step {
+            send: send,
+            status: Status::Busy,
+        }
+    }
+
+    fn fail(error: Error) -> Self {
+        Step {
+            send: &[CAN, CAN],
+            status: Status::Failed(error),
+        }
+    }
+}
+
+/// Destination of a received file
+pub trait Sink {
+    /// YMODEM only: a file called `name` of `size` bytes (if the sender
+    /// reported it) is about to be received
+    fn file(&mut self, name: &str, size: Option<u32>) -> Result<(), ()> {
+        let _ = (name, size);
+        Ok(())
+    }
+
+    /// Stores `data` at `offset` bytes from the start of the file
+    ///
+    /// With XMODEM the last block includes the `SUB` padding
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()>;
+}
+
+/// Origin of a sent file
+pub trait Source {
+    /// Fills `buffer` with the data found at `offset` bytes from the start
+    /// of the file; returns the number of bytes read, 0 at the end of the file
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<usize, ()>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RxState {
+    // waiting for the first block, sending `C`
+    Start,
+    // waiting for SOH / STX / EOT / CAN
+    Header,
+    // receiving a block of this many data bytes
+    Block(usize),
+    // the first EOT was NAKed
+    Eot,
+    // YMODEM: waiting for the end of batch block
+    Batch,
+    Done,
+}
+
+/// Receiving end of a transfer
+pub struct Receiver {
+    mode: Mode,
+    state: RxState,
+    packet: [u8; MAX_PACKET],
+    len: usize,
+    // next data block number
+    block: u8,
+    offset: u32,
+    // YMODEM file size
+    size: Option<u32>,
+    // a block has been accepted
+    started: bool,
+    // YMODEM: the next block 0 carries the file information or ends the
+    // batch; any other block 0 is block 256, 512, ... of the file
+    expect_header: bool,
+    // YMODEM: the last block accepted was the file information
+    last_header: bool,
+    // YMODEM: the file is complete, the next block 0 ends the batch
+    batch_end: bool,
+    retries: u8,
+    cancels: u8,
+}
+
+impl Receiver {
+    /// Creates a receiver for `mode` transfers
+    ///
+    /// The receiver is ready to accept the first block; `start` returns the
+    /// byte that asks the sender to begin.
+    pub const fn new(mode: Mode) -> Self {
+        Receiver {
+            mode: mode,
+            state: RxState::Start,
+            packet: [0; MAX_PACKET],
+            len: 0,
+            block: 1,
+            offset: 0,
+            size: None,
+            started: false,
+            expect_header: true,
+            last_header: false,
+            batch_end: false,
+            retries: 0,
+            cancels: 0,
+        }
+    }
+
+    /// Starts (or restarts) a transfer; send the returned bytes
+    pub fn start(&mut self) -> &'static [u8] {
+        self.state = RxState::Start;
+        self.block = 1;
+        self.offset = 0;
+        self.size = None;
+        self.started = false;
+        self.expect_header = true;
+        self.last_header = false;
+        self.batch_end = false;
+        self.retries = 0;
+        self.cancels = 0;
+
+        &[CRC]
+    }
+
+    /// Number of bytes written to the sink so far
+    pub fn received(&self) -> u32 {
+        self.offset
+    }
+
+    /// Handles a period of silence of `TIMEOUT` seconds
+    pub fn timeout(&mut self) -> Step<'static> {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            self.state = RxState::Done;
+            return Step::fail(Error::Retries);
+        }
+
+        match self.state {
+            // keep asking for a CRC mode transfer
+            RxState::Start | RxState::Batch => Step::busy(&[CRC]),
+            RxState::Done => Step::busy(&[]),
+            _ => {
+                self.state = RxState::Header;
+                Step::busy(&[NAK])
+            }
+        }
+    }
+
+    /// Handles a received byte
+    pub fn feed<S>(&mut self, byte: u8, sink: &mut S) -> Step<'static>
+    where
+        S: Sink,
+    {
+        match self.state {
+            RxState::Done => Step::busy(&[]),
+            RxState::Block(size) => {
+                self.packet[self.len] = byte;
+                self.len += 1;
+
+                if self.len == 2 + size + 2 {
+                    self.state = RxState::Header;
+                    self.block(size, sink)
+                } else {
+                    Step::busy(&[])
+                }
+            }
+            _ => self.header(byte),
+        }
+    }
+
+    fn header(&mut self, byte: u8) -> Step<'static> {
+        if byte != CAN {
+            self.cancels = 0;
+        }
+
+        match byte {
+            SOH | STX => {
+                self.len = 0;
+                self.state = RxState::Block(if byte == SOH { 128 } else { 1024 });
+                Step::busy(&[])
+            }
+            // the first EOT is NAKed to make sure it's not line noise
+            EOT if self.state == RxState::Eot => match self.mode {
+                Mode::Xmodem => {
+                    self.state = RxState::Done;
+                    Step {
+                        send: &[ACK],
+                        status: Status::Done,
+                    }
+                }
+                // YMODEM: ask for the next file
+                Mode::Ymodem => {
+                    self.state = RxState::Batch;
+                    self.expect_header = true;
+                    self.batch_end = true;
+                    Step::busy(&[ACK, CRC])
+                }
+            },
+            // XMODEM: an EOT instead of the first block is an empty file
+            EOT if self.state != RxState::Start || self.mode == Mode::Xmodem => {
+                self.state = RxState::Eot;
+                Step::busy(&[NAK])
+            }
+            CAN => {
+                self.cancels += 1;
+                if self.cancels >= 2 {
+                    self.state = RxState::Done;
+                    Step {
+                        send: &[],
+                        status: Status::Failed(Error::Cancelled),
+                    }
+                } else {
+                    Step::busy(&[])
+                }
+            }
+            // noise between blocks
+            _ => {
+                if self.state == RxState::Eot {
+                    self.state = RxState::Header;
+                }
+                Step::busy(&[])
+            }
+        }
+    }
+
+    fn block<S>(&mut self, size: usize, sink: &mut S) -> Step<'static>
+    where
+        S: Sink,
+    {
+        let number = self.packet[0];
+        let data = &self.packet[2..2 + size];
+        let crc = (self.packet[2 + size] as u16) << 8 | self.packet[3 + size] as u16;
+
+        if number != !self.packet[1] || crc::crc16_xmodem(data) != crc {
+            self.retries += 1;
+            return if self.retries >= MAX_RETRIES {
+                self.state = RxState::Done;
+                Step::fail(Error::Retries)
+            } else {
+                Step::busy(&[NAK])
+            };
+        }
+
+        self.retries = 0;
+
+        // YMODEM block 0: file information, or the end of the batch
+        if self.mode == Mode::Ymodem && self.expect_header {
+            if number != 0 {
+                self.state = RxState::Done;
+                return Step::fail(Error::Protocol);
+            }
+
+            if self.batch_end || data[0] == 0 {
+                self.state = RxState::Done;
+                return Step {
+                    send: &[ACK],
+                    status: Status::Done,
+                };
+            }
+
+            let (name, size) = parse_header(data);
+            if sink.file(name, size).is_err() {
+                self.state = RxState::Done;
+                return Step::fail(Error::Storage);
+            }
+
+            self.size = size;
+            self.block = 1;
+            self.started = true;
+            self.expect_header = false;
+            self.last_header = true;
+            return Step::busy(&[ACK, CRC]);
+        }
+
+        if number == self.block.wrapping_sub(1) && self.started {
+            // our ACK got lost; the sender repeated the previous block
+            return if self.last_header {
+                Step::busy(&[ACK, CRC])
+            } else {
+                Step::busy(&[ACK])
+            };
+        }
+
+        if number != self.block {
+            self.state = RxState::Done;
+            return Step::fail(Error::Protocol);
+        }
+
+        self.started = true;
+        self.last_header = false;
+
+        // drop the padding when the size is known
+        let len = match self.size {
+            Some(size) => cmp::min(data.len() as u32, size.saturating_sub(self.offset)) as usize,
+            None => data.len(),
+        };
+
+        if sink.write(self.offset, &data[..len]).is_err() {
+            self.state = RxState::Done;
+            return Step::fail(Error::Storage);
+        }
+
+        self.offset += len as u32;
+        self.block = self.block.wrapping_add(1);
+        Step::busy(&[ACK])
+    }
+}
+
+// "name\0size[ mtime ...]\0"
+fn parse_header(data: &[u8]) -> (&str, Option<u32>) {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let name = str::from_utf8(&data[..end]).unwrap_or("");
+
+    let mut size = None;
+    for byte in data[cmp::min(end + 1, data.len())..].iter() {
+        match *byte {
+            b'0'...b'9' => {
+                let digit = (*byte - b'0') as u32;
+                size = Some(size.unwrap_or(0u32).saturating_mul(10).saturating_add(digit));
+            }
+            _ => break,
+        }
+    }
+
+    (name, size)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TxState {
+    // waiting for the receiver's `C`
+    Start,
+    // YMODEM: block 0 sent, waiting for ACK then `C`
+    Header,
+    // a data block was sent
+    Data,
+    // EOT sent
+    Eot,
+    // YMODEM: waiting for `C` to send the end of batch block
+    BatchStart,
+    // YMODEM: end of batch block sent
+    Batch,
+    Done,
+}
+
+/// Sending end of a transfer
+pub struct Sender<'a> {
+    mode: Mode,
+    name: &'a str,
+    size: u32,
+    large: bool,
+    state: TxState,
+    packet: [u8; MAX_PACKET],
+    len: usize,
+    block: u8,
+    offset: u32,
+    // length of the data in the last block sent
+    sent: usize,
+    retries: u8,
+    cancels: u8,
+}
+
+impl<'a> Sender<'a> {
+    /// Creates a sender
+    ///
+    /// `large` selects 1024 byte blocks (XMODEM-1K, and the usual YMODEM);
+    /// otherwise 128 byte blocks are used. `name` and `size` are only sent by
+    /// YMODEM.
+    pub const fn new(mode: Mode, large: bool, name: &'a str, size: u32) -> Self {
+        Sender {
+            mode: mode,
+            name: name,
+            size: size,
+            large: large,
+            state: TxState::Start,
+            packet: [0; MAX_PACKET],
+            len: 0,
+            block: 0,
+            offset: 0,
+            sent: 0,
+            retries: 0,
+            cancels: 0,
+        }
+    }
+
+    /// Number of bytes acknowledged by the receiver
+    pub fn sent(&self) -> u32 {
+        self.offset
+    }
+
+    /// Handles a period of silence of `TIMEOUT` seconds
+    ///
+    /// Nothing is sent: retransmissions are requested by the receiver
+    /// (`NAK` or `C`), re-sending on a timeout as well could make the sender
+    /// count an acknowledgement twice.
+    pub fn timeout(&mut self) -> Step {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            self.state = TxState::Done;
+            return Step::fail(Error::Retries);
+        }
+
+        Step::busy(&[])
+    }
+
+    /// Handles a received byte
+    pub fn feed<S>(&mut self, byte: u8, source: &mut S) -> Step
+    where
+        S: Source,
+    {
+        if byte == CAN {
+            self.cancels += 1;
+            if self.cancels >= 2 {
+                self.state = TxState::Done;
+                return Step {
+                    send: &[],
+                    status: Status::Failed(Error::Cancelled),
+                };
+            }
+            return Step::busy(&[]);
+        }
+        self.cancels = 0;
+
+        match (self.state, byte) {
+            (TxState::Start, CRC) => {
+                self.retries = 0;
+                match self.mode {
+                    Mode::Xmodem => {
+                        self.block = 1;
+                        self.next_block(source)
+                    }
+                    Mode::Ymodem => {
+                        self.state = TxState::Header;
+                        self.header_block(false)
+                    }
+                }
+            }
+            (TxState::Start, NAK) => {
+                // checksum mode is not supported
+                self.state = TxState::Done;
+                Step::fail(Error::Protocol)
+            }
+            (TxState::Header, ACK) => {
+                self.retries = 0;
+                self.block = 1;
+                self.state = TxState::Data;
+                // wait for the `C` that starts the data transfer
+                self.len = 0;
+                Step::busy(&[])
+            }
+            (TxState::Data, CRC) | (TxState::Data, NAK) if self.len == 0 => {
+                // YMODEM: first data block
+                self.next_block(source)
+            }
+            (TxState::Data, ACK) if self.len != 0 => {
+                self.retries = 0;
+                self.offset += self.sent as u32;
+                self.block = self.block.wrapping_add(1);
+                self.next_block(source)
+            }
+            (TxState::Eot, ACK) => match self.mode {
+                Mode::Xmodem => {
+                    self.state = TxState::Done;
+                    Step {
+                        send: &[],
+                        status: Status::Done,
+                    }
+                }
+                Mode::Ymodem => {
+                    self.retries = 0;
+                    self.state = TxState::BatchStart;
+                    Step::busy(&[])
+                }
+            },
+            (TxState::Eot, NAK) => Step::busy(&[EOT]),
+            // YMODEM: the receiver asks for the next file (even if the ACK of
+            // the EOT got lost)
+            (TxState::BatchStart, CRC) | (TxState::Eot, CRC) if self.mode == Mode::Ymodem => {
+                self.retries = 0;
+                self.state = TxState::Batch;
+                self.header_block(true)
+            }
+            (TxState::Batch, ACK) => {
+                self.state = TxState::Done;
+                Step {
+                    send: &[],
+                    status: Status::Done,
+                }
+            }
+            // the receiver missed the block (or, for block 0, our ACK)
+            (TxState::Header, NAK)
+            | (TxState::Header, CRC)
+            | (TxState::Data, NAK)
+            | (TxState::Batch, NAK)
+            | (TxState::Batch, CRC) => {
+                self.retries += 1;
+                if self.retries >= MAX_RETRIES {
+                    self.state = TxState::Done;
+                    Step::fail(Error::Retries)
+                } else {
+                    Step::busy(&self.packet[..self.len])
+                }
+            }
+            _ => Step::busy(&[]),
+        }
+    }
+
+    fn next_block<S>(&mut self, source: &mut S) -> Step
+    where
+        S: Source,
+    {
+        let max = if self.large { 1024 } else { 128 };
+
+        let n = {
+            let data = &mut self.packet[3..3 + max];
+            match source.read(self.offset, data) {
+                Ok(n) => cmp::min(n, max),
+                Err(()) => {
+                    self.state = TxState::Done;
+                    return Step::fail(Error::Storage);
+                }
+            }
+        };
+
+        if n == 0 {
+            self.state = TxState::Eot;
+            self.len = 0;
+            return Step::busy(&[EOT]);
+        }
+
+        // use a short block for a short tail
+        let size = if n <= 128 { 128 } else { 1024 };
+        for byte in self.packet[3 + n..3 + size].iter_mut() {
+            *byte = SUB;
+        }
+
+        self.state = TxState::Data;
+        self.sent = n;
+        self.frame(size)
+    }
+
+    // YMODEM block 0: file information, or empty to end the batch
+    fn header_block(&mut self, end: bool) -> Step {
+        for byte in self.packet[3..3 + 128].iter_mut() {
+            *byte = 0;
+        }
+
+        if !end {
+            let name = self.name.as_bytes();
+            let n = cmp::min(name.len(), 128 - 12);
+            self.packet[3..3 + n].copy_from_slice(&name[..n]);
+
+            // decimal size after the NUL
+            let mut digits = [0; 10];
+            let mut size = self.size;
+            let mut i = digits.len();
+            loop {
+                i -= 1;
+                digits[i] = b'0' + (size % 10) as u8;
+                size /= 10;
+                if size == 0 {
+                    break;
+                }
+            }
+            let len = digits.len() - i;
+            self.packet[4 + n..4 + n + len].copy_from_slice(&digits[i..]);
+        }
+
+        self.block = 0;
+        self.frame(128)
+    }
+
+    fn frame(&mut self, size: usize) -> Step {
+        let crc = crc::crc16_xmodem(&self.packet[3..3 + size]);
+
+        self.packet[0] = if size == 128 { SOH } else { STX };
+        self.packet[1] = self.block;
+        self.packet[2] = !self.block;
+        self.packet[3 + size] = (crc >> 8) as u8;
+        self.packet[4 + size] = crc as u8;
+        self.len = 3 + size + 2;
+
+        Step::busy(&self.packet[..self.len])
+    }
+}
+
+/// Receives a file over `serial` into `sink`
+///
+/// Blocks until the transfer completes or fails. The DWT cycle counter must
+/// be enabled.
+pub fn receive<U, S>(serial: Serial<U>, dwt: &DWT, mode: Mode, sink: &mut S) -> Result<(), Error>
+where
+    U: Any + Usart,
+    S: Sink,
+{
+    let mut receiver = Receiver::new(mode);
+
+    let start = receiver.start();
+    write_all(serial, start);
+
+    run(serial, dwt, |input| {
+        let step = match input {
+            Some(byte) => receiver.feed(byte, sink),
+            None => receiver.timeout(),
+        };
+
+        write_all(serial, step.send);
+        step.status
+    })
+}
+
+/// Sends the file provided by `source` over `serial`
+///
+/// Blocks until the transfer completes or fails. The DWT cycle counter must
+/// be enabled.
+pub fn send<U, S>(
+    serial: Serial<U>,
+    dwt: &DWT,
+    sender: &mut Sender,
+    source: &mut S,
+) -> Result<(), Error>
+where
+    U: Any + Usart,
+    S: Source,
+{
+    run(serial, dwt, |input| {
+        let step = match input {
+            Some(byte) => sender.feed(byte, source),
+            None => sender.timeout(),
+        };
+
+        write_all(serial, step.send);
+        step.status
+    })
+}
+
+// Feeds received bytes (`Some`) and timeouts (`None`) to `f` until the
+// transfer ends
+fn run<U, F>(serial: Serial<U>, dwt: &DWT, mut f: F) -> Result<(), Error>
+where
+    U: Any + Usart,
+    F: FnMut(Option<u8>) -> Status,
+{
+    let timeout: u32 = ::ahb1::Ticks::from(Seconds(TIMEOUT)).into();
+    let mut deadline = dwt.cyccnt.read().wrapping_add(timeout);
+
+    loop {
+        let input = match hal::serial::Read::read(&serial) {
+            Ok(byte) => Some(byte),
+            // NOTE a corrupted byte fails the block CRC
+            Err(nb::Error::Other(_)) => continue,
+            Err(nb::Error::WouldBlock) => {
+                if (deadline.wrapping_sub(dwt.cyccnt.read()) as i32) > 0 {
+                    continue;
+                }
+                None
+            }
+        };
+
+        deadline = dwt.cyccnt.read().wrapping_add(timeout);
+
+        match f(input) {
+            Status::Busy => {}
+            Status::Done => return Ok(()),
+            Status::Failed(e) => return Err(e),
+        }
+    }
+}
+
+fn write_all<U>(serial: Serial<U>, bytes: &[u8])
+where
+    U: Any + Usart,
+{
+    for byte in bytes {
+        while hal::serial::Write::write(&serial, *byte).is_err() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct File {
+        name: Option<String>,
+        size: Option<u32>,
+        data: Vec<u8>,
+    }
+
+    impl Sink for File {
+        fn file(&mut self, name: &str, size: Option<u32>) -> Result<(), ()> {
+            self.name = Some(String::from(name));
+            self.size = size;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+            assert_eq!(offset as usize, self.data.len());
+            self.data.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    impl<'a> Source for &'a [u8] {
+        fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<usize, ()> {
+            let rest = &self[cmp::min(offset as usize, self.len())..];
+            let n = cmp::min(rest.len(), buffer.len());
+            buffer[..n].copy_from_slice(&rest[..n]);
+            Ok(n)
+        }
+    }
+
+    fn block(number: u8, data: &[u8]) -> Vec<u8> {
+        packet(number, data, SUB)
+    }
+
+    fn packet(number: u8, data: &[u8], padding: u8) -> Vec<u8> {
+        let size = if data.len() <= 128 { 128 } else { 1024 };
+        let mut packet = [if size == 128 { SOH } else { STX }, number, !number].to_vec();
+        packet.extend_from_slice(data);
+        packet.resize(3 + size, padding);
+        let crc = crc::crc16_xmodem(&packet[3..]);
+        packet.push((crc >> 8) as u8);
+        packet.push(crc as u8);
+        packet
+    }
+
+    fn header(name: &str, size: u32) -> Vec<u8> {
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(format!("{} 13351763216 100644", size).as_bytes());
+        packet(0, &data, 0)
+    }
+
+    // Feeds `bytes` and returns the bytes sent back and the final status
+    fn feed(receiver: &mut Receiver, file: &mut File, bytes: &[u8]) -> (Vec<u8>, Status) {
+        let mut sent = Vec::new();
+        let mut status = Status::Busy;
+        for byte in bytes {
+            let step = receiver.feed(*byte, file);
+            sent.extend_from_slice(step.send);
+            status = step.status;
+        }
+        (sent, status)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn xmodem_wrap_around() {
+        // no `start`: the sender was started by other means
+        let mut receiver = Receiver::new(Mode::Xmodem);
+        let mut file = File::default();
+
+        // 300 blocks: the block numbers wrap around twice
+        let data = data(300 * 128);
+        for (i, chunk) in data.chunks(128).enumerate() {
+            let number = (i + 1) as u8;
+            assert_eq!(
+                feed(&mut receiver, &mut file, &block(number, chunk)),
+                ([ACK].to_vec(), Status::Busy)
+            );
+        }
+
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[EOT]),
+            ([NAK].to_vec(), Status::Busy)
+        );
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[EOT]),
+            ([ACK].to_vec(), Status::Done)
+        );
+        assert_eq!(file.data, data);
+        assert_eq!(receiver.received(), data.len() as u32);
+    }
+
+    #[test]
+    fn ymodem_wrap_around() {
+        let mut receiver = Receiver::new(Mode::Ymodem);
+        let mut file = File::default();
+
+        assert_eq!(receiver.start(), [CRC]);
+
+        // 300 blocks and a short tail; block 256 is numbered 0
+        let data = data(300 * 128 + 50);
+        assert_eq!(
+            feed(
+                &mut receiver,
+                &mut file,
+                &header("foo.bin", data.len() as u32)
+            ),
+            ([ACK, CRC].to_vec(), Status::Busy)
+        );
+        for (i, chunk) in data.chunks(128).enumerate() {
+            let number = (i + 1) as u8;
+            assert_eq!(
+                feed(&mut receiver, &mut file, &block(number, chunk)),
+                ([ACK].to_vec(), Status::Busy)
+            );
+        }
+
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[EOT]),
+            ([NAK].to_vec(), Status::Busy)
+        );
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[EOT]),
+            ([ACK, CRC].to_vec(), Status::Busy)
+        );
+        // end of the batch
+        assert_eq!(
+            feed(&mut receiver, &mut file, &packet(0, &[], 0)),
+            ([ACK].to_vec(), Status::Done)
+        );
+
+        assert_eq!(file.name, Some(String::from("foo.bin")));
+        assert_eq!(file.size, Some(data.len() as u32));
+        // the padding of the last block is dropped
+        assert_eq!(file.data, data);
+    }
+
+    #[test]
+    fn lost_ack() {
+        let mut receiver = Receiver::new(Mode::Ymodem);
+        let mut file = File::default();
+        receiver.start();
+
+        let data = data(257 * 128);
+        let blocks: Vec<Vec<u8>> = data
+            .chunks(128)
+            .enumerate()
+            .map(|(i, chunk)| block((i + 1) as u8, chunk))
+            .collect();
+
+        // the repeated file information asks for the data again
+        let info = header("foo.bin", data.len() as u32);
+        feed(&mut receiver, &mut file, &info);
+        assert_eq!(
+            feed(&mut receiver, &mut file, &info),
+            ([ACK, CRC].to_vec(), Status::Busy)
+        );
+
+        for (i, packet) in blocks.iter().enumerate() {
+            feed(&mut receiver, &mut file, packet);
+
+            // a repeated data block, including block 256, is only ACKed
+            if i == 0 || i == 255 {
+                assert_eq!(
+                    feed(&mut receiver, &mut file, packet),
+                    ([ACK].to_vec(), Status::Busy)
+                );
+            }
+        }
+        assert_eq!(file.data, data);
+
+        // a block from further back is a protocol error
+        let (sent, status) = feed(&mut receiver, &mut file, &blocks[10]);
+        assert_eq!(
+            (sent, status),
+            ([CAN, CAN].to_vec(), Status::Failed(Error::Protocol))
+        );
+    }
+
+    #[test]
+    fn retries() {
+        let mut receiver = Receiver::new(Mode::Xmodem);
+        let mut file = File::default();
+        receiver.start();
+
+        let good = block(1, b"hello");
+        let mut bad = good.clone();
+        bad[10] ^= 0xff;
+
+        // corrupted blocks are NAKed, a good one resets the count
+        for _ in 0..MAX_RETRIES - 1 {
+            assert_eq!(
+                feed(&mut receiver, &mut file, &bad),
+                ([NAK].to_vec(), Status::Busy)
+            );
+        }
+        assert_eq!(
+            feed(&mut receiver, &mut file, &good),
+            ([ACK].to_vec(), Status::Busy)
+        );
+
+        // a bad complement of the block number is a corrupted block too
+        let mut bad = block(2, b"world");
+        bad[2] = 0;
+        for _ in 0..MAX_RETRIES - 1 {
+            assert_eq!(
+                feed(&mut receiver, &mut file, &bad),
+                ([NAK].to_vec(), Status::Busy)
+            );
+        }
+        assert_eq!(
+            feed(&mut receiver, &mut file, &bad),
+            ([CAN, CAN].to_vec(), Status::Failed(Error::Retries))
+        );
+        assert_eq!(file.data[..5], *b"hello");
+
+        // timeouts: `C` until the first block, then NAK
+        let mut receiver = Receiver::new(Mode::Xmodem);
+        let mut file = File::default();
+        receiver.start();
+        assert_eq!(receiver.timeout().send, [CRC]);
+        feed(&mut receiver, &mut file, &block(1, b"a"));
+        assert_eq!(receiver.timeout().send, [NAK]);
+        for _ in 0..MAX_RETRIES - 2 {
+            assert_eq!(receiver.timeout().status, Status::Busy);
+        }
+        let step = receiver.timeout();
+        assert_eq!(step.send, [CAN, CAN]);
+        assert_eq!(step.status, Status::Failed(Error::Retries));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut receiver = Receiver::new(Mode::Xmodem);
+        let mut file = File::default();
+        receiver.start();
+
+        // a single CAN is line noise
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[CAN, b'x']),
+            ([].to_vec(), Status::Busy)
+        );
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[CAN, CAN]),
+            ([].to_vec(), Status::Failed(Error::Cancelled))
+        );
+    }
+
+    #[test]
+    fn empty_file() {
+        let mut receiver = Receiver::new(Mode::Xmodem);
+        let mut file = File::default();
+        receiver.start();
+
+        assert_eq!(
+            feed(&mut receiver, &mut file, &[EOT, EOT]),
+            ([NAK, ACK].to_vec(), Status::Done)
+        );
+        assert!(file.data.is_empty());
+
+        // YMODEM: an empty batch
+        let mut receiver = Receiver::new(Mode::Ymodem);
+        receiver.start();
+        assert_eq!(
+            feed(&mut receiver, &mut file, &packet(0, &[], 0)),
+            ([ACK].to_vec(), Status::Done)
+        );
+        assert!(file.name.is_none());
+    }
+
+    // Runs a sender and a receiver against each other; `lose` drops the
+    // n-th byte sent by the receiver
+    fn loopback(mode: Mode, large: bool, data: &[u8], lose: Option<usize>) -> File {
+        let mut file = File::default();
+        let mut source = data;
+        let mut sender = Sender::new(mode, large, "foo.bin", data.len() as u32);
+        let mut receiver = Receiver::new(mode);
+
+        let mut to_sender = receiver.start().to_vec();
+        let mut count = 0;
+        loop {
+            if to_sender.is_empty() {
+                // the line went silent
+                let step = receiver.timeout();
+                assert_eq!(step.status, Status::Busy);
+                to_sender.extend_from_slice(step.send);
+                continue;
+            }
+
+            let mut to_receiver = Vec::new();
+            for byte in to_sender.drain(..) {
+                count += 1;
+                if Some(count) == lose {
+                    continue;
+                }
+                let step = sender.feed(byte, &mut source);
+                to_receiver.extend_from_slice(step.send);
+                match step.status {
+                    Status::Busy => {}
+                    Status::Done => return file,
+                    Status::Failed(e) => panic!("sender failed: {:?}", e),
+                }
+            }
+
+            for byte in to_receiver {
+                let step = receiver.feed(byte, &mut file);
+                to_sender.extend_from_slice(step.send);
+                match step.status {
+                    Status::Busy | Status::Done => {}
+                    Status::Failed(e) => panic!("receiver failed: {:?}", e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sender_to_receiver() {
+        let data = data(300 * 1024 + 100);
+
+        let file = loopback(Mode::Ymodem, true, &data, None);
+        assert_eq!(file.name, Some(String::from("foo.bin")));
+        assert_eq!(file.data, data);
+
+        let file = loopback(Mode::Ymodem, false, &data[..40_000], None);
+        assert_eq!(file.data, &data[..40_000]);
+
+        // XMODEM keeps the padding of the last block
+        let file = loopback(Mode::Xmodem, false, &data[..40_000], None);
+        assert_eq!(file.data.len(), 40_064);
+        assert_eq!(file.data[..40_000], data[..40_000]);
+        assert!(file.data[40_000..].iter().all(|b| *b == SUB));
+
+        // lose the ACK of the file information (after the first `C`), of
+        // block 7 and of block 256
+        for lose in &[2, 10, 259] {
+            let file = loopback(Mode::Ymodem, false, &data[..40_000], Some(*lose));
+            assert_eq!(file.data, &data[..40_000]);
+        }
+    }
+}